@@ -0,0 +1,56 @@
+// Blocked vs naive matmul on the shapes the CIFAR-10 MLP in main.rs hits every step.
+// Run with `cargo bench --bench matmul`.
+use std::time::{Duration, Instant};
+
+use nn::matrix::Matrix;
+use rand::{rngs::StdRng, SeedableRng};
+
+// the blocked kernel sums in a different order than the naive one,
+// so results are compared with a relative tolerance of
+//   |blocked - naive| <= TOL * max(1, |naive|)
+const TOL: f32 = 1e-4;
+
+fn time<F: FnMut() -> Matrix>(iters: usize, mut f: F) -> (Duration, Matrix) {
+    let mut out = f(); // warmup
+    let start = Instant::now();
+    for _ in 0..iters {
+        out = f();
+    }
+    (start.elapsed() / iters as u32, out)
+}
+
+fn max_rel_err(a: &Matrix, b: &Matrix) -> f32 {
+    let mut err = 0.0f32;
+    for i in 0..a.rows() {
+        for j in 0..a.cols() {
+            let (x, y) = (a.get(i, j), b.get(i, j));
+            err = err.max((x - y).abs() / y.abs().max(1.0));
+        }
+    }
+    err
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(1337);
+    // (name, lhs, rhs) - forward of the first layer and both of its backward matmuls
+    let cases = vec![
+        ("x @ W1       ", Matrix::random(128, 3072, &mut rng), Matrix::random(3072, 128, &mut rng)),
+        ("x^T @ dy     ", Matrix::random(128, 3072, &mut rng).T(), Matrix::random(128, 128, &mut rng)),
+        ("dy @ W1^T    ", Matrix::random(128, 128, &mut rng), Matrix::random(3072, 128, &mut rng).T()),
+        ("h @ W2       ", Matrix::random(128, 128, &mut rng), Matrix::random(128, 128, &mut rng)),
+        ("odd shapes   ", Matrix::random(77, 301, &mut rng), Matrix::random(301, 45, &mut rng)),
+    ];
+
+    println!("case          | naive        | blocked      | speedup | max rel err");
+    for (name, a, b) in cases {
+        let (t_naive, y_naive) = time(3, || a.matmul_naive(&b));
+        let (t_blocked, y_blocked) = time(20, || a.matmul(&b));
+        let err = max_rel_err(&y_blocked, &y_naive);
+        println!(
+            "{} | {:>10.3?} | {:>10.3?} | {:>6.1}x | {:.2e}",
+            name, t_naive, t_blocked, t_naive.as_secs_f64() / t_blocked.as_secs_f64(), err
+        );
+        assert_eq!(y_blocked.shape, y_naive.shape);
+        assert!(err <= TOL, "{name}: blocked matmul differs from naive by {err} > {TOL}");
+    }
+}
@@ -0,0 +1,43 @@
+// Toy regression - fits y = sin(3x) on [-1, 1] from noisy samples with an MLP, MSE and SGD.
+// Run with `cargo run --release --example regression`.
+use nn::activation::Tanh;
+use nn::layer::Layer;
+use nn::loss::{L1Loss, Loss, MSELoss};
+use nn::matrix::Matrix;
+use nn::models::MLP;
+use nn::optimizer::{Optimizer, SGD};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let x = &(2.0 * &Matrix::random(256, 1, &mut rng)) - 1.0;
+    let noise = Matrix::from_vec(256, 1, (0..256).map(|_| rng.gen_range(-0.1..0.1)).collect());
+    let y = &(3.0 * &x).apply_unary(|v| v.sin()) + &noise;
+
+    let mut model = MLP::builder(&[1, 32, 32, 1]).with_activation(|| Box::new(Tanh::new())).build(&mut rng);
+    let mut loss_fn = MSELoss::new();
+    let mut optim = SGD::new(0.1, 0.0);
+
+    println!("epoch,mse");
+    for epoch in 0..2000 {
+        let mut losses = vec![];
+        for start in (0..256).step_by(32) {
+            let (xb, yb) = (x.narrow(0, start..start + 32), y.narrow(0, start..start + 32));
+            losses.push(loss_fn.forward(&model.forward(&xb), &yb));
+            optim.zero_grad(model.parameters());
+            model.backward(&loss_fn.backward(1.0));
+            optim.step(model.parameters());
+        }
+        if epoch % 200 == 0 {
+            println!("{},{}", epoch, losses.iter().sum::<f32>() / losses.len() as f32);
+        }
+    }
+
+    // error against the clean function on a grid
+    model.eval();
+    let grid = Matrix::from_vec(101, 1, (0..=100).map(|i| i as f32 / 50.0 - 1.0).collect());
+    let clean = (3.0 * &grid).apply_unary(|v| v.sin());
+    let pred = model.forward(&grid);
+    println!("test mse: {}", MSELoss::new().forward(&pred, &clean));
+    println!("test mae: {}", L1Loss::new().forward(&pred, &clean));
+}
@@ -0,0 +1,540 @@
+use crate::layer::Layer;
+use crate::matrix::Matrix;
+use crate::parameter::Parameter;
+
+// Elementwise activations cache their input and output on forward,
+// backward is then partial * dy/dx computed from both.
+#[derive(Default)]
+struct Elementwise {
+    input: Option<Matrix>,
+    output: Option<Matrix>,
+}
+
+impl Elementwise {
+    fn forward<F: Fn(f32) -> f32>(&mut self, input: &Matrix, f: F) -> Matrix {
+        let y = input.apply_unary(|x| f(*x));
+        self.input = Some(input.clone());
+        self.output = Some(y.clone());
+        y
+    }
+
+    // df(x, y) is the derivative at x, y = f(x) is there for the cases where that's cheaper
+    fn backward<F: Fn(f32, f32) -> f32>(&self, partial: &Matrix, df: F) -> Matrix {
+        let x = self.input.as_ref().expect("Cannot call backward before forward.");
+        let y = self.output.as_ref().unwrap();
+        partial * &x.apply_binary(y, |x, y| df(*x, *y))
+    }
+}
+
+pub(crate) fn sigmoid(x: f32) -> f32 {
+    // split so exp never overflows
+    if x >= 0.0 { 1.0 / (1.0 + (-x).exp()) } else { x.exp() / (1.0 + x.exp()) }
+}
+
+// Abramowitz and Stegun 7.1.26, max error 1.5e-7 which is below f32 precision anyway
+fn erf(x: f32) -> f32 {
+    let x = x as f64;
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let y = 1.0 - poly * (-x * x).exp();
+    (if x >= 0.0 { y } else { -y }) as f32
+}
+
+// Sigmoid layer - 1 / (1 + e^-x)
+#[derive(Default)]
+pub struct Sigmoid {
+    cache: Elementwise,
+}
+
+impl Sigmoid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Layer for Sigmoid {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.cache.forward(input, sigmoid)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.cache.backward(partial, |_, y| y * (1.0 - y))
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// Tanh layer
+#[derive(Default)]
+pub struct Tanh {
+    cache: Elementwise,
+}
+
+impl Tanh {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Layer for Tanh {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.cache.forward(input, f32::tanh)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.cache.backward(partial, |_, y| 1.0 - y * y)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// LeakyReLU layer - x for x > 0, negative_slope * x otherwise
+pub struct LeakyReLU {
+    negative_slope: f32,
+    cache: Elementwise,
+}
+
+impl LeakyReLU {
+    // pytorch uses 0.01 as the default slope
+    pub fn new(negative_slope: f32) -> Self {
+        Self { negative_slope, cache: Elementwise::default() }
+    }
+}
+
+impl Layer for LeakyReLU {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let a = self.negative_slope;
+        self.cache.forward(input, |x| if x > 0.0 { x } else { a * x })
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let a = self.negative_slope;
+        self.cache.backward(partial, |x, _| if x > 0.0 { 1.0 } else { a })
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// ELU layer - x for x > 0, alpha * (e^x - 1) otherwise
+pub struct ELU {
+    alpha: f32,
+    cache: Elementwise,
+}
+
+impl ELU {
+    pub fn new(alpha: f32) -> Self {
+        Self { alpha, cache: Elementwise::default() }
+    }
+}
+
+impl Layer for ELU {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let a = self.alpha;
+        self.cache.forward(input, |x| if x > 0.0 { x } else { a * x.exp_m1() })
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let a = self.alpha;
+        self.cache.backward(partial, |x, y| if x > 0.0 { 1.0 } else { y + a })
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// GELU layer - x * Phi(x) with Phi the standard normal cdf,
+// or the tanh approximation 0.5x(1 + tanh(sqrt(2/pi)(x + 0.044715x^3)))
+pub struct GELU {
+    approximate: bool,
+    cache: Elementwise,
+}
+
+const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+const GELU_CUBIC: f32 = 0.044715;
+
+impl GELU {
+    // exact, with erf
+    pub fn new() -> Self {
+        Self { approximate: false, cache: Elementwise::default() }
+    }
+
+    // tanh approximation
+    pub fn approximate() -> Self {
+        Self { approximate: true, cache: Elementwise::default() }
+    }
+}
+
+impl Default for GELU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for GELU {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        if self.approximate {
+            self.cache.forward(input, |x| {
+                0.5 * x * (1.0 + (SQRT_2_OVER_PI * (x + GELU_CUBIC * x * x * x)).tanh())
+            })
+        } else {
+            self.cache.forward(input, |x| 0.5 * x * (1.0 + erf(x / std::f32::consts::SQRT_2)))
+        }
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        if self.approximate {
+            self.cache.backward(partial, |x, _| {
+                let t = (SQRT_2_OVER_PI * (x + GELU_CUBIC * x * x * x)).tanh();
+                0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * SQRT_2_OVER_PI * (1.0 + 3.0 * GELU_CUBIC * x * x)
+            })
+        } else {
+            self.cache.backward(partial, |x, _| {
+                let cdf = 0.5 * (1.0 + erf(x / std::f32::consts::SQRT_2));
+                let pdf = (-0.5 * x * x).exp() / (2.0 * std::f32::consts::PI).sqrt();
+                cdf + x * pdf
+            })
+        }
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// SiLU layer - x * sigmoid(x), a.k.a. swish
+#[derive(Default)]
+pub struct SiLU {
+    cache: Elementwise,
+}
+
+pub type Swish = SiLU;
+
+impl SiLU {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Layer for SiLU {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.cache.forward(input, |x| x * sigmoid(x))
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.cache.backward(partial, |x, _| {
+            let s = sigmoid(x);
+            s + x * s * (1.0 - s)
+        })
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// Softplus layer - ln(1 + e^(beta x)) / beta, linear where beta * x > threshold
+// so the exp doesn't overflow
+pub struct Softplus {
+    beta: f32,
+    threshold: f32,
+    cache: Elementwise,
+}
+
+impl Softplus {
+    // pytorch defaults are beta 1 and threshold 20
+    pub fn new(beta: f32, threshold: f32) -> Self {
+        Self { beta, threshold, cache: Elementwise::default() }
+    }
+}
+
+impl Default for Softplus {
+    fn default() -> Self {
+        Self::new(1.0, 20.0)
+    }
+}
+
+impl Layer for Softplus {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let (beta, threshold) = (self.beta, self.threshold);
+        self.cache.forward(input, |x| {
+            if beta * x > threshold { x } else { (beta * x).exp().ln_1p() / beta }
+        })
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let (beta, threshold) = (self.beta, self.threshold);
+        self.cache.backward(partial, |x, _| if beta * x > threshold { 1.0 } else { sigmoid(beta * x) })
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// Hardtanh layer - clamps to [min, max], no gradient outside
+pub struct Hardtanh {
+    min: f32,
+    max: f32,
+    cache: Elementwise,
+}
+
+impl Hardtanh {
+    pub fn new(min: f32, max: f32) -> Self {
+        if min > max { panic!("Hardtanh min {min} above max {max}.") }
+        Self { min, max, cache: Elementwise::default() }
+    }
+}
+
+impl Default for Hardtanh {
+    fn default() -> Self {
+        Self::new(-1.0, 1.0)
+    }
+}
+
+impl Layer for Hardtanh {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let (min, max) = (self.min, self.max);
+        self.cache.forward(input, |x| x.clamp(min, max))
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let (min, max) = (self.min, self.max);
+        self.cache.backward(partial, |x, _| (x > min && x < max) as i32 as f32)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// Softmax layer - e^x / sum(e^x) along axis
+pub struct Softmax {
+    axis: usize,
+    output: Option<Matrix>,
+}
+
+impl Softmax {
+    pub fn new(axis: usize) -> Self {
+        Self { axis, output: None }
+    }
+}
+
+impl Layer for Softmax {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let y = (input - &input.logsumexp_axis(self.axis, true)).exp();
+        self.output = Some(y.clone());
+        y
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let y = self.output.as_ref().expect("Cannot call backward before forward.");
+        y * &(partial - &(partial * y).sum_axis(self.axis, true))
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// LogSoftmax layer - x - ln(sum(e^x)) along axis, stable for large inputs
+pub struct LogSoftmax {
+    axis: usize,
+    output: Option<Matrix>,
+}
+
+impl LogSoftmax {
+    pub fn new(axis: usize) -> Self {
+        Self { axis, output: None }
+    }
+}
+
+impl Layer for LogSoftmax {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let y = input - &input.logsumexp_axis(self.axis, true);
+        self.output = Some(y.clone());
+        y
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let y = self.output.as_ref().expect("Cannot call backward before forward.");
+        partial - &(&y.exp() * &partial.sum_axis(self.axis, true))
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// PReLU layer - LeakyReLU with a learnable slope, either one shared slope
+// or one per channel (axis 1 of the input)
+pub struct PReLU {
+    weight: Parameter,
+    input: Option<Matrix>,
+}
+
+impl PReLU {
+    // pytorch initializes the slopes to 0.25
+    pub fn new(num_parameters: usize, init: f32) -> Self {
+        if num_parameters == 0 { panic!("PReLU needs at least one parameter.") }
+        Self { weight: Parameter::new(Matrix::full(1, num_parameters, init)), input: None }
+    }
+
+    // slopes shaped to broadcast against the input
+    fn slopes(&self, input: &Matrix) -> Matrix {
+        let n = self.weight.data.cols();
+        if n == 1 {
+            return self.weight.data.reshape(&vec![1; input.ndim()]);
+        }
+        if input.ndim() < 2 || input.shape[1] != n {
+            panic!("PReLU with {n} parameters got input of shape {:?}.", input.shape);
+        }
+        let mut shape = vec![1; input.ndim()];
+        shape[1] = n;
+        self.weight.data.reshape(&shape)
+    }
+}
+
+impl Layer for PReLU {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.input = Some(input.clone());
+        input.apply_binary(&self.slopes(input), |x, a| if *x > 0.0 { *x } else { a * x })
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let x = self.input.as_ref().expect("Cannot call backward before forward.");
+
+        // slope gradient is partial * x where x <= 0, summed over everything but the channel
+        let mut grad = partial * &x.apply_unary(|x| if *x > 0.0 { 0.0 } else { *x });
+        if self.weight.data.cols() == 1 {
+            grad = Matrix::full(1, 1, grad.sum());
+        } else {
+            for axis in (0..grad.ndim()).rev().filter(|a| *a != 1) {
+                grad = grad.sum_axis(axis, false);
+            }
+            grad = grad.reshape(&[1, self.weight.data.cols()]);
+        }
+        self.weight.grad = &self.weight.grad + &grad;
+
+        partial * &x.apply_binary(&self.slopes(x), |x, a| if *x > 0.0 { 1.0 } else { *a })
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight]
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        vec![("weight".to_string(), &mut self.weight)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{gradcheck, one_hot};
+    use crate::layer::{Flatten, Sequential};
+    use crate::loss::Crossentropy;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // uniform in [-3, 3], but kept away from 0 so kinks don't fall inside eps = 1e-2
+    fn input(shape: &[usize], seed: u64) -> Matrix {
+        let mut rng = StdRng::seed_from_u64(seed);
+        Matrix::random_nd(shape, &mut rng)
+            .apply_unary(|x| if *x < 0.5 { -0.1 - 5.8 * x } else { 5.8 * x - 2.8 })
+    }
+
+    fn check(layer: &mut dyn Layer, seed: u64) {
+        let x = input(&[4, 5], seed);
+        gradcheck(layer, &mut Crossentropy::new(), &x, &one_hot(&[0, 4, 2, 3], 5), 1e-2).assert_below(1e-2);
+    }
+
+    fn assert_close(a: &Matrix, b: &[f32]) {
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-5, "{:?} != {b:?}", a.to_vec());
+        }
+    }
+
+    #[test]
+    fn forward_values() {
+        let x = Matrix::from_vec(1, 3, vec![-1.0, 0.0, 2.0]);
+        assert_close(&Sigmoid::new().forward(&x), &[0.268_941_4, 0.5, 0.880_797]);
+        assert_close(&Tanh::new().forward(&x), &[-0.761_594_2, 0.0, 0.964_027_6]);
+        assert_close(&LeakyReLU::new(0.1).forward(&x), &[-0.1, 0.0, 2.0]);
+        assert_close(&ELU::new(1.0).forward(&x), &[-0.632_120_6, 0.0, 2.0]);
+        assert_close(&GELU::new().forward(&x), &[-0.158_655_3, 0.0, 1.954_5]);
+        assert_close(&GELU::approximate().forward(&x), &[-0.158_808, 0.0, 1.954_597_7]);
+        assert_close(&SiLU::new().forward(&x), &[-0.268_941_4, 0.0, 1.761_594_2]);
+        assert_close(&Softplus::default().forward(&x), &[0.313_261_7, std::f32::consts::LN_2, 2.126_928]);
+        assert_close(&Hardtanh::new(-0.5, 1.0).forward(&x), &[-0.5, 0.0, 1.0]);
+        assert_close(&PReLU::new(1, 0.25).forward(&x), &[-0.25, 0.0, 2.0]);
+    }
+
+    #[test]
+    fn softplus_is_linear_past_threshold() {
+        let y = Softplus::new(2.0, 20.0).forward(&Matrix::from_vec(1, 2, vec![11.0, 100.0]));
+        assert_eq!(y.to_vec(), vec![11.0, 100.0]);
+    }
+
+    #[test]
+    fn softmax_sums_to_one_along_axis() {
+        let x = input(&[3, 4], 0);
+        let rows = Softmax::new(1).forward(&x).sum_axis(1, false);
+        let cols = Softmax::new(0).forward(&x).sum_axis(0, false);
+        assert_close(&rows, &[1.0; 3]);
+        assert_close(&cols, &[1.0; 4]);
+        let log = LogSoftmax::new(1).forward(&x).exp();
+        assert_close(&log, &Softmax::new(1).forward(&x).to_vec());
+    }
+
+    #[test]
+    fn elementwise_gradients() {
+        check(&mut Sigmoid::new(), 1);
+        check(&mut Tanh::new(), 2);
+        check(&mut LeakyReLU::new(0.1), 3);
+        check(&mut ELU::new(0.7), 4);
+        check(&mut GELU::new(), 5);
+        check(&mut GELU::approximate(), 6);
+        check(&mut SiLU::new(), 7);
+        check(&mut Softplus::default(), 8);
+        check(&mut Softplus::new(3.0, 2.0), 9);
+    }
+
+    #[test]
+    fn hardtanh_gradients() {
+        // nothing close to the clamp edges
+        let x = Matrix::from_vec(2, 4, vec![-2.0, -0.7, 0.3, 1.5, 0.9, -1.3, -0.2, 0.6]);
+        let report = gradcheck(&mut Hardtanh::default(), &mut Crossentropy::new(), &x, &one_hot(&[1, 3], 4), 1e-2);
+        report.assert_below(1e-2);
+    }
+
+    #[test]
+    fn softmax_gradients() {
+        check(&mut Softmax::new(1), 10);
+        check(&mut Softmax::new(0), 11);
+        check(&mut LogSoftmax::new(1), 12);
+        check(&mut LogSoftmax::new(0), 13);
+    }
+
+    #[test]
+    fn prelu_gradients() {
+        let mut prelu = PReLU::new(1, 0.25);
+        check(&mut prelu, 14);
+
+        // one slope per channel of an image
+        let mut model = Sequential::new(vec![Box::new(PReLU::new(3, 0.1)), Box::new(Flatten::new())]);
+        for (i, p) in model.parameters()[0].data.to_vec().iter().enumerate() {
+            assert_eq!(*p, 0.1, "slope {i}");
+        }
+        model.parameters()[0].data = Matrix::from_vec(1, 3, vec![0.1, 0.3, -0.2]);
+        let x = input(&[2, 3, 2, 2], 15);
+        let report = gradcheck(&mut model, &mut Crossentropy::new(), &x, &one_hot(&[4, 11], 12), 1e-2);
+        assert_eq!(report.parameters.len(), 1);
+        report.assert_below(1e-2);
+    }
+}
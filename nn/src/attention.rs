@@ -0,0 +1,406 @@
+use rand::Rng;
+
+use crate::activation::Softmax;
+use crate::layer::{prefixed, Dropout, Layer, Linear, ReLU};
+use crate::matrix::Matrix;
+use crate::norm::LayerNorm;
+use crate::parameter::Parameter;
+
+// added to the scores of masked keys, large enough for exp to underflow to 0.
+// A query with every key masked ends up attending uniformly instead of producing NaNs.
+const MASKED: f32 = -1e9;
+
+// Multi-head self-attention over [batch, time, embed_dim] inputs.
+// Queries, keys and values come from one Linear projection and are split into heads of
+// embed_dim / num_heads features, softmax(q k^T / sqrt(head_dim)) v per head,
+// the heads are concatenated again and go through the output Linear.
+pub struct MultiheadAttention {
+    in_proj: Linear,
+    out_proj: Linear,
+    softmax: Softmax,
+    embed_dim: usize,
+    num_heads: usize,
+    causal: bool,
+    // q, k, v as [batch, heads, time, head_dim] and the attention weights of the last forward
+    qkv: Option<(Matrix, Matrix, Matrix)>,
+    attn: Option<Matrix>,
+}
+
+impl MultiheadAttention {
+    pub fn new<R: Rng>(embed_dim: usize, num_heads: usize, rng: &mut R) -> Self {
+        if num_heads == 0 || embed_dim % num_heads != 0 {
+            panic!("Embedding dim {embed_dim} can't be split into {num_heads} heads.");
+        }
+        Self {
+            in_proj: Linear::new(embed_dim, 3 * embed_dim, true, rng),
+            out_proj: Linear::new(embed_dim, embed_dim, true, rng),
+            softmax: Softmax::new(3),
+            embed_dim, num_heads,
+            causal: false,
+            qkv: None,
+            attn: None,
+        }
+    }
+
+    // position t only attends to positions <= t, for autoregressive models
+    pub fn with_causal(mut self, causal: bool) -> Self {
+        self.causal = causal;
+        self
+    }
+
+    // attention weights of the last forward, [batch, heads, time, time]
+    pub fn attention_weights(&self) -> Option<&Matrix> {
+        self.attn.as_ref()
+    }
+
+    // [batch * time, embed_dim] -> [batch, heads, time, head_dim]
+    fn split_heads(&self, x: &Matrix, batch: usize, time: usize) -> Matrix {
+        let head_dim = x.cols() / self.num_heads;
+        x.reshape(&[batch, time, self.num_heads, head_dim]).permute(&[0, 2, 1, 3])
+    }
+
+    // [batch, heads, time, head_dim] -> [batch * time, embed_dim]
+    fn merge_heads(&self, x: &Matrix) -> Matrix {
+        let (batch, time) = (x.shape[0], x.shape[2]);
+        x.permute(&[0, 2, 1, 3]).reshape(&[batch * time, self.num_heads * x.shape[3]])
+    }
+
+    // forward where keys with a nonzero entry in the [batch, time] `key_padding_mask`
+    // are ignored, e.g. padding tokens of shorter sequences
+    pub fn forward_with_mask(&mut self, input: &Matrix, key_padding_mask: Option<&Matrix>) -> Matrix {
+        let embed_dim = self.embed_dim;
+        if input.ndim() != 3 || input.shape[2] != embed_dim {
+            panic!("Expected [batch, time, {embed_dim}] input, got {:?}.", input.shape);
+        }
+        let (batch, time) = (input.shape[0], input.shape[1]);
+        let qkv = self.in_proj.forward(&input.reshape(&[batch * time, embed_dim]));
+        let part = |i: usize| self.split_heads(&qkv.narrow(1, i * embed_dim..(i + 1) * embed_dim), batch, time);
+        let (q, k, v) = (part(0), part(1), part(2));
+
+        let head_dim = embed_dim / self.num_heads;
+        let mut scores = &q.batched_matmul(&k.transpose(2, 3)) * (1.0 / (head_dim as f32).sqrt());
+        if let Some(mask) = self.mask(batch, time, key_padding_mask) {
+            scores = &scores + &mask;
+        }
+        let attn = self.softmax.forward(&scores);
+        let out = self.merge_heads(&attn.batched_matmul(&v));
+        self.qkv = Some((q, k, v));
+        self.attn = Some(attn);
+        self.out_proj.forward(&out).reshape(&input.shape)
+    }
+
+    // additive mask broadcasting against the [batch, heads, time, time] scores
+    fn mask(&self, batch: usize, time: usize, key_padding_mask: Option<&Matrix>) -> Option<Matrix> {
+        let mut mask = None;
+        if self.causal {
+            let mut causal = Matrix::full(time, time, 0.0);
+            for query in 0..time {
+                for key in query + 1..time {
+                    causal.set(query, key, MASKED);
+                }
+            }
+            mask = Some(causal.reshape(&[1, 1, time, time]));
+        }
+        if let Some(padding) = key_padding_mask {
+            if padding.shape != [batch, time] {
+                panic!("Expected key padding mask of shape {:?}, got {:?}.", [batch, time], padding.shape);
+            }
+            let padding = padding.apply_unary(|x| if *x != 0.0 { MASKED } else { 0.0 }).reshape(&[batch, 1, 1, time]);
+            mask = Some(match mask {
+                Some(causal) => &causal + &padding,
+                None => padding,
+            });
+        }
+        mask
+    }
+}
+
+impl Layer for MultiheadAttention {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.forward_with_mask(input, None)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let (q, k, v) = self.qkv.as_ref().expect("Cannot call backward before forward.");
+        let attn = self.attn.as_ref().unwrap();
+        let (batch, time, embed_dim) = (partial.shape[0], partial.shape[1], partial.shape[2]);
+        let d_out = self.out_proj.backward(&partial.reshape(&[batch * time, embed_dim]));
+        let d_out = self.split_heads(&d_out, batch, time);
+
+        let d_attn = d_out.batched_matmul(&v.transpose(2, 3));
+        let d_v = attn.transpose(2, 3).batched_matmul(&d_out);
+        // masked scores have zero weight, so they get no gradient either
+        let d_scores = &self.softmax.backward(&d_attn) * (1.0 / (q.shape[3] as f32).sqrt());
+        let d_q = d_scores.batched_matmul(k);
+        let d_k = d_scores.transpose(2, 3).batched_matmul(q);
+
+        let d_qkv = Matrix::concat(&[self.merge_heads(&d_q), self.merge_heads(&d_k), self.merge_heads(&d_v)], 1);
+        self.in_proj.backward(&d_qkv).reshape(&partial.shape)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        let mut params = self.in_proj.parameters();
+        params.append(&mut self.out_proj.parameters());
+        params
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut params = prefixed("in_proj", self.in_proj.named_parameters());
+        params.append(&mut prefixed("out_proj", self.out_proj.named_parameters()));
+        params
+    }
+}
+
+// Transformer encoder layer - self-attention and a two layer feed forward network,
+// each wrapped in dropout, a residual connection and LayerNorm.
+// Post-norm like the original transformer by default, `with_norm_first` for pre-norm (ViT, GPT-2)
+// which trains more stably in deep stacks.
+pub struct TransformerEncoderLayer {
+    self_attn: MultiheadAttention,
+    linear1: Linear,
+    activation: Box<dyn Layer>,
+    dropout: Dropout,
+    linear2: Linear,
+    norm1: LayerNorm,
+    norm2: LayerNorm,
+    dropout1: Dropout,
+    dropout2: Dropout,
+    norm_first: bool,
+}
+
+impl TransformerEncoderLayer {
+    // relu feed forward network with dim_feedforward hidden units
+    pub fn new<R: Rng>(d_model: usize, num_heads: usize, dim_feedforward: usize, dropout: f32, rng: &mut R) -> Self {
+        Self {
+            self_attn: MultiheadAttention::new(d_model, num_heads, rng),
+            linear1: Linear::new(d_model, dim_feedforward, true, rng),
+            activation: Box::new(ReLU::new()),
+            dropout: Dropout::new(dropout, rng),
+            linear2: Linear::new(dim_feedforward, d_model, true, rng),
+            norm1: LayerNorm::new(&[d_model], true),
+            norm2: LayerNorm::new(&[d_model], true),
+            dropout1: Dropout::new(dropout, rng),
+            dropout2: Dropout::new(dropout, rng),
+            norm_first: false,
+        }
+    }
+
+    pub fn with_norm_first(mut self, norm_first: bool) -> Self {
+        self.norm_first = norm_first;
+        self
+    }
+
+    pub fn with_causal(mut self, causal: bool) -> Self {
+        self.self_attn = self.self_attn.with_causal(causal);
+        self
+    }
+
+    // e.g. GELU instead of relu
+    pub fn with_activation(mut self, activation: Box<dyn Layer>) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    // see MultiheadAttention::forward_with_mask
+    pub fn forward_with_mask(&mut self, input: &Matrix, key_padding_mask: Option<&Matrix>) -> Matrix {
+        if self.norm_first {
+            let normed = self.norm1.forward(input);
+            let x = input + &self.attention_block(&normed, key_padding_mask);
+            let normed = self.norm2.forward(&x);
+            &x + &self.feed_forward_block(&normed)
+        } else {
+            let x = input + &self.attention_block(input, key_padding_mask);
+            let x = self.norm1.forward(&x);
+            let y = &x + &self.feed_forward_block(&x);
+            self.norm2.forward(&y)
+        }
+    }
+
+    fn attention_block(&mut self, x: &Matrix, key_padding_mask: Option<&Matrix>) -> Matrix {
+        self.dropout1.forward(&self.self_attn.forward_with_mask(x, key_padding_mask))
+    }
+
+    fn attention_block_backward(&mut self, partial: &Matrix) -> Matrix {
+        self.self_attn.backward(&self.dropout1.backward(partial))
+    }
+
+    // linear works on [rows, features], so the time axis is folded into the batch
+    fn feed_forward_block(&mut self, x: &Matrix) -> Matrix {
+        let rows = x.reshape(&[x.shape[0] * x.shape[1], x.shape[2]]);
+        let h = self.dropout.forward(&self.activation.forward(&self.linear1.forward(&rows)));
+        self.dropout2.forward(&self.linear2.forward(&h).reshape(&x.shape))
+    }
+
+    fn feed_forward_block_backward(&mut self, partial: &Matrix) -> Matrix {
+        let d = self.dropout2.backward(partial);
+        let d = self.linear2.backward(&d.reshape(&[d.shape[0] * d.shape[1], d.shape[2]]));
+        let d = self.linear1.backward(&self.activation.backward(&self.dropout.backward(&d)));
+        d.reshape(&partial.shape)
+    }
+}
+
+impl Layer for TransformerEncoderLayer {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.forward_with_mask(input, None)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        if self.norm_first {
+            let d = self.feed_forward_block_backward(partial);
+            let d_x = partial + &self.norm2.backward(&d);
+            let d = self.attention_block_backward(&d_x);
+            &d_x + &self.norm1.backward(&d)
+        } else {
+            let d_y = self.norm2.backward(partial);
+            let d_x = &d_y + &self.feed_forward_block_backward(&d_y);
+            let d_x = self.norm1.backward(&d_x);
+            &d_x + &self.attention_block_backward(&d_x)
+        }
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        let mut params = self.self_attn.parameters();
+        params.append(&mut self.linear1.parameters());
+        params.append(&mut self.activation.parameters());
+        params.append(&mut self.linear2.parameters());
+        params.append(&mut self.norm1.parameters());
+        params.append(&mut self.norm2.parameters());
+        params
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut params = prefixed("self_attn", self.self_attn.named_parameters());
+        params.append(&mut prefixed("linear1", self.linear1.named_parameters()));
+        params.append(&mut prefixed("activation", self.activation.named_parameters()));
+        params.append(&mut prefixed("linear2", self.linear2.named_parameters()));
+        params.append(&mut prefixed("norm1", self.norm1.named_parameters()));
+        params.append(&mut prefixed("norm2", self.norm2.named_parameters()));
+        params
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        let mut buffers = prefixed("self_attn", self.self_attn.named_buffers());
+        buffers.append(&mut prefixed("dropout", self.dropout.named_buffers()));
+        buffers.append(&mut prefixed("activation", self.activation.named_buffers()));
+        buffers.append(&mut prefixed("dropout1", self.dropout1.named_buffers()));
+        buffers.append(&mut prefixed("dropout2", self.dropout2.named_buffers()));
+        buffers
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.activation.set_training(training);
+        self.dropout.set_training(training);
+        self.dropout1.set_training(training);
+        self.dropout2.set_training(training);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::GELU;
+    use crate::gradcheck::{gradcheck, one_hot};
+    use crate::layer::{Flatten, Sequential};
+    use crate::loss::Crossentropy;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn input(shape: &[usize], rng: &mut StdRng) -> Matrix {
+        &(2.0 * &Matrix::random_nd(shape, rng)) - 1.0
+    }
+
+    // gradcheck of [2, 3, 4] input through layer and a flatten
+    fn check(layer: Box<dyn Layer>, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let x = input(&[2, 3, 4], &mut rng);
+        let mut model = Sequential::new(vec![layer, Box::new(Flatten::new())]);
+        gradcheck(&mut model, &mut Crossentropy::new(), &x, &one_hot(&[2, 7], 12), 1e-2).assert_below(1e-2);
+    }
+
+    // attention with a fixed key padding mask, so gradcheck can drive it
+    struct Masked(MultiheadAttention, Matrix);
+
+    impl Layer for Masked {
+        fn forward(&mut self, input: &Matrix) -> Matrix {
+            self.0.forward_with_mask(input, Some(&self.1))
+        }
+        fn backward(&mut self, partial: &Matrix) -> Matrix {
+            self.0.backward(partial)
+        }
+        fn parameters(&mut self) -> Vec<&mut Parameter> {
+            self.0.parameters()
+        }
+    }
+
+    #[test]
+    fn attention_gradients() {
+        let mut rng = StdRng::seed_from_u64(0);
+        check(Box::new(MultiheadAttention::new(4, 2, &mut rng)), 1);
+        check(Box::new(MultiheadAttention::new(4, 1, &mut rng).with_causal(true)), 2);
+        let mask = Matrix::from_vec(2, 3, vec![0., 0., 1., 0., 1., 1.]);
+        check(Box::new(Masked(MultiheadAttention::new(4, 2, &mut rng).with_causal(true), mask)), 3);
+    }
+
+    #[test]
+    fn causal_attention_ignores_the_future() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut mha = MultiheadAttention::new(6, 3, &mut rng).with_causal(true);
+        let x = input(&[1, 5, 6], &mut rng);
+        let y = mha.forward(&x);
+        let weights = mha.attention_weights().unwrap();
+        assert_eq!(weights.shape, vec![1, 3, 5, 5]);
+        assert!(weights.get_nd(&[0, 1, 2, 3]) == 0.0 && weights.get_nd(&[0, 1, 3, 3]) > 0.0);
+
+        // changing the last step leaves the outputs before it alone
+        let mut x2 = x.clone();
+        for i in 0..6 {
+            x2.set_nd(&[0, 4, i], 5.0);
+        }
+        let y2 = mha.forward(&x2);
+        for (a, b) in y.narrow(1, 0..4).iter().zip(y2.narrow(1, 0..4).iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+        assert!((y.get_nd(&[0, 4, 0]) - y2.get_nd(&[0, 4, 0])).abs() > 1e-4);
+    }
+
+    #[test]
+    fn padded_keys_are_ignored() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut mha = MultiheadAttention::new(4, 2, &mut rng);
+        let x = input(&[2, 4, 4], &mut rng);
+        let mask = Matrix::from_vec(2, 4, vec![0., 0., 0., 1., 0., 0., 1., 1.]);
+        let y = mha.forward_with_mask(&x, Some(&mask));
+        let mut x2 = x.clone();
+        x2.set_nd(&[0, 3, 1], 3.0);
+        x2.set_nd(&[1, 2, 0], -3.0);
+        let y2 = mha.forward_with_mask(&x2, Some(&mask));
+        // unpadded queries see the same keys
+        for (a, b) in y.select(0, 0).narrow(0, 0..3).iter().zip(y2.select(0, 0).narrow(0, 0..3).iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+        for (a, b) in y.select(0, 1).narrow(0, 0..2).iter().zip(y2.select(0, 1).narrow(0, 0..2).iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn encoder_layer_gradients() {
+        let mut rng = StdRng::seed_from_u64(6);
+        check(Box::new(TransformerEncoderLayer::new(4, 2, 8, 0.0, &mut rng)), 7);
+        let layer = TransformerEncoderLayer::new(4, 2, 8, 0.0, &mut rng)
+            .with_norm_first(true)
+            .with_causal(true)
+            .with_activation(Box::new(GELU::new()));
+        check(Box::new(layer), 8);
+    }
+
+    #[test]
+    fn encoder_layer_dropout_only_in_training() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut layer = TransformerEncoderLayer::new(4, 2, 8, 0.5, &mut rng);
+        assert_eq!(layer.parameters().len(), 12);
+        let x = input(&[2, 3, 4], &mut rng);
+        assert_ne!(layer.forward(&x).to_vec(), layer.forward(&x).to_vec());
+        layer.eval();
+        assert_eq!(layer.forward(&x).to_vec(), layer.forward(&x).to_vec());
+    }
+}
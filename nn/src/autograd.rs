@@ -0,0 +1,458 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::rc::Rc;
+
+use crate::matrix::Matrix;
+
+// Reverse mode autograd on top of Matrix.
+// Every op on a Var records its inputs, `backward` then walks the graph in reverse
+// topological order and accumulates gradients into every node that requires them.
+//
+//   let w = weight.var();                       // Parameter as a leaf
+//   let x = Var::constant(input.clone());       // no gradient needed
+//   let loss = x.matmul(&w).maximum(0.0).mean();
+//   loss.backward();
+//   weight.accumulate_grad(&w);                 // optimizers take it from here
+//
+// Gradients accumulate over repeated backward calls, same as Parameter.grad.
+#[derive(Clone)]
+pub struct Var(Rc<Node>);
+
+struct Node {
+    value: Matrix,
+    grad: RefCell<Option<Matrix>>,
+    requires_grad: bool,
+    op: Op,
+}
+
+enum Op {
+    Leaf,
+    Add(Var, Var),
+    Sub(Var, Var),
+    Mul(Var, Var),
+    Div(Var, Var),
+    Neg(Var),
+    AddScalar(Var),
+    MulScalar(Var, f32),
+    MatMul(Var, Var),
+    Exp(Var),
+    Ln(Var),
+    Maximum(Var, f32),
+    Sum(Var),
+    SumAxis(Var, usize, bool),
+    Reshape(Var),
+    Transpose(Var),
+}
+
+impl Op {
+    fn inputs(&self) -> Vec<&Var> {
+        match self {
+            Op::Leaf => vec![],
+            Op::Add(a, b) | Op::Sub(a, b) | Op::Mul(a, b) | Op::Div(a, b) | Op::MatMul(a, b) => vec![a, b],
+            Op::Neg(a) | Op::AddScalar(a) | Op::MulScalar(a, _) | Op::Exp(a) | Op::Ln(a)
+            | Op::Maximum(a, _) | Op::Sum(a) | Op::SumAxis(a, _, _) | Op::Reshape(a)
+            | Op::Transpose(a) => vec![a],
+        }
+    }
+
+    // gradients w.r.t. each input, in the order of `inputs`
+    fn backward(&self, grad: &Matrix, out: &Matrix) -> Vec<Matrix> {
+        match self {
+            Op::Leaf => vec![],
+            Op::Add(a, b) => vec![
+                unbroadcast(grad, a.shape()),
+                unbroadcast(grad, b.shape()),
+            ],
+            Op::Sub(a, b) => vec![
+                unbroadcast(grad, a.shape()),
+                unbroadcast(&-grad, b.shape()),
+            ],
+            Op::Mul(a, b) => vec![
+                unbroadcast(&(grad * b.value()), a.shape()),
+                unbroadcast(&(grad * a.value()), b.shape()),
+            ],
+            Op::Div(a, b) => vec![
+                unbroadcast(&(grad / b.value()), a.shape()),
+                unbroadcast(&-&(&(grad * out) / b.value()), b.shape()),
+            ],
+            Op::Neg(_) => vec![-grad],
+            Op::AddScalar(_) => vec![grad.clone()],
+            Op::MulScalar(_, c) => vec![grad * *c],
+            Op::MatMul(a, b) => vec![
+                grad.matmul(&b.value().T()),
+                a.value().T().matmul(grad),
+            ],
+            Op::Exp(_) => vec![grad * out],
+            Op::Ln(a) => vec![grad / a.value()],
+            Op::Maximum(a, c) => vec![grad * &a.value().maximum(*c)],
+            Op::Sum(a) => vec![grad.broadcast_to(a.shape())],
+            Op::SumAxis(a, axis, keepdim) => {
+                let grad = if *keepdim { grad.clone() } else { grad.unsqueeze(*axis) };
+                vec![grad.broadcast_to(a.shape())]
+            }
+            Op::Reshape(a) => vec![grad.reshape(a.shape())],
+            Op::Transpose(_) => vec![grad.T()],
+        }
+    }
+}
+
+impl Var {
+    // leaf that collects a gradient
+    pub fn leaf(value: Matrix) -> Self {
+        Self::from_op(value, Op::Leaf, true)
+    }
+
+    // leaf that never gets a gradient, e.g. inputs and targets
+    pub fn constant(value: Matrix) -> Self {
+        Self::from_op(value, Op::Leaf, false)
+    }
+
+    fn from_op(value: Matrix, op: Op, requires_grad: bool) -> Self {
+        Self(Rc::new(Node { value, grad: RefCell::new(None), requires_grad, op }))
+    }
+
+    // result of an op, needs a gradient if any of its inputs does
+    fn record(value: Matrix, op: Op) -> Self {
+        let requires_grad = op.inputs().iter().any(|v| v.requires_grad());
+        Self::from_op(value, op, requires_grad)
+    }
+
+    pub fn value(&self) -> &Matrix {
+        &self.0.value
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.0.value.shape
+    }
+
+    // value of a single element Var, e.g. a loss
+    pub fn item(&self) -> f32 {
+        if self.value().numel() != 1 { panic!("item() on Var of shape {:?}.", self.shape()) }
+        self.value().iter().next().unwrap()
+    }
+
+    pub fn requires_grad(&self) -> bool {
+        self.0.requires_grad
+    }
+
+    // gradient accumulated by backward, None if nothing reached this node
+    pub fn grad(&self) -> Option<Matrix> {
+        self.0.grad.borrow().clone()
+    }
+
+    pub fn zero_grad(&self) {
+        *self.0.grad.borrow_mut() = None;
+    }
+
+    // backward from a single element Var, e.g. a loss
+    pub fn backward(&self) {
+        if self.value().numel() != 1 {
+            panic!("backward() on Var of shape {:?}, use backward_with for non-scalars.", self.shape());
+        }
+        self.backward_with(&Matrix::full_like(self.value(), 1.0));
+    }
+
+    // backward with an explicit upstream gradient of the same shape as self
+    pub fn backward_with(&self, seed: &Matrix) {
+        if seed.shape != self.shape() {
+            panic!("Seed of shape {:?} for Var of shape {:?}.", seed.shape, self.shape());
+        }
+        if !self.requires_grad() { return }
+
+        // every node gets its full gradient before it is pushed to its inputs
+        let order = self.topological_order();
+        let mut grads: Vec<Option<Matrix>> = vec![None; order.len()];
+        let index: HashMap<*const Node, usize> = order.iter()
+            .enumerate()
+            .map(|(i, v)| (Rc::as_ptr(&v.0), i))
+            .collect();
+        grads[order.len() - 1] = Some(seed.clone());
+
+        for (i, var) in order.iter().enumerate().rev() {
+            let grad = match grads[i].take() {
+                Some(grad) => grad,
+                None => continue,
+            };
+            for (input, input_grad) in var.0.op.inputs().into_iter().zip(var.0.op.backward(&grad, var.value())) {
+                if !input.requires_grad() { continue }
+                let j = index[&Rc::as_ptr(&input.0)];
+                grads[j] = Some(match grads[j].take() {
+                    Some(g) => &g + &input_grad,
+                    None => input_grad,
+                });
+            }
+            var.accumulate(grad);
+        }
+    }
+
+    fn accumulate(&self, grad: Matrix) {
+        let mut slot = self.0.grad.borrow_mut();
+        *slot = Some(match slot.take() {
+            Some(g) => &g + &grad,
+            None => grad,
+        });
+    }
+
+    // nodes that require grad, inputs before the ops that use them, self last
+    fn topological_order(&self) -> Vec<Var> {
+        let mut order = vec![];
+        let mut visited = HashSet::new();
+        // iterative dfs, deep graphs (long sequences) would overflow the stack otherwise
+        let mut stack = vec![(self.clone(), false)];
+        while let Some((var, expanded)) = stack.pop() {
+            if expanded {
+                order.push(var);
+                continue;
+            }
+            if !visited.insert(Rc::as_ptr(&var.0)) { continue }
+            let inputs: Vec<Var> = var.0.op.inputs().into_iter()
+                .filter(|v| v.requires_grad())
+                .cloned()
+                .collect();
+            stack.push((var, true));
+            for input in inputs {
+                if !visited.contains(&Rc::as_ptr(&input.0)) {
+                    stack.push((input, false));
+                }
+            }
+        }
+        order
+    }
+}
+
+// reduces a broadcasted gradient back to the shape of the operand
+fn unbroadcast(grad: &Matrix, shape: &[usize]) -> Matrix {
+    let mut grad = grad.clone();
+    while grad.ndim() > shape.len() {
+        grad = grad.sum_axis(0, false);
+    }
+    for (axis, &dim) in shape.iter().enumerate() {
+        if dim == 1 && grad.shape[axis] != 1 {
+            grad = grad.sum_axis(axis, true);
+        }
+    }
+    grad
+}
+
+
+// ops
+impl Var {
+    pub fn matmul(&self, other: &Var) -> Var {
+        Var::record(self.value().matmul(other.value()), Op::MatMul(self.clone(), other.clone()))
+    }
+
+    pub fn exp(&self) -> Var {
+        Var::record(self.value().exp(), Op::Exp(self.clone()))
+    }
+
+    pub fn ln(&self) -> Var {
+        Var::record(self.value().ln(), Op::Ln(self.clone()))
+    }
+
+    // elementwise max(x, c), gradient goes to elements where x >= c
+    pub fn maximum(&self, c: f32) -> Var {
+        Var::record(self.value().apply_unary(|x| x.max(c)), Op::Maximum(self.clone(), c))
+    }
+
+    // sum of all elements, as a 0-d Var
+    pub fn sum(&self) -> Var {
+        Var::record(Matrix::from_vec_nd(&[], vec![self.value().sum()]), Op::Sum(self.clone()))
+    }
+
+    pub fn mean(&self) -> Var {
+        &self.sum() * (1.0 / self.value().numel() as f32)
+    }
+
+    pub fn sum_axis(&self, axis: usize, keepdim: bool) -> Var {
+        Var::record(self.value().sum_axis(axis, keepdim), Op::SumAxis(self.clone(), axis, keepdim))
+    }
+
+    pub fn mean_axis(&self, axis: usize, keepdim: bool) -> Var {
+        &self.sum_axis(axis, keepdim) * (1.0 / self.shape()[axis] as f32)
+    }
+
+    pub fn reshape(&self, shape: &[usize]) -> Var {
+        Var::record(self.value().reshape(shape), Op::Reshape(self.clone()))
+    }
+
+    #[allow(non_snake_case)]
+    pub fn T(&self) -> Var {
+        Var::record(self.value().T(), Op::Transpose(self.clone()))
+    }
+}
+
+
+// OP overloads
+impl Add<&Var> for &Var {
+    type Output = Var;
+    fn add(self, rhs: &Var) -> Var {
+        Var::record(self.value() + rhs.value(), Op::Add(self.clone(), rhs.clone()))
+    }
+}
+impl Add<f32> for &Var {
+    type Output = Var;
+    fn add(self, rhs: f32) -> Var {
+        Var::record(self.value() + rhs, Op::AddScalar(self.clone()))
+    }
+}
+
+impl Sub<&Var> for &Var {
+    type Output = Var;
+    fn sub(self, rhs: &Var) -> Var {
+        Var::record(self.value() - rhs.value(), Op::Sub(self.clone(), rhs.clone()))
+    }
+}
+impl Sub<f32> for &Var {
+    type Output = Var;
+    fn sub(self, rhs: f32) -> Var {
+        Var::record(self.value() - rhs, Op::AddScalar(self.clone()))
+    }
+}
+
+impl Mul<&Var> for &Var {
+    type Output = Var;
+    fn mul(self, rhs: &Var) -> Var {
+        Var::record(self.value() * rhs.value(), Op::Mul(self.clone(), rhs.clone()))
+    }
+}
+impl Mul<f32> for &Var {
+    type Output = Var;
+    fn mul(self, rhs: f32) -> Var {
+        Var::record(self.value() * rhs, Op::MulScalar(self.clone(), rhs))
+    }
+}
+
+impl Div<&Var> for &Var {
+    type Output = Var;
+    fn div(self, rhs: &Var) -> Var {
+        Var::record(self.value() / rhs.value(), Op::Div(self.clone(), rhs.clone()))
+    }
+}
+impl Div<f32> for &Var {
+    type Output = Var;
+    fn div(self, rhs: f32) -> Var {
+        Var::record(self.value() / rhs, Op::MulScalar(self.clone(), 1.0 / rhs))
+    }
+}
+
+impl Neg for &Var {
+    type Output = Var;
+    fn neg(self) -> Var {
+        Var::record(-self.value(), Op::Neg(self.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::gradcheck_var;
+    use crate::optimizer::{Optimizer, SGD};
+    use crate::parameter::Parameter;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn uniform(shape: &[usize], lo: f32, hi: f32, rng: &mut StdRng) -> Matrix {
+        &((hi - lo) * &Matrix::random_nd(shape, rng)) + lo
+    }
+
+    fn assert_grads(errors: Vec<f32>) {
+        for (i, err) in errors.into_iter().enumerate() {
+            assert!(err < 1e-2, "Gradient of input {i} off by {err}.");
+        }
+    }
+
+    #[test]
+    fn broadcast_add_and_mul() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // [3, 4] with a row, a column and a 1-d vector, all unbroadcast on the way back
+        let inputs = [[3, 4].as_slice(), &[1, 4], &[3, 1], &[4]].map(|s| uniform(s, -1.0, 1.0, &mut rng));
+        let w = Var::constant(uniform(&[3, 4], -1.0, 1.0, &mut rng));
+        assert_grads(gradcheck_var(|v| (&(&(&(&v[0] + &v[1]) * &v[2]) - &v[3]) * &w).sum(), &inputs, 1e-2));
+    }
+
+    #[test]
+    fn div_exp_ln() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let inputs = [uniform(&[2, 3], -1.0, 1.0, &mut rng), uniform(&[2, 1], 1.0, 2.0, &mut rng)];
+        assert_grads(gradcheck_var(|v| (&(&v[0] / &v[1]).exp() + &(-&v[1]).exp().ln()).mean(), &inputs, 1e-2));
+    }
+
+    #[test]
+    fn matmul() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let inputs = [uniform(&[3, 5], -1.0, 1.0, &mut rng), uniform(&[5, 2], -1.0, 1.0, &mut rng)];
+        let w = Var::constant(uniform(&[3, 2], -1.0, 1.0, &mut rng));
+        assert_grads(gradcheck_var(|v| (&v[0].matmul(&v[1]) * &w).sum(), &inputs, 1e-2));
+    }
+
+    #[test]
+    fn maximum() {
+        // kept away from the kink at 0.3
+        let x = Matrix::from_vec(2, 3, vec![-0.5, 0.1, 0.35, 0.9, 0.6, -0.2]);
+        let w = Var::constant(Matrix::from_vec(2, 3, vec![1.0, -2.0, 0.5, 3.0, -1.0, 2.0]));
+        let leaf = Var::leaf(x);
+        assert_grads(gradcheck_var(|v| (&v[0].maximum(0.3) * &w).sum(), &[leaf.value().clone()], 1e-2));
+        (&leaf.maximum(0.3) * &w).sum().backward();
+        assert_eq!(leaf.grad().unwrap().to_vec(), vec![0.0, 0.0, 0.5, 3.0, -1.0, 0.0]);
+    }
+
+    #[test]
+    fn sum_axis_reshape_transpose() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let inputs = [uniform(&[2, 3, 4], -1.0, 1.0, &mut rng)];
+        let w = Var::constant(uniform(&[2, 4], -1.0, 1.0, &mut rng));
+        let u = Var::constant(uniform(&[4, 6], -1.0, 1.0, &mut rng));
+        // keepdim, without keepdim and a mean, all weighted so every element gets its own gradient
+        assert_grads(gradcheck_var(|v| (&v[0].sum_axis(1, true).reshape(&[2, 4]) * &w).sum(), &inputs, 1e-2));
+        assert_grads(gradcheck_var(|v| (&(&v[0].sum_axis(1, false) * &w).mean_axis(0, false) * &w.sum_axis(0, false)).sum(), &inputs, 1e-2));
+        assert_grads(gradcheck_var(|v| (&(&v[0] * &v[0]).reshape(&[6, 4]).T() * &u).sum(), &inputs, 1e-2));
+    }
+
+    #[test]
+    fn shared_nodes_sum_their_gradients() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let inputs = [uniform(&[2, 2], -1.0, 1.0, &mut rng), uniform(&[2, 2], -1.0, 1.0, &mut rng)];
+        // h feeds two ops and a feeds three
+        let f = |v: &[Var]| {
+            let h = &v[0] * &v[1];
+            (&(&h.exp() + &(&h * &v[0])) - &v[0].T()).sum()
+        };
+        assert_grads(gradcheck_var(f, &inputs, 1e-2));
+
+        // y = x + x, dy/dx = 2
+        let x = Var::leaf(Matrix::full(1, 1, 3.0));
+        (&x + &x).sum().backward();
+        assert_eq!(x.grad().unwrap().to_vec(), vec![2.0]);
+    }
+
+    #[test]
+    fn parameter_round_trip() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut weight = Parameter::new(uniform(&[3, 2], -1.0, 1.0, &mut rng));
+        let x = Var::constant(uniform(&[4, 3], -1.0, 1.0, &mut rng));
+        let target = Var::constant(uniform(&[4, 2], -1.0, 1.0, &mut rng));
+        let loss = |w: &Var| {
+            let diff = &x.matmul(w) - &target;
+            (&diff * &diff).mean()
+        };
+
+        let w = weight.var();
+        let before = loss(&w).item();
+        loss(&w).backward();
+        weight.accumulate_grad(&w);
+        assert_grads(gradcheck_var(|v| loss(&v[0]), &[weight.data.clone()], 1e-2));
+        assert_eq!(weight.grad.to_vec(), w.grad().unwrap().to_vec());
+
+        let data = weight.data.clone();
+        SGD::new(0.1, 0.0).step(vec![&mut weight]);
+        let expected = &data - &(0.1 * &w.grad().unwrap());
+        assert_eq!(weight.data.to_vec(), expected.to_vec());
+        assert!(loss(&weight.var()).item() < before);
+    }
+
+    #[test]
+    #[should_panic(expected = "backward() on Var of shape [2, 2], use backward_with for non-scalars.")]
+    fn backward_needs_a_scalar() {
+        Var::leaf(Matrix::full(2, 2, 1.0)).backward();
+    }
+}
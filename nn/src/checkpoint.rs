@@ -0,0 +1,332 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+
+use crate::layer::Layer;
+use crate::lr_scheduler::Scheduler;
+use crate::matrix::Matrix;
+use crate::optimizer::Optimizer;
+use crate::state::{StateDict, StateDictError};
+
+const MAGIC: &[u8; 4] = b"NNCK";
+const VERSION: u32 = 1;
+// magic, version, payload length, crc32 of the payload
+const HEADER_LEN: usize = 4 + 4 + 8 + 4;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    ChecksumMismatch,
+    // the checksum was fine but the payload doesn't parse
+    Corrupt(&'static str),
+    State(StateDictError),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Error reading checkpoint: {e}"),
+            Self::BadMagic => write!(f, "Not a checkpoint file."),
+            Self::UnsupportedVersion(v) => write!(f, "Unsupported checkpoint version {v}, expected {VERSION}."),
+            Self::ChecksumMismatch => write!(f, "Checkpoint checksum mismatch, the file is damaged."),
+            Self::Corrupt(what) => write!(f, "Corrupt checkpoint: {what}."),
+            Self::State(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for CheckpointError {}
+
+impl From<io::Error> for CheckpointError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<StateDictError> for CheckpointError {
+    fn from(e: StateDictError) -> Self {
+        Self::State(e)
+    }
+}
+
+// Everything needed to resume a training run where it stopped: model weights and buffers,
+// optimizer and scheduler state, the training rng and the number of finished epochs.
+// The rngs of dropout layers are model buffers, so they come with the model state.
+//
+// File layout, all little endian:
+//   header   "NNCK", version u32, payload length u64, crc32 of the payload u32
+//   payload  epoch u64, rng seed [u8; 32], stream u64, word position u128,
+//            then the model, optimizer and scheduler state dicts, each as
+//            count u32 and per entry name length u32, name, ndim u32, dims u64s, f32 data
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub epoch: usize,
+    pub model: StateDict,
+    pub optimizer: StateDict,
+    pub scheduler: StateDict,
+    pub rng: ChaCha12Rng,
+}
+
+impl Checkpoint {
+    pub fn new(epoch: usize, model: &mut dyn Layer, optimizer: &impl Optimizer, scheduler: &impl Scheduler, rng: &ChaCha12Rng) -> Self {
+        Self {
+            epoch,
+            model: model.state_dict(),
+            optimizer: optimizer.state_dict(),
+            scheduler: scheduler.state_dict(),
+            rng: rng.clone(),
+        }
+    }
+
+    // loads the states into freshly built model, optimizer and scheduler,
+    // `epoch` and `rng` are for the caller to pick up
+    pub fn restore(&self, model: &mut dyn Layer, optimizer: &mut impl Optimizer, scheduler: &mut impl Scheduler) -> Result<(), CheckpointError> {
+        model.load_state_dict(&self.model)?;
+        optimizer.load_state_dict(&self.optimizer)?;
+        scheduler.load_state_dict(&self.scheduler)?;
+        Ok(())
+    }
+
+    // writes `<path>.tmp` first and renames it over `path`, so a crash while writing
+    // leaves the previous checkpoint intact
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.to_bytes())?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CheckpointError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        payload.extend((self.epoch as u64).to_le_bytes());
+        payload.extend(self.rng.get_seed());
+        payload.extend(self.rng.get_stream().to_le_bytes());
+        payload.extend(self.rng.get_word_pos().to_le_bytes());
+        for state in [&self.model, &self.optimizer, &self.scheduler] {
+            write_state(&mut payload, state);
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend(MAGIC);
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend((payload.len() as u64).to_le_bytes());
+        bytes.extend(crc32(&payload).to_le_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CheckpointError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(CheckpointError::BadMagic);
+        }
+        let mut header = Reader { bytes: &bytes[MAGIC.len()..] };
+        let version = header.u32()?;
+        if version != VERSION {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+        let len = header.u64()? as usize;
+        let checksum = header.u32()?;
+        if header.bytes.len() != len {
+            return Err(CheckpointError::Corrupt("payload length doesn't match the header"));
+        }
+        if crc32(header.bytes) != checksum {
+            return Err(CheckpointError::ChecksumMismatch);
+        }
+
+        let mut payload = Reader { bytes: header.bytes };
+        let epoch = payload.u64()? as usize;
+        let mut rng = ChaCha12Rng::from_seed(payload.take(32)?.try_into().unwrap());
+        rng.set_stream(payload.u64()?);
+        rng.set_word_pos(u128::from_le_bytes(payload.take(16)?.try_into().unwrap()));
+        let model = read_state(&mut payload)?;
+        let optimizer = read_state(&mut payload)?;
+        let scheduler = read_state(&mut payload)?;
+        if !payload.bytes.is_empty() {
+            return Err(CheckpointError::Corrupt("trailing bytes"));
+        }
+        Ok(Self { epoch, model, optimizer, scheduler, rng })
+    }
+}
+
+fn write_state(bytes: &mut Vec<u8>, state: &StateDict) {
+    bytes.extend((state.len() as u32).to_le_bytes());
+    for (name, m) in state {
+        bytes.extend((name.len() as u32).to_le_bytes());
+        bytes.extend(name.as_bytes());
+        bytes.extend((m.ndim() as u32).to_le_bytes());
+        for &dim in &m.shape {
+            bytes.extend((dim as u64).to_le_bytes());
+        }
+        for x in m.iter() {
+            bytes.extend(x.to_le_bytes());
+        }
+    }
+}
+
+fn read_state(reader: &mut Reader) -> Result<StateDict, CheckpointError> {
+    let mut state = StateDict::new();
+    for _ in 0..reader.u32()? {
+        let len = reader.u32()? as usize;
+        let name = String::from_utf8(reader.take(len)?.to_vec())
+            .map_err(|_| CheckpointError::Corrupt("name isn't utf-8"))?;
+        let ndim = reader.u32()? as usize;
+        let shape = (0..ndim).map(|_| reader.u64().map(|d| d as usize)).collect::<Result<Vec<_>, _>>()?;
+        let numel = shape.iter().try_fold(1usize, |n, &d| n.checked_mul(d))
+            .ok_or(CheckpointError::Corrupt("shape overflows"))?;
+        let data = reader.take(numel.checked_mul(4).ok_or(CheckpointError::Corrupt("shape overflows"))?)?
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        state.insert(name, Matrix::from_vec_nd(&shape, data));
+    }
+    Ok(state)
+}
+
+// consumes little endian values from the front of a byte slice
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8], CheckpointError> {
+        if n > self.bytes.len() {
+            return Err(CheckpointError::Corrupt("unexpected end of data"));
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, CheckpointError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, CheckpointError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+// CRC-32 (IEEE, as in zip and png), bitwise since checkpoints are only written once per epoch
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::one_hot;
+    use crate::loss::{Crossentropy, Loss};
+    use crate::lr_scheduler::ExponentialDecay;
+    use crate::models::MLP;
+    use crate::optimizer::Adam;
+    use rand::Rng;
+
+    fn setup(seed: u64) -> (MLP, Adam, ExponentialDecay, ChaCha12Rng) {
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        let mut model = MLP::builder(&[4, 5, 3]).with_batch_norm(true).with_dropout(0.5).build(&mut rng);
+        let optim = Adam::new(model.parameters(), 0.01, 0.9, 0.999, 1e-8, 0.0);
+        let sch = ExponentialDecay::new(0.1, &optim);
+        (model, optim, sch, rng)
+    }
+
+    // one epoch of a single batch drawn from the rng
+    fn epoch(model: &mut MLP, optim: &mut Adam, sch: &mut ExponentialDecay, rng: &mut ChaCha12Rng) {
+        let x = Matrix::random(6, 4, rng);
+        let labels: Vec<usize> = (0..6).map(|_| rng.gen_range(0..3)).collect();
+        let mut loss_fn = Crossentropy::new();
+        loss_fn.forward(&model.forward(&x), &one_hot(&labels, 3));
+        optim.zero_grad(model.parameters());
+        model.backward(&loss_fn.backward(1.0));
+        optim.step(model.parameters());
+        sch.step(optim);
+    }
+
+    #[test]
+    fn resumes_exactly() {
+        let (mut model, mut optim, mut sch, mut rng) = setup(0);
+        for _ in 0..2 {
+            epoch(&mut model, &mut optim, &mut sch, &mut rng);
+        }
+        let bytes = Checkpoint::new(2, &mut model, &optim, &sch, &rng).to_bytes();
+        for _ in 0..2 {
+            epoch(&mut model, &mut optim, &mut sch, &mut rng);
+        }
+
+        // a different seed, everything has to come from the checkpoint
+        let (mut resumed, mut r_optim, mut r_sch, _) = setup(1);
+        let checkpoint = Checkpoint::from_bytes(&bytes).unwrap();
+        checkpoint.restore(&mut resumed, &mut r_optim, &mut r_sch).unwrap();
+        let mut r_rng = checkpoint.rng;
+        assert_eq!(checkpoint.epoch, 2);
+        for _ in 0..2 {
+            epoch(&mut resumed, &mut r_optim, &mut r_sch, &mut r_rng);
+        }
+
+        assert_eq!(optim.get_lr(), r_optim.get_lr());
+        assert_eq!(rng.gen::<u64>(), r_rng.gen::<u64>());
+        let (state, r_state) = (model.state_dict(), resumed.state_dict());
+        // linear and batchnorm weights, running stats and the dropout rng
+        assert_eq!(state.len(), 11);
+        for (name, value) in state {
+            assert_eq!(value.to_vec(), r_state[&name].to_vec(), "{name}");
+        }
+    }
+
+    #[test]
+    fn save_replaces_the_previous_file() {
+        let (mut model, optim, sch, rng) = setup(5);
+        let path = std::env::temp_dir().join(format!("nn-checkpoint-{}.bin", std::process::id()));
+        fs::write(&path, b"an older checkpoint").unwrap();
+        Checkpoint::new(3, &mut model, &optim, &sch, &rng).save(&path).unwrap();
+        let loaded = Checkpoint::load(&path);
+        assert!(!path.with_extension("bin.tmp").exists());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap().epoch, 3);
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let (mut model, optim, sch, rng) = setup(2);
+        let bytes = Checkpoint::new(0, &mut model, &optim, &sch, &rng).to_bytes();
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(Checkpoint::from_bytes(&flipped), Err(CheckpointError::ChecksumMismatch)));
+        assert!(matches!(Checkpoint::from_bytes(&bytes[..bytes.len() - 1]), Err(CheckpointError::Corrupt(_))));
+        assert!(matches!(Checkpoint::from_bytes(b"PK\x03\x04"), Err(CheckpointError::BadMagic)));
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert!(matches!(Checkpoint::from_bytes(&newer), Err(CheckpointError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn restore_checks_architecture() {
+        let (mut model, optim, sch, rng) = setup(3);
+        let checkpoint = Checkpoint::new(0, &mut model, &optim, &sch, &rng);
+        let mut rng = ChaCha12Rng::seed_from_u64(4);
+        let mut other = MLP::new(&[4, 6, 3], &mut rng);
+        let mut other_optim = Adam::new(other.parameters(), 0.01, 0.9, 0.999, 1e-8, 0.0);
+        let mut other_sch = ExponentialDecay::new(0.1, &other_optim);
+        let error = checkpoint.restore(&mut other, &mut other_optim, &mut other_sch).unwrap_err();
+        assert!(matches!(error, CheckpointError::State(e) if !e.unexpected.is_empty() && !e.mismatched.is_empty()));
+    }
+}
@@ -0,0 +1,302 @@
+use rand::Rng;
+
+use crate::layer::Layer;
+use crate::matrix::Matrix;
+use crate::parameter::Parameter;
+
+// 2D convolution over [batch, chan, height, width] inputs, square kernels.
+// Forward unrolls every receptive field into a row (im2col) so the whole
+// convolution is a single matmul with the [out_chan, in_chan * k * k] weight.
+pub struct Conv2d {
+    weight: Parameter,
+    bias: Option<Parameter>,
+    in_chan: usize,
+    out_chan: usize,
+    kernel_size: usize,
+    stride: usize,
+    padding: usize,
+    dilation: usize,
+    cols: Option<Matrix>,
+    input_shape: Option<Vec<usize>>,
+}
+
+impl Conv2d {
+    // stride 1, no padding, no dilation, see the `with_` methods to change those
+    pub fn new<R: Rng>(in_chan: usize, out_chan: usize, kernel_size: usize, bias: bool, rng: &mut R) -> Self {
+        let fan_in = in_chan * kernel_size * kernel_size;
+        let bound = 1.0 / (fan_in as f32).sqrt(); // kaiming unif bound for relu
+        let shape = [out_chan, in_chan, kernel_size, kernel_size];
+        Self {
+            weight: Parameter::new(bound - &((2.0*bound) * &Matrix::random_nd(&shape, rng))),
+            bias: if bias {
+                Some(Parameter::new(Matrix::full(1, out_chan, 0.0)))
+            } else { None },
+            in_chan, out_chan, kernel_size,
+            stride: 1,
+            padding: 0,
+            dilation: 1,
+            cols: None,
+            input_shape: None,
+        }
+    }
+
+    pub fn with_stride(mut self, stride: usize) -> Self {
+        if stride == 0 { panic!("Stride must be positive.") }
+        self.stride = stride;
+        self
+    }
+
+    // zero padding added on every side
+    pub fn with_padding(mut self, padding: usize) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn with_dilation(mut self, dilation: usize) -> Self {
+        if dilation == 0 { panic!("Dilation must be positive.") }
+        self.dilation = dilation;
+        self
+    }
+
+    fn geometry(&self, input_shape: &[usize]) -> ConvGeometry {
+        if input_shape.len() != 4 || input_shape[1] != self.in_chan {
+            panic!("Conv2d expects [batch, {}, height, width] input, got {input_shape:?}.", self.in_chan);
+        }
+        ConvGeometry::new(
+            input_shape,
+            self.kernel_size,
+            self.stride,
+            self.padding,
+            self.dilation,
+        )
+    }
+
+    fn weight_2d(&self) -> Matrix {
+        self.weight.data.reshape(&[self.out_chan, self.in_chan * self.kernel_size * self.kernel_size])
+    }
+}
+
+impl Layer for Conv2d {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let g = self.geometry(&input.shape);
+        let cols = g.im2col(input);
+
+        // [batch * h_out * w_out, out_chan] -> [batch, out_chan, h_out, w_out]
+        let mut y = cols.matmul(&self.weight_2d().T());
+        if let Some(bias) = &self.bias {
+            y = &y + &bias.data;
+        }
+        self.cols = Some(cols);
+        self.input_shape = Some(input.shape.clone());
+        y.reshape(&[g.batch, g.h_out, g.w_out, self.out_chan]).permute(&[0, 3, 1, 2])
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let cols = self.cols.as_ref().expect("Cannot call backward before forward.");
+        let g = self.geometry(self.input_shape.as_ref().unwrap());
+
+        // back to the layout of the matmul output
+        let partial = partial.permute(&[0, 2, 3, 1]).reshape(&[g.batch * g.h_out * g.w_out, self.out_chan]);
+        let weight_grad = partial.T().matmul(cols).reshape(&self.weight.data.shape);
+        self.weight.grad = &self.weight.grad + &weight_grad;
+        if let Some(bias) = &mut self.bias {
+            bias.grad = &bias.grad + &partial.sum_axis(0, true);
+        }
+        g.col2im(&partial.matmul(&self.weight_2d()))
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        match &mut self.bias {
+            Some(bias) => vec![&mut self.weight, bias],
+            None => vec![&mut self.weight]
+        }
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut params = vec![("weight".to_string(), &mut self.weight)];
+        if let Some(bias) = &mut self.bias {
+            params.push(("bias".to_string(), bias));
+        }
+        params
+    }
+}
+
+// Output size and index bookkeeping for sliding a kernel over [batch, chan, height, width].
+// Shared by convolutions and pooling.
+pub(crate) struct ConvGeometry {
+    pub batch: usize,
+    pub chan: usize,
+    pub h_in: usize,
+    pub w_in: usize,
+    pub h_out: usize,
+    pub w_out: usize,
+    pub kernel_size: usize,
+    pub stride: usize,
+    pub padding: usize,
+    pub dilation: usize,
+}
+
+impl ConvGeometry {
+    pub fn new(input_shape: &[usize], kernel_size: usize, stride: usize, padding: usize, dilation: usize) -> Self {
+        let (batch, chan, h_in, w_in) = (input_shape[0], input_shape[1], input_shape[2], input_shape[3]);
+        let span = dilation * (kernel_size - 1) + 1;
+        if h_in + 2 * padding < span || w_in + 2 * padding < span {
+            panic!("Kernel of span {span} does not fit into padded input {input_shape:?}.");
+        }
+        Self {
+            batch, chan, h_in, w_in,
+            h_out: (h_in + 2 * padding - span) / stride + 1,
+            w_out: (w_in + 2 * padding - span) / stride + 1,
+            kernel_size, stride, padding, dilation,
+        }
+    }
+
+    // input position read by output (oy, ox) at kernel offset (ky, kx), None if it falls into padding
+    pub fn source(&self, oy: usize, ox: usize, ky: usize, kx: usize) -> Option<(usize, usize)> {
+        let y = (oy * self.stride + ky * self.dilation).checked_sub(self.padding)?;
+        let x = (ox * self.stride + kx * self.dilation).checked_sub(self.padding)?;
+        if y < self.h_in && x < self.w_in { Some((y, x)) } else { None }
+    }
+
+    // [batch, chan, h, w] -> [batch * h_out * w_out, chan * k * k], padding reads as 0
+    pub fn im2col(&self, input: &Matrix) -> Matrix {
+        let x = input.to_vec();
+        let k = self.kernel_size;
+        let row_len = self.chan * k * k;
+        let mut cols = vec![0.0; self.batch * self.h_out * self.w_out * row_len];
+        for (row, out) in cols.chunks_mut(row_len).enumerate() {
+            let (n, oy, ox) = (row / (self.h_out * self.w_out), (row / self.w_out) % self.h_out, row % self.w_out);
+            for c in 0..self.chan {
+                let plane = (n * self.chan + c) * self.h_in * self.w_in;
+                for ky in 0..k {
+                    for kx in 0..k {
+                        if let Some((y, x_)) = self.source(oy, ox, ky, kx) {
+                            out[(c * k + ky) * k + kx] = x[plane + y * self.w_in + x_];
+                        }
+                    }
+                }
+            }
+        }
+        Matrix::from_vec(self.batch * self.h_out * self.w_out, row_len, cols)
+    }
+
+    // adjoint of im2col, overlapping receptive fields add up
+    pub fn col2im(&self, cols: &Matrix) -> Matrix {
+        let cols = cols.to_vec();
+        let k = self.kernel_size;
+        let row_len = self.chan * k * k;
+        let mut x = vec![0.0; self.batch * self.chan * self.h_in * self.w_in];
+        for (row, col) in cols.chunks(row_len).enumerate() {
+            let (n, oy, ox) = (row / (self.h_out * self.w_out), (row / self.w_out) % self.h_out, row % self.w_out);
+            for c in 0..self.chan {
+                let plane = (n * self.chan + c) * self.h_in * self.w_in;
+                for ky in 0..k {
+                    for kx in 0..k {
+                        if let Some((y, x_)) = self.source(oy, ox, ky, kx) {
+                            x[plane + y * self.w_in + x_] += col[(c * k + ky) * k + kx];
+                        }
+                    }
+                }
+            }
+        }
+        Matrix::from_vec_nd(&[self.batch, self.chan, self.h_in, self.w_in], x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{gradcheck, one_hot};
+    use crate::layer::{Flatten, Sequential};
+    use crate::loss::Crossentropy;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // direct sum over the kernel, no im2col
+    fn naive_conv(conv: &mut Conv2d, x: &Matrix) -> Matrix {
+        let g = conv.geometry(&x.shape);
+        let w = conv.weight.data.clone();
+        let mut y = Matrix::full_nd(&[g.batch, conv.out_chan, g.h_out, g.w_out], 0.0);
+        for n in 0..g.batch {
+            for o in 0..conv.out_chan {
+                for oy in 0..g.h_out {
+                    for ox in 0..g.w_out {
+                        let mut acc = conv.bias.as_ref().map_or(0.0, |b| b.data.get(0, o));
+                        for c in 0..g.chan {
+                            for ky in 0..g.kernel_size {
+                                for kx in 0..g.kernel_size {
+                                    if let Some((iy, ix)) = g.source(oy, ox, ky, kx) {
+                                        acc += w.get_nd(&[o, c, ky, kx]) * x.get_nd(&[n, c, iy, ix]);
+                                    }
+                                }
+                            }
+                        }
+                        y.set_nd(&[n, o, oy, ox], acc);
+                    }
+                }
+            }
+        }
+        y
+    }
+
+    #[test]
+    fn forward_matches_direct_convolution() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut conv = Conv2d::new(3, 4, 3, true, &mut rng).with_stride(2).with_padding(1).with_dilation(2);
+        conv.bias.as_mut().unwrap().data = Matrix::random(1, 4, &mut rng);
+        let x = Matrix::random_nd(&[2, 3, 7, 6], &mut rng);
+        let y = conv.forward(&x);
+        assert_eq!(y.shape, vec![2, 4, 3, 2]);
+        for (a, b) in y.iter().zip(naive_conv(&mut conv, &x).iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn output_shape() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let x = Matrix::random_nd(&[1, 3, 32, 32], &mut rng);
+        assert_eq!(Conv2d::new(3, 8, 3, true, &mut rng).with_padding(1).forward(&x).shape, vec![1, 8, 32, 32]);
+        assert_eq!(Conv2d::new(3, 8, 3, true, &mut rng).with_stride(2).with_padding(1).forward(&x).shape, vec![1, 8, 16, 16]);
+        assert_eq!(Conv2d::new(3, 8, 5, false, &mut rng).forward(&x).shape, vec![1, 8, 28, 28]);
+        assert_eq!(Conv2d::new(3, 8, 3, false, &mut rng).with_dilation(3).forward(&x).shape, vec![1, 8, 26, 26]);
+    }
+
+    fn check(conv: Conv2d, input_shape: &[usize], seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let x = &(2.0 * &Matrix::random_nd(input_shape, &mut rng)) - 1.0;
+        let mut model = Sequential::new(vec![Box::new(conv), Box::new(Flatten::new())]);
+        let classes = model.forward(&x).cols();
+        let labels: Vec<usize> = (0..input_shape[0]).map(|i| (i * 7) % classes).collect();
+        gradcheck(&mut model, &mut Crossentropy::new(), &x, &one_hot(&labels, classes), 1e-2).assert_below(1e-2);
+    }
+
+    #[test]
+    fn gradients() {
+        let mut rng = StdRng::seed_from_u64(0);
+        check(Conv2d::new(2, 3, 3, true, &mut rng), &[2, 2, 5, 5], 1);
+    }
+
+    #[test]
+    fn gradients_without_bias() {
+        let mut rng = StdRng::seed_from_u64(0);
+        check(Conv2d::new(2, 3, 2, false, &mut rng), &[2, 2, 4, 5], 2);
+    }
+
+    #[test]
+    fn gradients_with_stride_padding_dilation() {
+        let mut rng = StdRng::seed_from_u64(0);
+        check(Conv2d::new(2, 2, 3, true, &mut rng).with_stride(2).with_padding(1), &[2, 2, 5, 6], 3);
+        check(Conv2d::new(1, 2, 2, true, &mut rng).with_dilation(2).with_padding(2), &[1, 1, 4, 4], 4);
+    }
+
+    #[test]
+    fn parameters_reach_sequential() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut model = Sequential::new(vec![
+            Box::new(Conv2d::new(3, 4, 3, true, &mut rng)),
+            Box::new(Conv2d::new(4, 4, 3, false, &mut rng)),
+        ]);
+        let shapes: Vec<Vec<usize>> = model.parameters().iter().map(|p| p.data.shape.clone()).collect();
+        assert_eq!(shapes, vec![vec![4, 3, 3, 3], vec![1, 4], vec![4, 4, 3, 3]]);
+    }
+}
@@ -3,43 +3,59 @@ use rand::{seq::SliceRandom, Rng};
 
 use crate::matrix::Matrix;
 
+// no is_empty, datasets are never empty
+#[allow(clippy::len_without_is_empty)]
 pub trait Dataset {
     fn len(&self) -> usize;
-    fn get_sample(&self, index: usize) -> (&Vec<f32>, &Vec<f32>);
-    fn batch_iter(&self, batch_size: usize) -> BatchIter;
+    fn get_sample(&self, index: usize) -> (&[f32], &[f32]);
+    fn batch_iter(&self, batch_size: usize) -> BatchIter<'_>;
 }
 
 pub struct CIFAR10 {
     pub images: Vec<Vec<f32>>,
-    pub labels: Vec<Vec<f32>>,
+    // all labels back to back, label_width values each
+    pub labels: Vec<f32>,
+    pub label_width: usize,
 }
 
 impl CIFAR10 {
+    // one-hot labels, for Crossentropy
     pub fn new(files: Vec<&str>) -> Self {
+        Self::load(files, true)
+    }
+
+    // labels as a single class index, for SparseCrossentropy
+    pub fn with_class_indices(files: Vec<&str>) -> Self {
+        Self::load(files, false)
+    }
+
+    fn load(files: Vec<&str>, one_hot: bool) -> Self {
         let mut images = vec![];
         let mut labels = vec![];
         for file in files {
-            let (mut x, mut y) = Self::parse_file(file);
+            let (mut x, mut y) = Self::parse_file(file, one_hot);
             images.append(&mut x);
             labels.append(&mut y);
         }
-        Self { images, labels }
+        Self { images, labels, label_width: if one_hot { 10 } else { 1 } }
     }
 
     // read bytes and split them into image and label bytes
-    fn parse_file(file: &str) -> (Vec<Vec<f32>>, Vec<Vec<f32>>) {
+    fn parse_file(file: &str, one_hot: bool) -> (Vec<Vec<f32>>, Vec<f32>) {
         let bytes = read(file).unwrap();
         let mut x = vec![];
         let mut y = vec![];
         for chunk in bytes.chunks(3073) {
-            // one-hot encode label
+            // one-hot encode label, or keep the class index
             let label = chunk[0] as usize;
-            let mut oh = vec![0.0f32; 10];
-            oh[label] = 1.0;
-            y.push(oh);
+            if one_hot {
+                y.extend((0..10).map(|c| if c == label { 1.0 } else { 0.0 }));
+            } else {
+                y.push(label as f32);
+            }
             
             // convert image to f32 in range [0, 1]
-            let mut img = chunk[1..]
+            let img = chunk[1..]
                 .iter().map( |x| (*x as f32) / 255.0 ).collect();
             x.push(img);
         }
@@ -52,14 +68,14 @@ impl Dataset for CIFAR10 {
         self.images.len()
     }
 
-    fn get_sample(&self, index: usize) -> (&Vec<f32>, &Vec<f32>) {
+    fn get_sample(&self, index: usize) -> (&[f32], &[f32]) {
         (
             &self.images[index],
-            &self.labels[index],
+            &self.labels[index * self.label_width..(index + 1) * self.label_width],
         )
     }
 
-    fn batch_iter(&self, batch_size: usize) -> BatchIter {
+    fn batch_iter(&self, batch_size: usize) -> BatchIter<'_> {
         BatchIter::new(batch_size, self)
     }
 }
@@ -94,8 +110,8 @@ impl Iterator for BatchIter<'_> {
         let mut y_batch = vec![];
         for i in self.current..self.current+self.batch_size {
             let (x, y) = self.dataset.get_sample(self.indexes[i]);
-            x_batch.append(&mut x.clone());
-            y_batch.append(&mut y.clone());
+            x_batch.extend_from_slice(x);
+            y_batch.extend_from_slice(y);
         }
         self.current += self.batch_size;
         
@@ -0,0 +1,204 @@
+use rand::Rng;
+
+use crate::layer::Layer;
+use crate::matrix::Matrix;
+use crate::parameter::Parameter;
+
+// Embedding layer - lookup table from integer ids to vectors.
+// Input is a matrix of ids of any shape, e.g. [batch, seq], the output gets an extra
+// trailing dim, [batch, seq, embedding_dim]. Only the rows that were looked up get gradient.
+pub struct Embedding {
+    weight: Parameter,
+    padding_idx: Option<usize>,
+    ids: Option<Vec<usize>>,
+}
+
+impl Embedding {
+    // `padding_idx` row starts at zero and never gets gradient, for padding tokens
+    pub fn new<R: Rng>(num_embeddings: usize, embedding_dim: usize, padding_idx: Option<usize>, rng: &mut R) -> Self {
+        let bound = 3f32.sqrt(); // unit variance, same as torch's N(0, 1)
+        let mut weight = bound - &((2.0*bound) * &Matrix::random(num_embeddings, embedding_dim, rng));
+        if let Some(idx) = padding_idx {
+            if idx >= num_embeddings { panic!("Padding index {idx} out of range for {num_embeddings} embeddings.") }
+            for col in 0..embedding_dim {
+                weight.set(idx, col, 0.0);
+            }
+        }
+        Self { weight: Parameter::new(weight), padding_idx, ids: None }
+    }
+
+    // keep track of the rows used since the last zero_grad, Adam then only updates those
+    pub fn with_sparse_grad(mut self) -> Self {
+        self.weight.touched_rows = Some(vec![]);
+        self
+    }
+
+    pub fn weight(&self) -> &Matrix {
+        &self.weight.data
+    }
+}
+
+impl Layer for Embedding {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let num = self.weight.data.rows();
+        let ids: Vec<usize> = input.iter()
+            .map(|x| {
+                if x < 0.0 || x.fract() != 0.0 || x as usize >= num {
+                    panic!("Embedding index {x} out of range for {num} embeddings.");
+                }
+                x as usize
+            })
+            .collect();
+        let mut out = Vec::with_capacity(ids.len() * self.weight.data.cols());
+        for &id in &ids {
+            out.extend(self.weight.data.get_row(id).iter());
+        }
+        let mut shape = input.shape.clone();
+        shape.push(self.weight.data.cols());
+        self.ids = Some(ids);
+        Matrix::from_vec_nd(&shape, out)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let ids = self.ids.as_ref().expect("Cannot call backward before forward.");
+        let dim = self.weight.data.cols();
+        let mut input_shape = partial.shape.clone();
+        input_shape.pop();
+        let partial = partial.reshape(&[ids.len(), dim]);
+
+        // scatter add the rows of partial into the rows they came from, in place
+        let partial = partial.to_vec();
+        let grad = self.weight.grad.as_mut_slice();
+        for (i, &id) in ids.iter().enumerate() {
+            if Some(id) == self.padding_idx { continue }
+            for (g, p) in grad[id * dim..(id + 1) * dim].iter_mut().zip(&partial[i * dim..(i + 1) * dim]) {
+                *g += p;
+            }
+        }
+        if let Some(rows) = &mut self.weight.touched_rows {
+            rows.extend(ids.iter().filter(|id| Some(**id) != self.padding_idx));
+            rows.sort_unstable();
+            rows.dedup();
+        }
+
+        // ids aren't differentiable
+        Matrix::full_nd(&input_shape, 0.0)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.weight]
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        vec![("weight".to_string(), &mut self.weight)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::{Adam, Optimizer};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn ids(shape: &[usize], ids: &[usize]) -> Matrix {
+        Matrix::from_vec_nd(shape, ids.iter().map(|i| *i as f32).collect())
+    }
+
+    #[test]
+    fn looks_up_rows() {
+        let mut emb = Embedding::new(5, 3, None, &mut StdRng::seed_from_u64(0));
+        let y = emb.forward(&ids(&[2, 2], &[4, 0, 0, 2]));
+        assert_eq!(y.shape, vec![2, 2, 3]);
+        assert_eq!(y.select(0, 0).get_row(0).to_vec(), emb.weight().get_row(4).to_vec());
+        assert_eq!(y.select(0, 1).get_row(1).to_vec(), emb.weight().get_row(2).to_vec());
+    }
+
+    #[test]
+    fn gradient_only_into_used_rows() {
+        let mut emb = Embedding::new(5, 3, Some(1), &mut StdRng::seed_from_u64(1));
+        assert_eq!(emb.weight().get_row(1).to_vec(), vec![0.0; 3]);
+        let x = ids(&[2, 3], &[2, 1, 2, 0, 1, 4]);
+        emb.forward(&x);
+        let partial = Matrix::from_vec_nd(&[2, 3, 3], (0..18).map(|x| x as f32).collect());
+        let input_grad = emb.backward(&partial);
+        assert_eq!(input_grad.shape, x.shape);
+
+        let grad = &emb.parameters()[0].grad;
+        assert_eq!(grad.get_row(0).to_vec(), vec![9.0, 10.0, 11.0]);
+        // padding row and unused row stay zero, repeated ids add up
+        assert_eq!(grad.get_row(1).to_vec(), vec![0.0; 3]);
+        assert_eq!(grad.get_row(2).to_vec(), vec![6.0, 8.0, 10.0]);
+        assert_eq!(grad.get_row(3).to_vec(), vec![0.0; 3]);
+        assert_eq!(grad.get_row(4).to_vec(), vec![15.0, 16.0, 17.0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_out_of_range_ids() {
+        Embedding::new(5, 3, None, &mut StdRng::seed_from_u64(2)).forward(&ids(&[1, 1], &[5]));
+    }
+
+    #[test]
+    fn lazy_adam_only_touches_used_rows() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut sparse = Embedding::new(6, 4, None, &mut rng).with_sparse_grad();
+        let mut dense = Embedding::new(6, 4, None, &mut StdRng::seed_from_u64(3));
+        let mut sparse_adam = Adam::new(sparse.parameters(), 0.1, 0.9, 0.999, 1e-8, 0.0);
+        let mut dense_adam = Adam::new(dense.parameters(), 0.1, 0.9, 0.999, 1e-8, 0.0);
+
+        // first step, the same ids for both
+        for (emb, adam) in [(&mut sparse, &mut sparse_adam), (&mut dense, &mut dense_adam)] {
+            emb.forward(&ids(&[3], &[0, 3, 3]));
+            emb.backward(&Matrix::full(3, 4, 1.0));
+            adam.step(emb.parameters());
+            adam.zero_grad(emb.parameters());
+        }
+        assert_eq!(sparse.weight().to_vec(), dense.weight().to_vec());
+        // zero_grad only clears the touched rows, which are all there is
+        assert_eq!(sparse.parameters()[0].grad.to_vec(), vec![0.0; 24]);
+
+        // second step only uses row 1, dense adam keeps moving rows 0 and 3 on their momentum
+        let before = sparse.weight().clone();
+        for (emb, adam) in [(&mut sparse, &mut sparse_adam), (&mut dense, &mut dense_adam)] {
+            emb.forward(&ids(&[1], &[1]));
+            emb.backward(&Matrix::full(1, 4, 1.0));
+            adam.step(emb.parameters());
+        }
+        for row in [0, 2, 3, 4, 5] {
+            assert_eq!(sparse.weight().get_row(row).to_vec(), before.get_row(row).to_vec());
+        }
+        assert_eq!(sparse.weight().get_row(1).to_vec(), dense.weight().get_row(1).to_vec());
+        assert_ne!(dense.weight().get_row(0).to_vec(), before.get_row(0).to_vec());
+    }
+
+    #[test]
+    fn dense_adam_steps_by_lr_per_element() {
+        // with a constant gradient every Adam step moves by lr * sign(g), rows without gradient stay
+        let mut emb = Embedding::new(4, 2, None, &mut StdRng::seed_from_u64(4));
+        let mut adam = Adam::new(emb.parameters(), 0.1, 0.9, 0.999, 1e-8, 0.0);
+        let before = emb.weight().clone();
+        for step in 1..=2 {
+            adam.zero_grad(emb.parameters());
+            emb.forward(&ids(&[2], &[3, 0]));
+            emb.backward(&Matrix::from_vec(2, 2, vec![1.0, -2.0, -0.5, 3.0]));
+            adam.step(emb.parameters());
+            let moved = (&before - emb.weight()).to_vec();
+            let d = 0.1 * step as f32;
+            let expected = [-d, d, 0.0, 0.0, 0.0, 0.0, d, -d];
+            for (m, e) in moved.iter().zip(expected) {
+                assert!((m - e).abs() < 1e-5, "{moved:?}");
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Adam was built for 1 parameters, got 2.")]
+    fn adam_rejects_parameters_it_wasnt_built_for() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let (mut a, mut b) = (Embedding::new(3, 2, None, &mut rng), Embedding::new(3, 2, None, &mut rng));
+        let mut adam = Adam::new(a.parameters(), 0.1, 0.9, 0.999, 1e-8, 0.0);
+        let mut params = a.parameters();
+        params.append(&mut b.parameters());
+        adam.step(params);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::autograd::Var;
+use crate::layer::Layer;
+use crate::loss::{Loss, MultiLoss};
+use crate::matrix::Matrix;
+
+// Max relative error between analytical and numerical gradients,
+// for the layer input and for each parameter in `parameters()` order.
+#[derive(Debug, Clone)]
+pub struct GradcheckReport {
+    pub input: f32,
+    pub parameters: Vec<f32>,
+}
+
+impl GradcheckReport {
+    pub fn max(&self) -> f32 {
+        self.parameters.iter().fold(self.input, |a, b| a.max(*b))
+    }
+
+    pub fn assert_below(&self, tol: f32) {
+        if self.input > tol {
+            panic!("Input gradient off by {} > {tol}.", self.input);
+        }
+        for (i, err) in self.parameters.iter().enumerate() {
+            if *err > tol { panic!("Gradient of parameter {i} off by {err} > {tol}.") }
+        }
+    }
+}
+
+// Compares `layer.backward` and the Parameter grads it accumulates against central
+// finite differences (f(x + eps) - f(x - eps)) / 2eps of `loss(layer(input), target)`.
+// Everything is f32, so eps should stay around 1e-2 to 1e-3 to keep rounding error down,
+// the smaller end makes stepping over a relu kink less likely in deeper models.
+// Errors below 1e-2 mean the gradient is right.
+pub fn gradcheck<L, F>(layer: &mut L, loss: &mut F, input: &Matrix, target: &Matrix, eps: f32) -> GradcheckReport
+where
+    L: Layer + ?Sized,
+    F: Loss + ?Sized,
+{
+    // analytical
+    for param in layer.parameters() {
+        param.zero_grad();
+    }
+    let y = layer.forward(input);
+    loss.forward(&y, target);
+    let input_grad = layer.backward(&loss.backward(1.0));
+    let param_grads: Vec<Matrix> = layer.parameters().iter().map(|p| p.grad.clone()).collect();
+
+    // numerical
+    let mut eval = |layer: &mut L, x: &Matrix| loss.forward(&layer.forward(x), target);
+    let input_num = numerical_grad(input, |x| eval(layer, x), eps);
+    let mut param_nums = vec![];
+    for i in 0..param_grads.len() {
+        let data = layer.parameters()[i].data.clone();
+        let num = numerical_grad(&data, |d| {
+            layer.parameters()[i].data = d.clone();
+            eval(layer, input)
+        }, eps);
+        layer.parameters()[i].data = data;
+        param_nums.push(num);
+    }
+
+    GradcheckReport {
+        input: max_rel_error(&input_grad, &input_num),
+        parameters: param_grads.iter()
+            .zip(&param_nums)
+            .map(|(a, n)| max_rel_error(a, n))
+            .collect(),
+    }
+}
+
+// Same as `gradcheck` for a loss on its own, returns the max relative error of `backward`.
+pub fn gradcheck_loss<F: Loss + ?Sized>(loss: &mut F, input: &Matrix, target: &Matrix, eps: f32) -> f32 {
+    loss.forward(input, target);
+    let grad = loss.backward(1.0);
+    let num = numerical_grad(input, |x| loss.forward(x, target), eps);
+    max_rel_error(&grad, &num)
+}
+
+// Same for a loss over several inputs, the max relative error of each gradient of `backward_multi`.
+pub fn gradcheck_multi_loss<F: MultiLoss + ?Sized>(loss: &mut F, inputs: &[Matrix], target: Option<&Matrix>, eps: f32) -> Vec<f32> {
+    loss.forward_multi(inputs, target);
+    let grads = loss.backward_multi(1.0);
+    (0..inputs.len()).map(|i| {
+        let num = numerical_grad(&inputs[i], |x| {
+            let mut inputs = inputs.to_vec();
+            inputs[i] = x.clone();
+            loss.forward_multi(&inputs, target)
+        }, eps);
+        max_rel_error(&grads[i], &num)
+    }).collect()
+}
+
+// Checks the gradients autograd leaves in `Var::leaf`s of `inputs` against central differences
+// of `f`, which has to return a single element Var. Max relative error per input.
+pub fn gradcheck_var<F: Fn(&[Var]) -> Var>(f: F, inputs: &[Matrix], eps: f32) -> Vec<f32> {
+    let leaves: Vec<Var> = inputs.iter().cloned().map(Var::leaf).collect();
+    f(&leaves).backward();
+    (0..inputs.len()).map(|i| {
+        let grad = leaves[i].grad().unwrap_or_else(|| Matrix::full_like(&inputs[i], 0.0));
+        let num = numerical_grad(&inputs[i], |x| {
+            let mut consts: Vec<Var> = inputs.iter().cloned().map(Var::constant).collect();
+            consts[i] = Var::constant(x.clone());
+            f(&consts).item()
+        }, eps);
+        max_rel_error(&grad, &num)
+    }).collect()
+}
+
+// central differences of f w.r.t. every element of x
+fn numerical_grad<F: FnMut(&Matrix) -> f32>(x: &Matrix, mut f: F, eps: f32) -> Matrix {
+    let mut grad = vec![];
+    let mut index = vec![0; x.ndim()];
+    for _ in 0..x.numel() {
+        let value = x.get_nd(&index);
+        let mut x_ = x.clone();
+        x_.set_nd(&index, value + eps);
+        let plus = f(&x_);
+        x_.set_nd(&index, value - eps);
+        let minus = f(&x_);
+        grad.push((plus - minus) / (2.0 * eps));
+        next_index(&mut index, &x.shape);
+    }
+    Matrix::from_vec_nd(&x.shape, grad)
+}
+
+// row major increment of a multi index
+fn next_index(index: &mut [usize], shape: &[usize]) {
+    for axis in (0..index.len()).rev() {
+        index[axis] += 1;
+        if index[axis] < shape[axis] { return }
+        index[axis] = 0;
+    }
+}
+
+// |a - n| / max(|a|, |n|), with a floor on the denominator so that
+// near zero gradients are compared absolutely
+fn max_rel_error(analytical: &Matrix, numerical: &Matrix) -> f32 {
+    if analytical.shape != numerical.shape {
+        panic!("Gradient of shape {:?}, expected {:?}.", analytical.shape, numerical.shape);
+    }
+    analytical.iter()
+        .zip(numerical.iter())
+        .map(|(a, n)| (a - n).abs() / a.abs().max(n.abs()).max(1e-2))
+        .fold(0.0, f32::max)
+}
+
+// one-hot targets for the gradient tests of layers
+#[cfg(test)]
+pub(crate) fn one_hot(labels: &[usize], classes: usize) -> Matrix {
+    let mut y = Matrix::full(labels.len(), classes, 0.0);
+    for (i, &label) in labels.iter().enumerate() {
+        y.set(i, label, 1.0);
+    }
+    y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::Linear;
+    use crate::loss::Crossentropy;
+    use crate::parameter::Parameter;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // Linear whose backward forgets the bias and doubles the input grad
+    struct BrokenLinear(Linear);
+
+    impl Layer for BrokenLinear {
+        fn forward(&mut self, input: &Matrix) -> Matrix {
+            self.0.forward(input)
+        }
+        fn backward(&mut self, partial: &Matrix) -> Matrix {
+            let bias_grad = self.0.parameters()[1].grad.clone();
+            let grad = self.0.backward(partial);
+            self.0.parameters()[1].grad = bias_grad;
+            &grad * 2.0
+        }
+        fn parameters(&mut self) -> Vec<&mut Parameter> {
+            self.0.parameters()
+        }
+    }
+
+    #[test]
+    fn catches_wrong_gradients() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut layer = BrokenLinear(Linear::new(5, 3, true, &mut rng));
+        let x = Matrix::random(4, 5, &mut rng);
+        let y = Matrix::from_vec(4, 3, vec![1., 0., 0., 0., 1., 0., 0., 0., 1., 1., 0., 0.]);
+        let report = gradcheck(&mut layer, &mut Crossentropy::new(), &x, &y, 1e-2);
+        assert!(report.input > 0.5);
+        assert!(report.parameters[0] < 1e-2);
+        assert!(report.parameters[1] > 0.5);
+    }
+
+    #[test]
+    fn leaves_parameters_untouched() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut layer = Linear::new(5, 3, true, &mut rng);
+        let before = layer.parameters()[0].data.to_vec();
+        let x = Matrix::random(4, 5, &mut rng);
+        let y = Matrix::from_vec(4, 3, vec![1., 0., 0., 0., 1., 0., 0., 0., 1., 1., 0., 0.]);
+        gradcheck(&mut layer, &mut Crossentropy::new(), &x, &y, 1e-2);
+        assert_eq!(layer.parameters()[0].data.to_vec(), before);
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+
+use crate::layer::{prefixed, Layer};
+use crate::matrix::Matrix;
+use crate::parameter::Parameter;
+
+enum Op {
+    Layer(Box<dyn Layer>),
+    // elementwise sum of inputs of the same shape
+    Add,
+    Concat(usize),
+    // splits along axis into parts of the given sizes, one output per part
+    Split(usize, Vec<usize>),
+}
+
+struct Node {
+    name: String,
+    op: Op,
+    inputs: Vec<String>,
+    // shapes seen by the last forward, to split grads and fill in zeros for unused outputs
+    input_shapes: Vec<Vec<usize>>,
+    output_shapes: Vec<Vec<usize>>,
+}
+
+// Graph layer - a DAG of layers and ops wired by name, for residual connections,
+// multi-branch blocks and models with several inputs or outputs.
+// Nodes refer to graph inputs or other nodes by name, `split:1` is the second output
+// of a multi output node. They can be added in any order and run in topological order,
+// backward runs them in reverse and sums the grads of values used by several nodes.
+// Every layer belongs to exactly one node, so `parameters()` lists each Parameter once.
+pub struct Graph {
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    nodes: Vec<Node>,
+    order: Option<Vec<usize>>,
+    input_shapes: Vec<Vec<usize>>,
+}
+
+// "name:i" -> (name, i), plain "name" is output 0
+fn parse_ref(value: &str) -> (&str, usize) {
+    match value.rsplit_once(':') {
+        Some((name, i)) => match i.parse() {
+            Ok(i) => (name, i),
+            Err(_) => panic!("Invalid output index in {value}."),
+        },
+        None => (value, 0),
+    }
+}
+
+impl Graph {
+    pub fn new(inputs: &[&str]) -> Self {
+        Self {
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            outputs: vec![],
+            nodes: vec![],
+            order: None,
+            input_shapes: vec![],
+        }
+    }
+
+    fn push(mut self, name: &str, op: Op, inputs: &[&str]) -> Self {
+        if name.contains(':') { panic!("Node name {name} can't contain ':'.") }
+        if self.inputs.iter().chain(self.nodes.iter().map(|n| &n.name)).any(|n| n == name) {
+            panic!("Node {name} already exists.");
+        }
+        if inputs.is_empty() { panic!("Node {name} needs at least one input.") }
+        self.nodes.push(Node {
+            name: name.to_string(),
+            op,
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            input_shapes: vec![],
+            output_shapes: vec![],
+        });
+        self.order = None;
+        self
+    }
+
+    pub fn layer(self, name: &str, layer: Box<dyn Layer>, input: &str) -> Self {
+        self.push(name, Op::Layer(layer), &[input])
+    }
+
+    pub fn add(self, name: &str, inputs: &[&str]) -> Self {
+        self.push(name, Op::Add, inputs)
+    }
+
+    pub fn concat(self, name: &str, inputs: &[&str], axis: usize) -> Self {
+        self.push(name, Op::Concat(axis), inputs)
+    }
+
+    // outputs are `name:0`, `name:1`, ...
+    pub fn split(self, name: &str, input: &str, axis: usize, sizes: &[usize]) -> Self {
+        self.push(name, Op::Split(axis, sizes.to_vec()), &[input])
+    }
+
+    pub fn outputs(mut self, outputs: &[&str]) -> Self {
+        self.outputs = outputs.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    // Kahn's algorithm, ties keep the order nodes were added in
+    fn order(&mut self) -> Vec<usize> {
+        if let Some(order) = &self.order {
+            return order.clone();
+        }
+        let index: HashMap<&str, usize> = self.nodes.iter().enumerate().map(|(i, n)| (n.name.as_str(), i)).collect();
+        let mut pending = vec![0; self.nodes.len()];
+        let mut users = vec![vec![]; self.nodes.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            for input in &node.inputs {
+                let (name, _) = parse_ref(input);
+                if let Some(&j) = index.get(name) {
+                    pending[i] += 1;
+                    users[j].push(i);
+                } else if !self.inputs.iter().any(|n| n == name) {
+                    panic!("Node {} uses unknown value {input}.", node.name);
+                }
+            }
+        }
+        let mut ready: Vec<usize> = (0..self.nodes.len()).rev().filter(|i| pending[*i] == 0).collect();
+        let mut order = vec![];
+        while let Some(i) = ready.pop() {
+            order.push(i);
+            for &user in users[i].iter().rev() {
+                pending[user] -= 1;
+                if pending[user] == 0 { ready.push(user) }
+            }
+        }
+        if order.len() != self.nodes.len() {
+            panic!("Graph has a cycle.");
+        }
+        self.order = Some(order.clone());
+        order
+    }
+
+    // forward with one matrix per graph input, returns one per graph output
+    pub fn forward_multi(&mut self, inputs: &[Matrix]) -> Vec<Matrix> {
+        if inputs.len() != self.inputs.len() {
+            panic!("Graph has {} inputs, got {}.", self.inputs.len(), inputs.len());
+        }
+        if self.outputs.is_empty() { panic!("Graph has no outputs.") }
+        self.input_shapes = inputs.iter().map(|x| x.shape.clone()).collect();
+        let mut values: HashMap<String, Vec<Matrix>> = self.inputs.iter().cloned().zip(inputs.iter().map(|x| vec![x.clone()])).collect();
+        let get = |values: &HashMap<String, Vec<Matrix>>, value: &str| {
+            let (name, i) = parse_ref(value);
+            values[name].get(i).unwrap_or_else(|| panic!("{name} has no output {i}.")).clone()
+        };
+        for i in self.order() {
+            let node = &mut self.nodes[i];
+            let xs: Vec<Matrix> = node.inputs.iter().map(|v| get(&values, v)).collect();
+            let ys = match &mut node.op {
+                Op::Layer(layer) => vec![layer.forward(&xs[0])],
+                Op::Add => {
+                    if xs.iter().any(|x| x.shape != xs[0].shape) {
+                        let shapes: Vec<_> = xs.iter().map(|x| x.shape.clone()).collect();
+                        panic!("Add {} got different shapes {shapes:?}.", node.name);
+                    }
+                    vec![xs[1..].iter().fold(xs[0].clone(), |acc, x| &acc + x)]
+                }
+                Op::Concat(axis) => vec![Matrix::concat(&xs, *axis)],
+                Op::Split(axis, sizes) => {
+                    if sizes.iter().sum::<usize>() != xs[0].shape[*axis] {
+                        panic!("Split {} into {sizes:?} doesn't fit shape {:?}.", node.name, xs[0].shape);
+                    }
+                    let mut start = 0;
+                    sizes.iter().map(|size| {
+                        start += size;
+                        xs[0].narrow(*axis, start - size..start)
+                    }).collect()
+                }
+            };
+            node.input_shapes = xs.iter().map(|x| x.shape.clone()).collect();
+            node.output_shapes = ys.iter().map(|y| y.shape.clone()).collect();
+            values.insert(node.name.clone(), ys);
+        }
+        self.outputs.iter().map(|v| get(&values, v)).collect()
+    }
+
+    // backward with one grad per graph output, returns one per graph input
+    pub fn backward_multi(&mut self, partials: &[Matrix]) -> Vec<Matrix> {
+        if partials.len() != self.outputs.len() {
+            panic!("Graph has {} outputs, got {} grads.", self.outputs.len(), partials.len());
+        }
+        let order = self.order.clone().expect("Cannot call backward before forward.");
+        let mut grads: HashMap<(String, usize), Matrix> = HashMap::new();
+        fn accumulate(grads: &mut HashMap<(String, usize), Matrix>, value: &str, grad: Matrix) {
+            let (name, i) = parse_ref(value);
+            let key = (name.to_string(), i);
+            let sum = match grads.remove(&key) {
+                Some(g) => &g + &grad,
+                None => grad,
+            };
+            grads.insert(key, sum);
+        }
+        for (output, partial) in self.outputs.iter().zip(partials) {
+            accumulate(&mut grads, output, partial.clone());
+        }
+
+        for i in order.into_iter().rev() {
+            let node = &mut self.nodes[i];
+            let outs: Vec<Option<Matrix>> = (0..node.output_shapes.len())
+                .map(|j| grads.remove(&(node.name.clone(), j)))
+                .collect();
+            // nothing downstream depends on this node
+            if outs.iter().all(Option::is_none) { continue }
+            let outs: Vec<Matrix> = outs.into_iter()
+                .zip(&node.output_shapes)
+                .map(|(g, shape)| g.unwrap_or_else(|| Matrix::full_nd(shape, 0.0)))
+                .collect();
+            let d_inputs = match &mut node.op {
+                Op::Layer(layer) => vec![layer.backward(&outs[0])],
+                Op::Add => vec![outs[0].clone(); node.inputs.len()],
+                Op::Concat(axis) => {
+                    let mut start = 0;
+                    node.input_shapes.iter().map(|shape| {
+                        start += shape[*axis];
+                        outs[0].narrow(*axis, start - shape[*axis]..start)
+                    }).collect()
+                }
+                Op::Split(axis, _) => vec![Matrix::concat(&outs, *axis)],
+            };
+            for (input, grad) in node.inputs.iter().zip(d_inputs) {
+                accumulate(&mut grads, input, grad);
+            }
+        }
+
+        // inputs that don't reach any output get zero grads
+        self.inputs.iter()
+            .zip(&self.input_shapes)
+            .map(|(name, shape)| grads.remove(&(name.clone(), 0)).unwrap_or_else(|| Matrix::full_nd(shape, 0.0)))
+            .collect()
+    }
+}
+
+// single input, single output graphs are layers themselves
+impl Layer for Graph {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        if self.outputs.len() != 1 { panic!("Graph with {} outputs used as a Layer.", self.outputs.len()) }
+        self.forward_multi(std::slice::from_ref(input)).remove(0)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.backward_multi(std::slice::from_ref(partial)).remove(0)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        let mut params = vec![];
+        for node in self.nodes.iter_mut() {
+            if let Op::Layer(layer) = &mut node.op {
+                params.append(&mut layer.parameters());
+            }
+        }
+        params
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut params = vec![];
+        for node in self.nodes.iter_mut() {
+            if let Op::Layer(layer) = &mut node.op {
+                params.append(&mut prefixed(&node.name, layer.named_parameters()));
+            }
+        }
+        params
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        let mut buffers = vec![];
+        for node in self.nodes.iter_mut() {
+            if let Op::Layer(layer) = &mut node.op {
+                buffers.append(&mut prefixed(&node.name, layer.named_buffers()));
+            }
+        }
+        buffers
+    }
+
+    fn set_training(&mut self, training: bool) {
+        for node in self.nodes.iter_mut() {
+            if let Op::Layer(layer) = &mut node.op {
+                layer.set_training(training);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::Tanh;
+    use crate::gradcheck::{gradcheck, one_hot};
+    use crate::layer::{Dropout, Linear, ReLU};
+    use crate::loss::Crossentropy;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn input(rows: usize, cols: usize, rng: &mut StdRng) -> Matrix {
+        &(2.0 * &Matrix::random(rows, cols, rng)) - 1.0
+    }
+
+    #[test]
+    fn residual_gradients() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut graph = Graph::new(&["x"])
+            .layer("fc1", Box::new(Linear::new(4, 4, true, &mut rng)), "x")
+            .layer("act", Box::new(Tanh::new()), "fc1")
+            .layer("fc2", Box::new(Linear::new(4, 4, true, &mut rng)), "act")
+            .add("res", &["fc2", "x"])
+            .outputs(&["res"]);
+        let report = gradcheck(&mut graph, &mut Crossentropy::new(), &input(3, 4, &mut rng), &one_hot(&[0, 3, 1], 4), 1e-2);
+        assert_eq!(report.parameters.len(), 4);
+        report.assert_below(1e-2);
+    }
+
+    #[test]
+    fn split_concat_and_fan_in_gradients() {
+        // x feeds three nodes, the split halves go through different branches
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut graph = Graph::new(&["x"])
+            .concat("cat", &["left", "right", "x"], 1)
+            .layer("left", Box::new(Linear::new(3, 2, true, &mut rng)), "parts:0")
+            .split("parts", "x", 1, &[3, 2])
+            .layer("right", Box::new(Tanh::new()), "parts:1")
+            .layer("head", Box::new(Linear::new(9, 3, true, &mut rng)), "cat")
+            .add("out", &["head", "head"])
+            .outputs(&["out"]);
+        let report = gradcheck(&mut graph, &mut Crossentropy::new(), &input(4, 5, &mut rng), &one_hot(&[0, 1, 2, 1], 3), 1e-2);
+        report.assert_below(1e-2);
+        assert_eq!(graph.forward(&input(4, 5, &mut rng)).shape, vec![4, 3]);
+    }
+
+    #[test]
+    fn multiple_inputs_and_outputs() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut graph = Graph::new(&["a", "b", "unused"])
+            .layer("relu", Box::new(ReLU::new()), "a")
+            .add("sum", &["relu", "b"])
+            .outputs(&["sum", "relu"]);
+        let (a, b) = (input(2, 3, &mut rng), input(2, 3, &mut rng));
+        let out = graph.forward_multi(&[a.clone(), b.clone(), input(2, 3, &mut rng)]);
+        assert_eq!(out[1].to_vec(), a.apply_unary(|x| x.max(0.0)).to_vec());
+        assert_eq!(out[0].to_vec(), (&out[1] + &b).to_vec());
+
+        let ones = Matrix::full(2, 3, 1.0);
+        let grads = graph.backward_multi(&[ones.clone(), ones.clone()]);
+        // relu output is used twice, b once, the third input not at all
+        assert_eq!(grads[0].to_vec(), a.apply_unary(|x| if *x > 0.0 { 2.0 } else { 0.0 }).to_vec());
+        assert_eq!(grads[1].to_vec(), ones.to_vec());
+        assert_eq!(grads[2].to_vec(), vec![0.0; 6]);
+    }
+
+    #[test]
+    fn propagates_training_mode() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut graph = Graph::new(&["x"])
+            .layer("drop", Box::new(Dropout::new(0.5, &mut rng)), "x")
+            .outputs(&["drop"]);
+        let x = Matrix::full(10, 10, 1.0);
+        assert_ne!(graph.forward(&x).to_vec(), x.to_vec());
+        graph.eval();
+        assert_eq!(graph.forward(&x).to_vec(), x.to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "cycle")]
+    fn rejects_cycles() {
+        let mut graph = Graph::new(&["x"])
+            .add("a", &["x", "b"])
+            .add("b", &["a"])
+            .outputs(&["b"]);
+        graph.forward(&Matrix::full(1, 1, 0.0));
+    }
+}
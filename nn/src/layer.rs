@@ -1,13 +1,66 @@
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 
 use crate::matrix::Matrix;
 use crate::parameter::Parameter;
+use crate::state::{check_keys, StateDict, StateDictError};
 
 // Layer trait
 pub trait Layer {
     fn forward(&mut self, input: &Matrix) -> Matrix;
     fn backward(&mut self, partial: &Matrix) -> Matrix;
     fn parameters(&mut self) -> Vec<&mut Parameter>;
+
+    // switches between training and inference behaviour, layers start out training.
+    // Only layers like batchnorm care, containers have to pass it on to their children.
+    fn set_training(&mut self, _training: bool) {}
+
+    fn train(&mut self) {
+        self.set_training(true);
+    }
+
+    fn eval(&mut self) {
+        self.set_training(false);
+    }
+
+    // parameters with dotted names like `layers.2.weight`, in `parameters()` order.
+    // The default names them by position, layers with parameters override it with
+    // proper names and containers prefix the names of their children.
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        self.parameters().into_iter().enumerate().map(|(i, p)| (i.to_string(), p)).collect()
+    }
+
+    // state that isn't trained but belongs in a state_dict, like batchnorm's running stats
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        vec![]
+    }
+
+    // copies of all parameters and buffers by name
+    fn state_dict(&mut self) -> StateDict {
+        let mut state: StateDict = self.named_parameters().into_iter().map(|(n, p)| (n, p.data.clone())).collect();
+        state.extend(self.named_buffers().into_iter().map(|(n, b)| (n, b.clone())));
+        state
+    }
+
+    // loads a state_dict of the same architecture. Names and shapes are all checked
+    // before anything is written, so on error the layer is left as it was.
+    fn load_state_dict(&mut self, state: &StateDict) -> Result<(), StateDictError> {
+        let mut expected: Vec<(String, Vec<usize>)> = self.named_parameters().into_iter().map(|(n, p)| (n, p.data.shape.clone())).collect();
+        expected.extend(self.named_buffers().into_iter().map(|(n, b)| (n, b.shape.clone())));
+        check_keys(&expected, state)?;
+        for (name, param) in self.named_parameters() {
+            param.data = state[&name].clone();
+        }
+        for (name, buffer) in self.named_buffers() {
+            *buffer = state[&name].clone();
+        }
+        Ok(())
+    }
+}
+
+// `prefix.name` for every name, how containers namespace the entries of their children
+pub fn prefixed<T>(prefix: &str, named: Vec<(String, T)>) -> Vec<(String, T)> {
+    named.into_iter().map(|(name, x)| (format!("{prefix}.{name}"), x)).collect()
 }
 
 // Linear layer
@@ -42,8 +95,8 @@ impl Layer for Linear {
     
     fn backward(&mut self, partial: &Matrix) -> Matrix {
         self.weight.grad = &self.weight.grad + &self.input.as_mut().unwrap().T().matmul(partial);
-        if !self.bias.is_none() {
-            self.bias.as_mut().unwrap().grad = &self.bias.as_ref().unwrap().grad + &partial.row_sum();
+        if let Some(bias) = &mut self.bias {
+            bias.grad = &bias.grad + &partial.sum_axis(0, true);
         }
         partial.matmul(&self.weight.data.T())
     }
@@ -54,9 +107,18 @@ impl Layer for Linear {
             None => vec![&mut self.weight]
         }
     }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut params = vec![("weight".to_string(), &mut self.weight)];
+        if let Some(bias) = &mut self.bias {
+            params.push(("bias".to_string(), bias));
+        }
+        params
+    }
 }
 
 // ReLU layer
+#[derive(Default)]
 pub struct ReLU {
     mask: Option<Matrix>,
 }
@@ -82,6 +144,214 @@ impl Layer for ReLU {
     }
 }
 
+// Dropout layer - zeroes elements with probability p while training and scales
+// the rest by 1 / (1 - p) (inverted dropout), so it is the identity at inference.
+// Draws its masks from its own rng, seeded from the one passed in, so runs are reproducible.
+pub struct Dropout {
+    p: f32,
+    rng: RngState,
+    training: bool,
+    mask: Option<Matrix>,
+}
+
+impl Dropout {
+    pub fn new<R: Rng>(p: f32, rng: &mut R) -> Self {
+        if !(0.0..=1.0).contains(&p) { panic!("Dropout probability {p} not in [0, 1].") }
+        Self { p, rng: RngState::new(rng), training: true, mask: None }
+    }
+}
+
+// The rng of a dropout layer kept as buffers, so state_dict and checkpoints carry it and a
+// resumed run draws the same masks. Seed, stream and word position of a ChaCha12Rng as their
+// little endian bytes, one f32 each.
+struct RngState {
+    seed: Matrix,
+    stream: Matrix,
+    word_pos: Matrix,
+}
+
+impl RngState {
+    fn new<R: Rng>(rng: &mut R) -> Self {
+        Self::from_rng(&ChaCha12Rng::seed_from_u64(rng.gen()))
+    }
+
+    fn from_rng(rng: &ChaCha12Rng) -> Self {
+        let buffer = |bytes: &[u8]| Matrix::from_vec_nd(&[bytes.len()], bytes.iter().map(|&b| b as f32).collect());
+        Self {
+            seed: buffer(&rng.get_seed()),
+            stream: buffer(&rng.get_stream().to_le_bytes()),
+            word_pos: buffer(&rng.get_word_pos().to_le_bytes()),
+        }
+    }
+
+    fn rng(&self) -> ChaCha12Rng {
+        let bytes = |buffer: &Matrix| buffer.iter().map(|b| b as u8).collect::<Vec<u8>>();
+        let mut rng = ChaCha12Rng::from_seed(bytes(&self.seed).try_into().unwrap());
+        rng.set_stream(u64::from_le_bytes(bytes(&self.stream).try_into().unwrap()));
+        rng.set_word_pos(u128::from_le_bytes(bytes(&self.word_pos).try_into().unwrap()));
+        rng
+    }
+
+    // keep mask of the given shape, already scaled by 1 / (1 - p)
+    fn mask(&mut self, shape: &[usize], p: f32) -> Matrix {
+        let mut rng = self.rng();
+        let scale = if p < 1.0 { 1.0 / (1.0 - p) } else { 0.0 };
+        let n = shape.iter().product();
+        let mask = Matrix::from_vec_nd(shape, (0..n).map(|_| if rng.gen::<f32>() >= p { scale } else { 0.0 }).collect());
+        *self = Self::from_rng(&rng);
+        mask
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        vec![
+            ("rng_seed".to_string(), &mut self.seed),
+            ("rng_stream".to_string(), &mut self.stream),
+            ("rng_word_pos".to_string(), &mut self.word_pos),
+        ]
+    }
+}
+
+impl Layer for Dropout {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        if !self.training || self.p == 0.0 {
+            self.mask = None;
+            return input.clone();
+        }
+        let mask = self.rng.mask(&input.shape, self.p);
+        let y = input * &mask;
+        self.mask = Some(mask);
+        y
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        match &self.mask {
+            Some(mask) => partial * mask,
+            None => partial.clone(),
+        }
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        self.rng.named_buffers()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+// Dropout2d layer - like Dropout, but zeroes whole channels of [batch, chan, ...] inputs,
+// neighbouring pixels of conv feature maps are too correlated for elementwise dropout to help
+pub struct Dropout2d {
+    p: f32,
+    rng: RngState,
+    training: bool,
+    mask: Option<Matrix>,
+}
+
+impl Dropout2d {
+    pub fn new<R: Rng>(p: f32, rng: &mut R) -> Self {
+        if !(0.0..=1.0).contains(&p) { panic!("Dropout probability {p} not in [0, 1].") }
+        Self { p, rng: RngState::new(rng), training: true, mask: None }
+    }
+}
+
+impl Layer for Dropout2d {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        if !self.training || self.p == 0.0 {
+            self.mask = None;
+            return input.clone();
+        }
+        if input.ndim() < 3 {
+            panic!("Dropout2d expects [batch, chan, ...] input, got {:?}.", input.shape);
+        }
+        // one value per channel, broadcast over the spatial dims
+        let mut shape = vec![1; input.ndim()];
+        shape[0] = input.shape[0];
+        shape[1] = input.shape[1];
+        let mask = self.rng.mask(&shape, self.p);
+        let y = input * &mask;
+        self.mask = Some(mask);
+        y
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        match &self.mask {
+            Some(mask) => partial * mask,
+            None => partial.clone(),
+        }
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        self.rng.named_buffers()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+// Flatten layer - [batch, ...] -> [batch, features], e.g. between conv and linear layers
+#[derive(Default)]
+pub struct Flatten {
+    input_shape: Option<Vec<usize>>,
+}
+
+impl Flatten {
+    pub fn new() -> Self {
+        Self { input_shape: None }
+    }
+}
+
+impl Layer for Flatten {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.input_shape = Some(input.shape.clone());
+        input.flatten()
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        partial.reshape(self.input_shape.as_ref().expect("Cannot call backward before forward."))
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// Unflatten layer - [batch, features] -> [batch, ...shape], e.g. flat CIFAR10 rows into [3, 32, 32] images
+pub struct Unflatten {
+    shape: Vec<usize>,
+}
+
+impl Unflatten {
+    pub fn new(shape: &[usize]) -> Self {
+        Self { shape: shape.to_vec() }
+    }
+}
+
+impl Layer for Unflatten {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let mut shape = vec![input.rows()];
+        shape.extend(&self.shape);
+        input.reshape(&shape)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        partial.flatten()
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
 // Sequential layer - run layers sequentially
 pub struct Sequential {
     layers: Vec<Box<dyn Layer>>
@@ -117,4 +387,259 @@ impl Layer for Sequential {
         }
         params
     }
-}
\ No newline at end of file
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut params = vec![];
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            params.append(&mut prefixed(&format!("layers.{i}"), layer.named_parameters()));
+        }
+        params
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        let mut buffers = vec![];
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            buffers.append(&mut prefixed(&format!("layers.{i}"), layer.named_buffers()));
+        }
+        buffers
+    }
+
+    fn set_training(&mut self, training: bool) {
+        for layer in self.layers.iter_mut() {
+            layer.set_training(training);
+        }
+    }
+}
+
+// Residual layer - main(x) + shortcut(x), the shortcut is the identity if None.
+// A projection shortcut (e.g. 1x1 conv) is needed when main changes the shape.
+pub struct Residual {
+    main: Box<dyn Layer>,
+    shortcut: Option<Box<dyn Layer>>,
+}
+
+impl Residual {
+    pub fn new(main: Box<dyn Layer>, shortcut: Option<Box<dyn Layer>>) -> Self {
+        Self { main, shortcut }
+    }
+}
+
+impl Layer for Residual {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let y = self.main.forward(input);
+        match &mut self.shortcut {
+            Some(shortcut) => &y + &shortcut.forward(input),
+            None => &y + input,
+        }
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let x = self.main.backward(partial);
+        match &mut self.shortcut {
+            Some(shortcut) => &x + &shortcut.backward(partial),
+            None => &x + partial,
+        }
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        let mut params = self.main.parameters();
+        if let Some(shortcut) = &mut self.shortcut {
+            params.append(&mut shortcut.parameters());
+        }
+        params
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        let mut params = prefixed("main", self.main.named_parameters());
+        if let Some(shortcut) = &mut self.shortcut {
+            params.append(&mut prefixed("shortcut", shortcut.named_parameters()));
+        }
+        params
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        let mut buffers = prefixed("main", self.main.named_buffers());
+        if let Some(shortcut) = &mut self.shortcut {
+            buffers.append(&mut prefixed("shortcut", shortcut.named_buffers()));
+        }
+        buffers
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.main.set_training(training);
+        if let Some(shortcut) = &mut self.shortcut {
+            shortcut.set_training(training);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{gradcheck, one_hot};
+    use crate::loss::Crossentropy;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // uniform in [-1, 1], but kept away from 0 so relu kinks don't fall inside eps
+    fn input(rows: usize, cols: usize, rng: &mut StdRng) -> Matrix {
+        Matrix::random(rows, cols, rng)
+            .apply_unary(|x| if *x < 0.5 { -0.1 - 1.8 * x } else { 1.8 * x - 0.8 })
+    }
+
+    #[test]
+    fn linear_gradients() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut layer = Linear::new(6, 4, true, &mut rng);
+        let report = gradcheck(&mut layer, &mut Crossentropy::new(), &input(5, 6, &mut rng), &one_hot(&[0, 1, 2, 3, 0], 4), 1e-2);
+        assert_eq!(report.parameters.len(), 2);
+        report.assert_below(1e-2);
+    }
+
+    #[test]
+    fn linear_without_bias_gradients() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut layer = Linear::new(6, 4, false, &mut rng);
+        let report = gradcheck(&mut layer, &mut Crossentropy::new(), &input(5, 6, &mut rng), &one_hot(&[3, 1, 2, 3, 0], 4), 1e-2);
+        assert_eq!(report.parameters.len(), 1);
+        report.assert_below(1e-2);
+    }
+
+    #[test]
+    fn relu_gradients() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut layer = ReLU::new();
+        let report = gradcheck(&mut layer, &mut Crossentropy::new(), &input(5, 4, &mut rng), &one_hot(&[0, 1, 2, 3, 0], 4), 1e-2);
+        report.assert_below(1e-2);
+    }
+
+    #[test]
+    fn relu_forward() {
+        let y = ReLU::new().forward(&Matrix::from_vec(1, 4, vec![-2.0, -0.5, 0.5, 2.0]));
+        assert_eq!(y.to_vec(), vec![0.0, 0.0, 0.5, 2.0]);
+    }
+
+    #[test]
+    fn dropout_is_identity_in_eval() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let x = input(4, 5, &mut rng);
+        let mut dropout = Dropout::new(0.5, &mut rng);
+        dropout.eval();
+        assert_eq!(dropout.forward(&x).to_vec(), x.to_vec());
+        assert_eq!(dropout.backward(&x).to_vec(), x.to_vec());
+    }
+
+    #[test]
+    fn dropout_zeroes_and_rescales() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut dropout = Dropout::new(0.25, &mut rng);
+        let y = dropout.forward(&Matrix::full(100, 100, 1.0));
+        let dropped = y.iter().filter(|x| *x == 0.0).count() as f32 / 10000.0;
+        assert!((dropped - 0.25).abs() < 0.02);
+        assert!(y.iter().all(|x| x == 0.0 || (x - 4.0 / 3.0).abs() < 1e-6));
+        // backward goes through the same mask
+        let grad = dropout.backward(&Matrix::full(100, 100, 1.0));
+        assert_eq!(grad.to_vec(), y.to_vec());
+    }
+
+    #[test]
+    fn dropout_is_reproducible() {
+        let x = Matrix::full(10, 10, 1.0);
+        let mask = |seed| Dropout::new(0.5, &mut StdRng::seed_from_u64(seed)).forward(&x).to_vec();
+        assert_eq!(mask(1337), mask(1337));
+        assert_ne!(mask(1337), mask(1338));
+    }
+
+    #[test]
+    fn dropout2d_drops_whole_channels() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut dropout = Dropout2d::new(0.5, &mut rng);
+        let y = dropout.forward(&Matrix::full_nd(&[4, 8, 3, 3], 1.0));
+        let mut dropped = 0;
+        for n in 0..4 {
+            for c in 0..8 {
+                let channel = y.select(0, n).select(0, c);
+                assert!(channel.iter().all(|x| x == channel.get(0, 0)));
+                dropped += (channel.get(0, 0) == 0.0) as usize;
+            }
+        }
+        assert!(dropped > 0 && dropped < 32);
+        let grad = dropout.backward(&Matrix::full_nd(&[4, 8, 3, 3], 1.0));
+        assert_eq!(grad.to_vec(), y.to_vec());
+    }
+
+    #[test]
+    fn sequential_gradients() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut model = Sequential::new(vec![
+            Box::new(Linear::new(6, 8, true, &mut rng)),
+            Box::new(ReLU::new()),
+            Box::new(Linear::new(8, 3, true, &mut rng)),
+        ]);
+        let report = gradcheck(&mut model, &mut Crossentropy::new(), &input(4, 6, &mut rng), &one_hot(&[0, 1, 2, 1], 3), 1e-3);
+        assert_eq!(report.parameters.len(), 4);
+        report.assert_below(1e-2);
+    }
+
+    #[test]
+    fn residual_gradients() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut model = Residual::new(Box::new(Linear::new(4, 4, true, &mut rng)), None);
+        gradcheck(&mut model, &mut Crossentropy::new(), &input(3, 4, &mut rng), &one_hot(&[0, 3, 1], 4), 1e-2).assert_below(1e-2);
+        let mut model = Residual::new(
+            Box::new(Sequential::new(vec![Box::new(Linear::new(4, 3, true, &mut rng)), Box::new(ReLU::new())])),
+            Some(Box::new(Linear::new(4, 3, false, &mut rng))),
+        );
+        let report = gradcheck(&mut model, &mut Crossentropy::new(), &input(3, 4, &mut rng), &one_hot(&[0, 2, 1], 3), 1e-3);
+        assert_eq!(report.parameters.len(), 3);
+        report.assert_below(1e-2);
+    }
+
+    fn model(seed: u64) -> Sequential {
+        let mut rng = StdRng::seed_from_u64(seed);
+        Sequential::new(vec![
+            Box::new(Linear::new(4, 3, true, &mut rng)),
+            Box::new(ReLU::new()),
+            Box::new(Residual::new(Box::new(Linear::new(3, 3, false, &mut rng)), None)),
+        ])
+    }
+
+    #[test]
+    fn named_parameters() {
+        let mut model = model(8);
+        let names: Vec<String> = model.named_parameters().into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, vec!["layers.0.weight", "layers.0.bias", "layers.2.main.weight"]);
+        // same order as parameters(), optimizers rely on it
+        let shapes: Vec<_> = model.parameters().iter().map(|p| p.data.shape.clone()).collect();
+        let named_shapes: Vec<_> = model.named_parameters().iter().map(|(_, p)| p.data.shape.clone()).collect();
+        assert_eq!(shapes, named_shapes);
+    }
+
+    #[test]
+    fn state_dict_round_trip() {
+        let (mut a, mut b) = (model(9), model(10));
+        let x = input(2, 4, &mut StdRng::seed_from_u64(11));
+        assert_ne!(a.forward(&x).to_vec(), b.forward(&x).to_vec());
+        b.load_state_dict(&a.state_dict()).unwrap();
+        assert_eq!(a.forward(&x).to_vec(), b.forward(&x).to_vec());
+    }
+
+    #[test]
+    fn load_state_dict_reports_bad_keys() {
+        let mut model = model(12);
+        let before = model.state_dict();
+        let mut state = before.clone();
+        state.remove("layers.0.bias");
+        state.insert("layers.5.weight".to_string(), Matrix::full(1, 1, 0.0));
+        state.insert("layers.2.main.weight".to_string(), Matrix::full(2, 3, 0.0));
+        state.insert("layers.0.weight".to_string(), Matrix::full(4, 3, 0.0));
+
+        let error = model.load_state_dict(&state).unwrap_err();
+        assert_eq!(error.missing, vec!["layers.0.bias"]);
+        assert_eq!(error.unexpected, vec!["layers.5.weight"]);
+        assert_eq!(error.mismatched, vec![("layers.2.main.weight".to_string(), vec![3, 3], vec![2, 3])]);
+        // nothing was loaded
+        for (name, value) in model.state_dict() {
+            assert_eq!(value.to_vec(), before[&name].to_vec());
+        }
+    }
+}
@@ -0,0 +1,25 @@
+// `x % n != 0` and `map_or(true, ..)` over is_multiple_of (1.87) and is_none_or (1.82),
+// neither is worth raising the minimum rust version for
+#![allow(clippy::manual_is_multiple_of, clippy::unnecessary_map_or)]
+
+pub mod matrix;
+pub mod autograd;
+pub mod layer;
+pub mod activation;
+pub mod conv;
+pub mod pool;
+pub mod norm;
+pub mod embedding;
+pub mod rnn;
+pub mod attention;
+pub mod graph;
+pub mod models;
+pub mod parameter;
+pub mod state;
+pub mod checkpoint;
+pub mod loss;
+pub mod optimizer;
+pub mod lr_scheduler;
+pub mod data;
+pub mod metric;
+pub mod gradcheck;
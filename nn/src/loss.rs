@@ -1,3 +1,6 @@
+use std::iter::zip;
+
+use crate::activation::sigmoid;
 use crate::matrix::Matrix;
 
 pub trait Loss {
@@ -5,8 +8,75 @@ pub trait Loss {
     fn backward(&self, seed: f32) -> Matrix;
 }
 
+// Losses over several predictions at once, e.g. the embeddings of anchor, positive and negative
+// for TripletMarginLoss, with a gradient for each of them. Every `Loss` is a `MultiLoss` of one
+// input with a target.
+pub trait MultiLoss {
+    fn forward_multi(&mut self, inputs: &[Matrix], target: Option<&Matrix>) -> f32;
+    // gradients in the order of `inputs`
+    fn backward_multi(&self, seed: f32) -> Vec<Matrix>;
+}
+
+impl<L: Loss + ?Sized> MultiLoss for L {
+    fn forward_multi(&mut self, inputs: &[Matrix], target: Option<&Matrix>) -> f32 {
+        if inputs.len() != 1 { panic!("Loss takes 1 input, got {}.", inputs.len()) }
+        self.forward(&inputs[0], target.expect("Loss needs a target."))
+    }
+
+    fn backward_multi(&self, seed: f32) -> Vec<Matrix> {
+        vec![self.backward(seed)]
+    }
+}
+
+// How a loss combines its individual losses, per element or per sample depending on the loss,
+// into the value of `forward`. With `None` forward returns their sum, so `backward` has a scalar
+// to start from, and the individual losses are available via `losses()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Reduction {
+    #[default]
+    Mean,
+    Sum,
+    None,
+}
+
+impl Reduction {
+    fn reduce(self, losses: &Matrix) -> f32 {
+        match self {
+            Reduction::Mean => losses.mean(),
+            Reduction::Sum | Reduction::None => losses.sum(),
+        }
+    }
+
+    // derivative of `reduce` w.r.t. every individual loss
+    fn scale(self, numel: usize) -> f32 {
+        match self {
+            Reduction::Mean => 1.0 / numel as f32,
+            Reduction::Sum | Reduction::None => 1.0,
+        }
+    }
+}
+
+// targets have to match the input exactly, a broadcast target is almost always a bug
+fn check_shapes(loss: &str, input: &Matrix, target: &Matrix) {
+    if input.shape != target.shape {
+        panic!("{loss} input and target shapes differ, {:?} and {:?}.", input.shape, target.shape);
+    }
+}
+
+// input - target, unlike the arithmetic ops without broadcasting
+fn difference(loss: &str, input: &Matrix, target: &Matrix) -> Matrix {
+    check_shapes(loss, input, target);
+    input - target
+}
+
+// log softmax over the classes of [batch, classes] logits, normalized by logsumexp for stability
+fn log_softmax(input: &Matrix) -> Matrix {
+    input - &input.logsumexp_axis(1, true)
+}
+
 // Crossentropy from Logits - LogSoftmax for stability
 // assumes that targets are one-hot encoded
+#[derive(Default)]
 pub struct Crossentropy {
     target: Option<Matrix>,
     activation: Option<Matrix>,
@@ -20,10 +90,8 @@ impl Crossentropy {
 
 impl Loss for Crossentropy {
     fn forward(&mut self, input: &Matrix, target: &Matrix) -> f32 {
-        // log softmax, normalize for stability
         self.target = Some(target.clone());
-        let mut x = input - &input.col_max();
-        x = &x - &x.exp().col_sum().ln();
+        let x = log_softmax(input);
         self.activation = Some(x.exp().clone());
 
         // crossentropy
@@ -36,3 +104,1128 @@ impl Loss for Crossentropy {
         (seed / n) * &(self.activation.as_ref().unwrap() - self.target.as_ref().unwrap())
     }
 }
+
+// Crossentropy from logits with class indices as targets, [batch, classes] logits and
+// [batch] (or [batch, 1]) labels holding 0..classes as f32, no one-hot matrix needed.
+// Per-class `weight` rescales the loss of each sample by the weight of its class,
+// samples labelled `ignore_index` don't count at all, and `label_smoothing` mixes the
+// one-hot target with the uniform distribution. The mean is over the weights of the
+// counted samples (0 if there are none), as in PyTorch.
+#[derive(Default)]
+pub struct SparseCrossentropy {
+    weight: Option<Vec<f32>>,
+    ignore_index: Option<usize>,
+    label_smoothing: f32,
+    reduction: Reduction,
+    // d loss / d logits for seed 1, computed in forward
+    grad: Option<Matrix>,
+    losses: Option<Matrix>,
+}
+
+impl SparseCrossentropy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_weight(mut self, weight: &[f32]) -> Self {
+        self.weight = Some(weight.to_vec());
+        self
+    }
+
+    pub fn with_ignore_index(mut self, ignore_index: usize) -> Self {
+        self.ignore_index = Some(ignore_index);
+        self
+    }
+
+    // target is (1 - label_smoothing) * one_hot + label_smoothing / classes
+    pub fn with_label_smoothing(mut self, label_smoothing: f32) -> Self {
+        if !(0.0..=1.0).contains(&label_smoothing) {
+            panic!("Label smoothing has to be in [0, 1], got {label_smoothing}.");
+        }
+        self.label_smoothing = label_smoothing;
+        self
+    }
+
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // per sample losses of the last forward, 0 for ignored samples
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+}
+
+impl Loss for SparseCrossentropy {
+    fn forward(&mut self, input: &Matrix, target: &Matrix) -> f32 {
+        if input.ndim() != 2 || target.numel() != input.rows() || target.ndim() > 2 {
+            panic!("SparseCrossentropy needs [batch, classes] logits and [batch] labels, got {:?} and {:?}.", input.shape, target.shape);
+        }
+        let (n, classes) = (input.rows(), input.cols());
+        let weight = match &self.weight {
+            Some(w) if w.len() != classes => panic!("SparseCrossentropy has {} class weights for {classes} classes.", w.len()),
+            Some(w) => w.clone(),
+            None => vec![1.0; classes],
+        };
+        let log_p = log_softmax(input).to_vec();
+        let smooth = self.label_smoothing / classes as f32;
+        let mut losses = vec![0.0; n];
+        let mut grad = vec![0.0; n * classes];
+        let mut total_weight = 0.0;
+        for (i, label) in target.iter().enumerate() {
+            if label < 0.0 || label.fract() != 0.0 {
+                panic!("SparseCrossentropy label {label} isn't a class index.");
+            }
+            let label = label as usize;
+            if Some(label) == self.ignore_index { continue }
+            if label >= classes {
+                panic!("SparseCrossentropy label {label} out of range for {classes} classes.");
+            }
+            total_weight += weight[label];
+            // loss = -sum_c a_c * log_p_c, so d loss / d logits = sum(a) * p - a
+            let row = i * classes..(i + 1) * classes;
+            let a: Vec<f32> = (0..classes)
+                .map(|c| smooth * weight[c] + if c == label { (1.0 - self.label_smoothing) * weight[label] } else { 0.0 })
+                .collect();
+            let a_sum: f32 = a.iter().sum();
+            for (c, (lp, g)) in zip(&log_p[row.clone()], &mut grad[row]).enumerate() {
+                losses[i] -= a[c] * lp;
+                *g = a_sum * lp.exp() - a[c];
+            }
+        }
+
+        let scale = match self.reduction {
+            Reduction::Mean if total_weight > 0.0 => 1.0 / total_weight,
+            Reduction::Mean => 0.0,
+            Reduction::Sum | Reduction::None => 1.0,
+        };
+        let losses = Matrix::from_vec_nd(&[n], losses);
+        let loss = scale * losses.sum();
+        self.grad = Some(scale * &Matrix::from_vec(n, classes, grad));
+        self.losses = Some(losses);
+        loss
+    }
+
+    fn backward(&self, seed: f32) -> Matrix {
+        seed * self.grad.as_ref().expect("Cannot call backward before forward.")
+    }
+}
+
+// Focal loss from logits - crossentropy with the loss of every class scaled by (1 - p)^gamma,
+// which shrinks the loss of samples that are already classified well, so the rare and hard ones
+// dominate. -sum_c alpha_c * y_c * (1 - p_c)^gamma * ln(p_c) per sample, targets one-hot (or
+// probabilities) like Crossentropy. gamma 0 without alpha is plain Crossentropy.
+pub struct FocalLoss {
+    gamma: f32,
+    alpha: Option<Vec<f32>>,
+    reduction: Reduction,
+    // d loss / d logits for seed 1, computed in forward
+    grad: Option<Matrix>,
+    losses: Option<Matrix>,
+}
+
+impl FocalLoss {
+    pub fn new(gamma: f32) -> Self {
+        if gamma < 0.0 { panic!("FocalLoss gamma can't be negative, got {gamma}.") }
+        Self { gamma, alpha: None, reduction: Reduction::Mean, grad: None, losses: None }
+    }
+
+    // per class weights, e.g. inverse class frequencies
+    pub fn with_alpha(mut self, alpha: &[f32]) -> Self {
+        self.alpha = Some(alpha.to_vec());
+        self
+    }
+
+    // Mean is over the samples, as in Crossentropy
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // per sample losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+}
+
+impl Loss for FocalLoss {
+    fn forward(&mut self, input: &Matrix, target: &Matrix) -> f32 {
+        check_shapes("FocalLoss", input, target);
+        let (n, classes) = (input.rows(), input.cols());
+        if let Some(alpha) = self.alpha.as_ref().filter(|a| a.len() != classes) {
+            panic!("FocalLoss has {} alphas for {classes} classes.", alpha.len());
+        }
+        let gamma = self.gamma;
+        let log_p = log_softmax(input).to_vec();
+        let mut losses = vec![0.0; n];
+        // d loss / d log_p
+        let mut d_log_p = vec![0.0; n * classes];
+        for (i, (lp, y)) in zip(&log_p, target.iter()).enumerate() {
+            let a = y * self.alpha.as_ref().map_or(1.0, |alpha| alpha[i % classes]);
+            let (p, lp) = (lp.exp(), *lp);
+            let focus = (1.0 - p).powf(gamma);
+            losses[i / classes] -= a * focus * lp;
+            // at p = 1 it is inf * 0 for gamma < 1, the limit is 0 since log p ~ p - 1
+            let d_focus = if gamma == 0.0 || p == 1.0 { 0.0 } else { gamma * (1.0 - p).powf(gamma - 1.0) * p * lp };
+            d_log_p[i] = a * (d_focus - focus);
+        }
+
+        // through the log softmax, dx = d - p * sum(d)
+        let d_log_p = Matrix::from_vec(n, classes, d_log_p);
+        let p = Matrix::from_vec(n, classes, log_p).exp();
+        let grad = &d_log_p - &(&p * &d_log_p.sum_axis(1, true));
+
+        let scale = match self.reduction {
+            Reduction::Mean => 1.0 / n as f32,
+            Reduction::Sum | Reduction::None => 1.0,
+        };
+        let losses = Matrix::from_vec_nd(&[n], losses);
+        let loss = scale * losses.sum();
+        self.grad = Some(scale * &grad);
+        self.losses = Some(losses);
+        loss
+    }
+
+    fn backward(&self, seed: f32) -> Matrix {
+        seed * self.grad.as_ref().expect("Cannot call backward before forward.")
+    }
+}
+
+// KL divergence - sum target * (ln(target) - input) for log-probabilities as input and a target
+// distribution, e.g. LogSoftmax of a student against the softmax of a teacher for distillation.
+// Elements with target 0 contribute 0. Mean divides by the batch size (PyTorch's "batchmean"),
+// the mean over all elements isn't a divergence between the row distributions.
+#[derive(Default)]
+pub struct KLDivLoss {
+    log_target: bool,
+    reduction: Reduction,
+    // target probabilities
+    target: Option<Matrix>,
+    losses: Option<Matrix>,
+}
+
+impl KLDivLoss {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the target is given as log-probabilities too, which is more precise for tiny ones
+    pub fn with_log_target(mut self, log_target: bool) -> Self {
+        self.log_target = log_target;
+        self
+    }
+
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // elementwise losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+
+    fn scale(&self, target: &Matrix) -> f32 {
+        match self.reduction {
+            Reduction::Mean => 1.0 / target.shape[0] as f32,
+            Reduction::Sum | Reduction::None => 1.0,
+        }
+    }
+}
+
+impl Loss for KLDivLoss {
+    fn forward(&mut self, input: &Matrix, target: &Matrix) -> f32 {
+        check_shapes("KLDivLoss", input, target);
+        let (target, log_target) = if self.log_target {
+            (target.exp(), target.clone())
+        } else {
+            // 0 * ln(0) is 0, skipped below
+            (target.clone(), target.apply_unary(|t| if *t > 0.0 { t.ln() } else { 0.0 }))
+        };
+        let losses = target.apply_binary(&(&log_target - input), |t, d| if *t == 0.0 { 0.0 } else { t * d });
+        let loss = self.scale(&target) * losses.sum();
+        (self.target, self.losses) = (Some(target), Some(losses));
+        loss
+    }
+
+    fn backward(&self, seed: f32) -> Matrix {
+        let target = self.target.as_ref().expect("Cannot call backward before forward.");
+        (-seed * self.scale(target)) * target
+    }
+}
+
+// the inputs of the embedding losses, `count` [batch, dim] matrices of the same shape,
+// and the batch size
+fn check_embeddings(loss: &str, inputs: &[Matrix], count: usize) -> usize {
+    if inputs.len() != count { panic!("{loss} takes {count} inputs, got {}.", inputs.len()) }
+    if inputs[0].ndim() != 2 || inputs.iter().any(|x| x.shape != inputs[0].shape) {
+        let shapes: Vec<_> = inputs.iter().map(|x| &x.shape).collect();
+        panic!("{loss} needs [batch, dim] inputs of the same shape, got {shapes:?}.");
+    }
+    inputs[0].rows()
+}
+
+// one label per sample
+fn check_labels(loss: &str, target: Option<&Matrix>, n: usize) -> Vec<f32> {
+    let target = target.unwrap_or_else(|| panic!("{loss} needs a target."));
+    if target.numel() != n { panic!("{loss} needs {n} labels, got {:?}.", target.shape) }
+    target.to_vec()
+}
+
+// scales every row of [batch, dim] x by its own factor
+fn scale_rows(x: &Matrix, factors: Vec<f32>) -> Matrix {
+    x * &Matrix::from_vec(factors.len(), 1, factors)
+}
+
+// Row-wise distance ||a - b + eps||_p as PyTorch's pairwise_distance, the eps keeps the
+// gradient finite for identical rows. Returns the [batch] distances and their gradient w.r.t. a,
+// the one w.r.t. b is its negative.
+fn pairwise_distance(a: &Matrix, b: &Matrix, p: f32, eps: f32) -> (Vec<f32>, Matrix) {
+    let diff = &(a - b) + eps;
+    let dist: Vec<f32> = diff.apply_unary(|d| d.abs().powf(p)).sum_axis(1, false).iter().map(|s| s.powf(1.0 / p)).collect();
+    // d ||v||_p / dv = sign(v) * |v|^(p - 1) / ||v||^(p - 1)
+    let inv: Vec<f32> = dist.iter().map(|d| if *d == 0.0 { 0.0 } else { d.powf(1.0 - p) }).collect();
+    let grad = scale_rows(&diff.apply_unary(|d| d.signum() * d.abs().powf(p - 1.0)), inv);
+    (dist, grad)
+}
+
+// Triplet margin loss - max(0, d(anchor, positive) - d(anchor, negative) + margin) per sample,
+// pulls the positive closer to the anchor than the negative by at least margin.
+// Inputs are [anchor, positive, negative] embeddings, no target. With `swap` the negative
+// distance is the smaller of d(anchor, negative) and d(positive, negative).
+pub struct TripletMarginLoss {
+    margin: f32,
+    p: f32,
+    eps: f32,
+    swap: bool,
+    reduction: Reduction,
+    // d loss / d inputs for seed 1, computed in forward
+    grads: Option<Vec<Matrix>>,
+    losses: Option<Matrix>,
+}
+
+impl Default for TripletMarginLoss {
+    fn default() -> Self {
+        Self { margin: 1.0, p: 2.0, eps: 1e-6, swap: false, reduction: Reduction::Mean, grads: None, losses: None }
+    }
+}
+
+impl TripletMarginLoss {
+    // margin 1, euclidean distance
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_margin(mut self, margin: f32) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    // order of the norm, 2 for euclidean, 1 for manhattan
+    pub fn with_p(mut self, p: f32) -> Self {
+        if p < 1.0 { panic!("TripletMarginLoss needs a norm with p >= 1, got {p}.") }
+        self.p = p;
+        self
+    }
+
+    pub fn with_swap(mut self, swap: bool) -> Self {
+        self.swap = swap;
+        self
+    }
+
+    // Mean is over the samples
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // per sample losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+}
+
+impl MultiLoss for TripletMarginLoss {
+    fn forward_multi(&mut self, inputs: &[Matrix], _target: Option<&Matrix>) -> f32 {
+        let n = check_embeddings("TripletMarginLoss", inputs, 3);
+        let (anchor, positive, negative) = (&inputs[0], &inputs[1], &inputs[2]);
+        let (d_ap, g_ap) = pairwise_distance(anchor, positive, self.p, self.eps);
+        let (d_an, g_an) = pairwise_distance(anchor, negative, self.p, self.eps);
+        let (d_pn, g_pn) = if self.swap {
+            pairwise_distance(positive, negative, self.p, self.eps)
+        } else {
+            (d_an.clone(), Matrix::full_like(&g_an, 0.0))
+        };
+
+        let scale = self.reduction.scale(n);
+        let mut losses = vec![0.0; n];
+        // per sample factors of the anchor-negative and positive-negative gradients
+        let (mut active, mut an, mut pn) = (vec![0.0; n], vec![0.0; n], vec![0.0; n]);
+        for i in 0..n {
+            let swapped = d_pn[i] < d_an[i];
+            losses[i] = (d_ap[i] - d_an[i].min(d_pn[i]) + self.margin).max(0.0);
+            if losses[i] > 0.0 {
+                active[i] = scale;
+                if swapped { pn[i] = scale } else { an[i] = scale }
+            }
+        }
+        let (g_ap, g_an, g_pn) = (scale_rows(&g_ap, active), scale_rows(&g_an, an), scale_rows(&g_pn, pn));
+        self.grads = Some(vec![
+            &g_ap - &g_an,
+            &(-&g_ap) - &g_pn,
+            &g_an + &g_pn,
+        ]);
+        let losses = Matrix::from_vec_nd(&[n], losses);
+        let loss = scale * losses.sum();
+        self.losses = Some(losses);
+        loss
+    }
+
+    fn backward_multi(&self, seed: f32) -> Vec<Matrix> {
+        let grads = self.grads.as_ref().expect("Cannot call backward before forward.");
+        grads.iter().map(|g| seed * g).collect()
+    }
+}
+
+// Contrastive loss (Hadsell et al. 2006) over pairs of embeddings, target 1 for similar and
+// 0 for dissimilar pairs: 0.5 * d^2 for similar, 0.5 * max(0, margin - d)^2 for dissimilar
+// ones, d the euclidean distance. Similar pairs are pulled together, dissimilar ones pushed
+// at least margin apart.
+pub struct ContrastiveLoss {
+    margin: f32,
+    reduction: Reduction,
+    grads: Option<Vec<Matrix>>,
+    losses: Option<Matrix>,
+}
+
+impl Default for ContrastiveLoss {
+    fn default() -> Self {
+        Self { margin: 1.0, reduction: Reduction::Mean, grads: None, losses: None }
+    }
+}
+
+impl ContrastiveLoss {
+    // margin 1
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_margin(mut self, margin: f32) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    // Mean is over the pairs
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // per pair losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+}
+
+impl MultiLoss for ContrastiveLoss {
+    fn forward_multi(&mut self, inputs: &[Matrix], target: Option<&Matrix>) -> f32 {
+        let n = check_embeddings("ContrastiveLoss", inputs, 2);
+        let similar = check_labels("ContrastiveLoss", target, n);
+        let (dist, grad) = pairwise_distance(&inputs[0], &inputs[1], 2.0, 1e-6);
+
+        let scale = self.reduction.scale(n);
+        let mut losses = vec![0.0; n];
+        // d loss / d dist
+        let mut d_dist = vec![0.0; n];
+        for (i, (y, d)) in zip(similar, dist).enumerate() {
+            let gap = (self.margin - d).max(0.0);
+            losses[i] = 0.5 * (y * d * d + (1.0 - y) * gap * gap);
+            d_dist[i] = scale * (y * d - (1.0 - y) * gap);
+        }
+        let grad = scale_rows(&grad, d_dist);
+        self.grads = Some(vec![grad.clone(), -&grad]);
+        let losses = Matrix::from_vec_nd(&[n], losses);
+        let loss = scale * losses.sum();
+        self.losses = Some(losses);
+        loss
+    }
+
+    fn backward_multi(&self, seed: f32) -> Vec<Matrix> {
+        let grads = self.grads.as_ref().expect("Cannot call backward before forward.");
+        grads.iter().map(|g| seed * g).collect()
+    }
+}
+
+// Cosine embedding loss over pairs of embeddings, target 1 for similar and -1 for dissimilar
+// pairs: 1 - cos(x1, x2) for similar, max(0, cos(x1, x2) - margin) for dissimilar ones.
+// Only the direction of the embeddings matters, not their length.
+#[derive(Default)]
+pub struct CosineEmbeddingLoss {
+    margin: f32,
+    reduction: Reduction,
+    grads: Option<Vec<Matrix>>,
+    losses: Option<Matrix>,
+}
+
+impl CosineEmbeddingLoss {
+    // margin 0
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // somewhere in [-1, 1], PyTorch suggests 0 to 0.5
+    pub fn with_margin(mut self, margin: f32) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    // Mean is over the pairs
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // per pair losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+}
+
+impl MultiLoss for CosineEmbeddingLoss {
+    fn forward_multi(&mut self, inputs: &[Matrix], target: Option<&Matrix>) -> f32 {
+        let n = check_embeddings("CosineEmbeddingLoss", inputs, 2);
+        let labels = check_labels("CosineEmbeddingLoss", target, n);
+        let (x1, x2) = (&inputs[0], &inputs[1]);
+        // squared norms get an eps as in PyTorch, so zero embeddings don't divide by zero
+        let sq1 = &(x1 * x1).sum_axis(1, true) + 1e-8;
+        let sq2 = &(x2 * x2).sum_axis(1, true) + 1e-8;
+        let norms = (&sq1 * &sq2).sqrt();
+        let cos = &(x1 * x2).sum_axis(1, true) / &norms;
+
+        let scale = self.reduction.scale(n);
+        let mut losses = vec![0.0; n];
+        // d loss / d cos
+        let mut d_cos = vec![0.0; n];
+        for (i, (y, c)) in zip(labels, cos.iter()).enumerate() {
+            if y == 1.0 {
+                (losses[i], d_cos[i]) = (1.0 - c, -scale);
+            } else if y == -1.0 {
+                let violated = c > self.margin;
+                (losses[i], d_cos[i]) = if violated { (c - self.margin, scale) } else { (0.0, 0.0) };
+            } else {
+                panic!("CosineEmbeddingLoss labels have to be 1 or -1, got {y}.");
+            }
+        }
+        // d cos / d x1 = x2 / (|x1| |x2|) - cos * x1 / |x1|^2, symmetric for x2
+        let d_cos = Matrix::from_vec(n, 1, d_cos);
+        let d_x1 = &(&(x2 / &norms) - &(&(x1 * &cos) / &sq1)) * &d_cos;
+        let d_x2 = &(&(x1 / &norms) - &(&(x2 * &cos) / &sq2)) * &d_cos;
+        self.grads = Some(vec![d_x1, d_x2]);
+        let losses = Matrix::from_vec_nd(&[n], losses);
+        let loss = scale * losses.sum();
+        self.losses = Some(losses);
+        loss
+    }
+
+    fn backward_multi(&self, seed: f32) -> Vec<Matrix> {
+        let grads = self.grads.as_ref().expect("Cannot call backward before forward.");
+        grads.iter().map(|g| seed * g).collect()
+    }
+}
+
+// Mean squared error - (input - target)^2
+#[derive(Default)]
+pub struct MSELoss {
+    reduction: Reduction,
+    diff: Option<Matrix>,
+    losses: Option<Matrix>,
+}
+
+impl MSELoss {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // elementwise losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+}
+
+impl Loss for MSELoss {
+    fn forward(&mut self, input: &Matrix, target: &Matrix) -> f32 {
+        let diff = difference("MSELoss", input, target);
+        let losses = &diff * &diff;
+        let loss = self.reduction.reduce(&losses);
+        (self.diff, self.losses) = (Some(diff), Some(losses));
+        loss
+    }
+
+    fn backward(&self, seed: f32) -> Matrix {
+        let diff = self.diff.as_ref().expect("Cannot call backward before forward.");
+        (2.0 * seed * self.reduction.scale(diff.numel())) * diff
+    }
+}
+
+// Mean absolute error - |input - target|
+#[derive(Default)]
+pub struct L1Loss {
+    reduction: Reduction,
+    diff: Option<Matrix>,
+    losses: Option<Matrix>,
+}
+
+impl L1Loss {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // elementwise losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+}
+
+impl Loss for L1Loss {
+    fn forward(&mut self, input: &Matrix, target: &Matrix) -> f32 {
+        let diff = difference("L1Loss", input, target);
+        let losses = diff.apply_unary(|d| d.abs());
+        let loss = self.reduction.reduce(&losses);
+        (self.diff, self.losses) = (Some(diff), Some(losses));
+        loss
+    }
+
+    fn backward(&self, seed: f32) -> Matrix {
+        let diff = self.diff.as_ref().expect("Cannot call backward before forward.");
+        let scale = seed * self.reduction.scale(diff.numel());
+        // subgradient 0 where input == target
+        diff.apply_unary(|d| if *d == 0.0 { 0.0 } else { scale * d.signum() })
+    }
+}
+
+// quadratic for |d| < threshold, linear with matching slope outside, scaled so the
+// linear part has slope `slope`, shared by Huber (slope delta) and SmoothL1 (slope 1)
+fn huber(d: f32, threshold: f32, slope: f32) -> f32 {
+    if d.abs() < threshold {
+        0.5 * d * d * slope / threshold
+    } else {
+        slope * (d.abs() - 0.5 * threshold)
+    }
+}
+
+fn huber_grad(d: f32, threshold: f32, slope: f32) -> f32 {
+    if d.abs() < threshold { d * slope / threshold } else { slope * d.signum() }
+}
+
+// Huber loss - 0.5 * d^2 for |d| < delta, delta * (|d| - 0.5 * delta) beyond, d = input - target.
+// Squared error for small residuals, absolute error (times delta) for outliers.
+pub struct HuberLoss {
+    delta: f32,
+    reduction: Reduction,
+    diff: Option<Matrix>,
+    losses: Option<Matrix>,
+}
+
+impl HuberLoss {
+    pub fn new(delta: f32) -> Self {
+        if delta <= 0.0 { panic!("HuberLoss delta has to be positive, got {delta}.") }
+        Self { delta, reduction: Reduction::Mean, diff: None, losses: None }
+    }
+
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // elementwise losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+}
+
+impl Loss for HuberLoss {
+    fn forward(&mut self, input: &Matrix, target: &Matrix) -> f32 {
+        let diff = difference("HuberLoss", input, target);
+        let losses = diff.apply_unary(|d| huber(*d, self.delta, self.delta));
+        let loss = self.reduction.reduce(&losses);
+        (self.diff, self.losses) = (Some(diff), Some(losses));
+        loss
+    }
+
+    fn backward(&self, seed: f32) -> Matrix {
+        let diff = self.diff.as_ref().expect("Cannot call backward before forward.");
+        let scale = seed * self.reduction.scale(diff.numel());
+        diff.apply_unary(|d| scale * huber_grad(*d, self.delta, self.delta))
+    }
+}
+
+// Smooth L1 loss - 0.5 * d^2 / beta for |d| < beta, |d| - 0.5 * beta beyond.
+// Huber loss divided by beta, so the outlier part is exactly L1 whatever beta is.
+pub struct SmoothL1Loss {
+    beta: f32,
+    reduction: Reduction,
+    diff: Option<Matrix>,
+    losses: Option<Matrix>,
+}
+
+impl Default for SmoothL1Loss {
+    fn default() -> Self {
+        Self { beta: 1.0, reduction: Reduction::Mean, diff: None, losses: None }
+    }
+}
+
+impl SmoothL1Loss {
+    // beta 1
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_beta(mut self, beta: f32) -> Self {
+        if beta <= 0.0 { panic!("SmoothL1Loss beta has to be positive, got {beta}.") }
+        self.beta = beta;
+        self
+    }
+
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // elementwise losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+}
+
+impl Loss for SmoothL1Loss {
+    fn forward(&mut self, input: &Matrix, target: &Matrix) -> f32 {
+        let diff = difference("SmoothL1Loss", input, target);
+        let losses = diff.apply_unary(|d| huber(*d, self.beta, 1.0));
+        let loss = self.reduction.reduce(&losses);
+        (self.diff, self.losses) = (Some(diff), Some(losses));
+        loss
+    }
+
+    fn backward(&self, seed: f32) -> Matrix {
+        let diff = self.diff.as_ref().expect("Cannot call backward before forward.");
+        let scale = seed * self.reduction.scale(diff.numel());
+        diff.apply_unary(|d| scale * huber_grad(*d, self.beta, 1.0))
+    }
+}
+
+// ln(1 + e^x) without overflow
+fn softplus(x: f32) -> f32 {
+    x.max(0.0) + (-x.abs()).exp().ln_1p()
+}
+
+// Binary crossentropy from logits, one independent yes/no per element (multi-label tagging)
+// -w * (pos_weight * y * ln(sigmoid(x)) + (1 - y) * ln(1 - sigmoid(x))),
+// with the log-sigmoids written as softplus for stability. Targets are in [0, 1].
+#[derive(Default)]
+pub struct BCEWithLogitsLoss {
+    pos_weight: Option<Matrix>,
+    weight: Option<Matrix>,
+    reduction: Reduction,
+    input: Option<Matrix>,
+    target: Option<Matrix>,
+    losses: Option<Matrix>,
+}
+
+impl BCEWithLogitsLoss {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // weight of the positive term per class, [classes], broadcast over the last axis.
+    // Above 1 trades precision for recall, e.g. negatives / positives for rare labels.
+    pub fn with_pos_weight(mut self, pos_weight: Matrix) -> Self {
+        self.pos_weight = Some(pos_weight);
+        self
+    }
+
+    // rescales every elementwise loss, broadcast to the input shape
+    pub fn with_weight(mut self, weight: Matrix) -> Self {
+        self.weight = Some(weight);
+        self
+    }
+
+    pub fn with_reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    // elementwise losses of the last forward
+    pub fn losses(&self) -> &Matrix {
+        self.losses.as_ref().expect("Cannot call losses before forward.")
+    }
+
+    // pos_weight * y, the coefficient of -ln(sigmoid(x))
+    fn positive(&self, target: &Matrix) -> Matrix {
+        match &self.pos_weight {
+            Some(p) => target * p,
+            None => target.clone(),
+        }
+    }
+
+    fn weighted(&self, x: Matrix) -> Matrix {
+        match &self.weight {
+            Some(w) => &x * w,
+            None => x,
+        }
+    }
+}
+
+impl Loss for BCEWithLogitsLoss {
+    fn forward(&mut self, input: &Matrix, target: &Matrix) -> f32 {
+        check_shapes("BCEWithLogitsLoss", input, target);
+        // -ln(sigmoid(x)) = softplus(-x), -ln(1 - sigmoid(x)) = softplus(x)
+        let pos = &self.positive(target) * &input.apply_unary(|x| softplus(-x));
+        let neg = &(1.0 - target) * &input.apply_unary(|x| softplus(*x));
+        let losses = self.weighted(&pos + &neg);
+        let loss = self.reduction.reduce(&losses);
+        (self.input, self.target, self.losses) = (Some(input.clone()), Some(target.clone()), Some(losses));
+        loss
+    }
+
+    fn backward(&self, seed: f32) -> Matrix {
+        let input = self.input.as_ref().expect("Cannot call backward before forward.");
+        let target = self.target.as_ref().unwrap();
+        let p = input.apply_unary(|x| sigmoid(*x));
+        let grad = &(&(1.0 - target) * &p) - &(&self.positive(target) * &(1.0 - &p));
+        let grad = self.weighted(grad);
+        (seed * self.reduction.scale(grad.numel())) * &grad
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{gradcheck_loss, gradcheck_multi_loss};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // [rows, cols] of uniform random values in [lo, hi)
+    fn uniform(rows: usize, cols: usize, lo: f32, hi: f32, seed: u64) -> Matrix {
+        &((hi - lo) * &Matrix::random(rows, cols, &mut StdRng::seed_from_u64(seed))) + lo
+    }
+
+    #[test]
+    fn crossentropy_of_uniform_logits_is_ln_classes() {
+        let target = Matrix::from_vec(2, 4, vec![1., 0., 0., 0., 0., 0., 0., 1.]);
+        let loss = Crossentropy::new().forward(&Matrix::full(2, 4, 3.0), &target);
+        assert!((loss - 4f32.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn crossentropy_gradients() {
+        let x = uniform(3, 5, -2.0, 2.0, 0);
+        let target = Matrix::from_vec(3, 5, vec![0., 1., 0., 0., 0., 0., 0., 0., 0., 1., 1., 0., 0., 0., 0.]);
+        assert!(gradcheck_loss(&mut Crossentropy::new(), &x, &target, 1e-2) < 1e-2);
+    }
+
+    #[test]
+    fn regression_loss_values() {
+        let x = Matrix::from_vec(1, 4, vec![0.0, 0.5, -2.0, 3.0]);
+        let target = Matrix::full(1, 4, 0.0);
+        assert_eq!(MSELoss::new().forward(&x, &target), (0.25 + 4.0 + 9.0) / 4.0);
+        assert_eq!(L1Loss::new().with_reduction(Reduction::Sum).forward(&x, &target), 5.5);
+
+        let mut huber = HuberLoss::new(2.0).with_reduction(Reduction::None);
+        assert_eq!(huber.forward(&x, &target), 0.125 + 2.0 + 4.0);
+        assert_eq!(huber.losses().to_vec(), vec![0.0, 0.125, 2.0, 4.0]);
+
+        // SmoothL1 is Huber over beta
+        let mut smooth = SmoothL1Loss::new().with_beta(2.0).with_reduction(Reduction::None);
+        smooth.forward(&x, &target);
+        assert_eq!(smooth.losses().to_vec(), vec![0.0, 0.0625, 1.0, 2.0]);
+        // and L1 once the residuals are beyond beta
+        let y = Matrix::from_vec(1, 2, vec![-1.5, 4.0]);
+        let zero = Matrix::full(1, 2, 0.0);
+        assert_eq!(SmoothL1Loss::new().forward(&y, &zero) + 0.5, L1Loss::new().forward(&y, &zero));
+    }
+
+    #[test]
+    fn regression_loss_gradients() {
+        let (x, target) = (uniform(3, 4, -2.0, 2.0, 1), uniform(3, 4, -2.0, 2.0, 2));
+        for reduction in [Reduction::Mean, Reduction::Sum, Reduction::None] {
+            let losses: Vec<Box<dyn Loss>> = vec![
+                Box::new(MSELoss::new().with_reduction(reduction)),
+                Box::new(L1Loss::new().with_reduction(reduction)),
+                Box::new(HuberLoss::new(1.5).with_reduction(reduction)),
+                Box::new(SmoothL1Loss::new().with_beta(0.5).with_reduction(reduction)),
+            ];
+            for mut loss in losses {
+                assert!(gradcheck_loss(&mut *loss, &x, &target, 1e-3) < 1e-2);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "MSELoss input and target shapes differ, [3, 4] and [3, 1].")]
+    fn regression_losses_dont_broadcast() {
+        MSELoss::new().forward(&Matrix::full(3, 4, 0.0), &Matrix::full(3, 1, 0.0));
+    }
+
+    #[test]
+    fn bce_with_logits_values() {
+        let x = Matrix::from_vec(2, 2, vec![0.0, 2.0, -1.0, 100.0]);
+        let target = Matrix::from_vec(2, 2, vec![1.0, 0.0, 0.5, 1.0]);
+        let mut loss = BCEWithLogitsLoss::new().with_reduction(Reduction::None);
+        loss.forward(&x, &target);
+        let expected = [2f32.ln(), (1.0 + 2f32.exp()).ln(), 0.5 * (1.0 + 1f32.exp()).ln() + 0.5 * (1.0 + (-1f32).exp()).ln(), 0.0];
+        for (l, e) in loss.losses().iter().zip(expected) {
+            assert!((l - e).abs() < 1e-6);
+        }
+
+        // large logits neither overflow nor lose the loss of a confident mistake
+        let mut loss = BCEWithLogitsLoss::new();
+        let l = loss.forward(&Matrix::from_vec(1, 2, vec![-200.0, 200.0]), &Matrix::from_vec(1, 2, vec![1.0, 0.0]));
+        assert_eq!(l, 200.0);
+        assert_eq!(loss.backward(1.0).to_vec(), vec![-0.5, 0.5]);
+    }
+
+    #[test]
+    fn bce_with_logits_gradients() {
+        let x = uniform(4, 3, -3.0, 3.0, 3);
+        let target = Matrix::from_vec(4, 3, vec![1., 0., 0., 0., 1., 1., 0.3, 0., 1., 1., 1., 0.]);
+        let pos_weight = Matrix::from_vec_nd(&[3], vec![1.0, 3.0, 0.5]);
+        let weight = uniform(4, 3, 0.0, 1.0, 4);
+        for reduction in [Reduction::Mean, Reduction::Sum, Reduction::None] {
+            let mut loss = BCEWithLogitsLoss::new().with_reduction(reduction);
+            assert!(gradcheck_loss(&mut loss, &x, &target, 1e-2) < 1e-2);
+            let mut loss = BCEWithLogitsLoss::new().with_pos_weight(pos_weight.clone()).with_weight(weight.clone()).with_reduction(reduction);
+            assert!(gradcheck_loss(&mut loss, &x, &target, 1e-2) < 1e-2);
+        }
+    }
+
+    #[test]
+    fn bce_pos_weight_scales_positive_term() {
+        let x = Matrix::from_vec(1, 2, vec![0.3, -0.7]);
+        let target = Matrix::from_vec(1, 2, vec![1.0, 1.0]);
+        let plain = BCEWithLogitsLoss::new().forward(&x, &target);
+        let weighted = BCEWithLogitsLoss::new().with_pos_weight(Matrix::from_vec_nd(&[2], vec![2.0, 2.0])).forward(&x, &target);
+        assert!((weighted - 2.0 * plain).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sparse_crossentropy_matches_one_hot() {
+        let x = uniform(5, 4, -2.0, 2.0, 3);
+        let labels = Matrix::from_vec(5, 1, vec![0., 3., 1., 1., 2.]);
+        let one_hot = crate::gradcheck::one_hot(&[0, 3, 1, 1, 2], 4);
+        let (mut sparse, mut dense) = (SparseCrossentropy::new(), Crossentropy::new());
+        assert!((sparse.forward(&x, &labels) - dense.forward(&x, &one_hot)).abs() < 1e-6);
+        for (a, b) in sparse.backward(0.5).iter().zip(dense.backward(0.5).iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn sparse_crossentropy_weights_ignore_and_smoothing() {
+        let x = uniform(5, 4, -2.0, 2.0, 4);
+        let labels = Matrix::from_vec_nd(&[5], vec![2., 0., 7., 3., 0.]);
+        let weight = [0.5, 1.0, 2.0, 1.5];
+        let mut loss = SparseCrossentropy::new().with_weight(&weight).with_ignore_index(7).with_reduction(Reduction::None);
+        loss.forward(&x, &labels);
+        let per_sample = loss.losses().to_vec();
+        assert_eq!(per_sample[2], 0.0);
+
+        // weighted mean over the weights of the counted samples
+        let mut mean = SparseCrossentropy::new().with_weight(&weight).with_ignore_index(7);
+        let expected = per_sample.iter().sum::<f32>() / (2.0 + 0.5 + 1.5 + 0.5);
+        assert!((mean.forward(&x, &labels) - expected).abs() < 1e-6);
+        assert_eq!(mean.backward(1.0).get_row(2).to_vec(), vec![0.0; 4]);
+
+        // full smoothing is the crossentropy against the uniform distribution
+        let mut uniform = SparseCrossentropy::new().with_label_smoothing(1.0);
+        let log_p = log_softmax(&x);
+        let two = x.narrow(0, 0..2);
+        let expected = -log_p.narrow(0, 0..2).sum() / 8.0;
+        assert!((uniform.forward(&two, &Matrix::from_vec_nd(&[2], vec![1., 3.])) - expected).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sparse_crossentropy_gradients() {
+        let x = uniform(5, 4, -2.0, 2.0, 5);
+        let labels = Matrix::from_vec_nd(&[5], vec![1., 3., 0., 2., 3.]);
+        for reduction in [Reduction::Mean, Reduction::Sum, Reduction::None] {
+            for smoothing in [0.0, 0.2] {
+                let mut loss = SparseCrossentropy::new().with_label_smoothing(smoothing).with_reduction(reduction);
+                assert!(gradcheck_loss(&mut loss, &x, &labels, 1e-2) < 1e-2);
+                let mut loss = SparseCrossentropy::new()
+                    .with_weight(&[0.3, 1.0, 2.0, 0.7])
+                    .with_ignore_index(2)
+                    .with_label_smoothing(smoothing)
+                    .with_reduction(reduction);
+                assert!(gradcheck_loss(&mut loss, &x, &labels, 1e-2) < 1e-2);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "SparseCrossentropy label 4 out of range for 4 classes.")]
+    fn sparse_crossentropy_rejects_bad_labels() {
+        SparseCrossentropy::new().forward(&uniform(5, 4, -2.0, 2.0, 6), &Matrix::from_vec_nd(&[5], vec![0., 1., 4., 2., 3.]));
+    }
+
+    #[test]
+    #[should_panic(expected = "SparseCrossentropy needs [batch, classes] logits and [batch] labels, got [4] and [1].")]
+    fn sparse_crossentropy_rejects_1d_logits() {
+        SparseCrossentropy::new().forward(&Matrix::from_vec_nd(&[4], vec![0.0; 4]), &Matrix::from_vec_nd(&[1], vec![0.0]));
+    }
+
+    #[test]
+    fn focal_loss_without_focus_is_crossentropy() {
+        let x = uniform(5, 4, -2.0, 2.0, 7);
+        let target = crate::gradcheck::one_hot(&[1, 0, 3, 3, 2], 4);
+        let mut focal = FocalLoss::new(0.0);
+        let mut ce = Crossentropy::new();
+        assert!((focal.forward(&x, &target) - ce.forward(&x, &target)).abs() < 1e-6);
+        for (a, b) in focal.backward(1.0).iter().zip(ce.backward(1.0).iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+        // focusing only ever lowers the loss, most for confident samples
+        let mut focal = FocalLoss::new(2.0).with_reduction(Reduction::None);
+        focal.forward(&x, &target);
+        let mut plain = FocalLoss::new(0.0).with_reduction(Reduction::None);
+        plain.forward(&x, &target);
+        for (f, p) in focal.losses().iter().zip(plain.losses().iter()) {
+            assert!(f < p);
+        }
+    }
+
+    #[test]
+    fn focal_loss_of_saturated_logits() {
+        let x = Matrix::from_vec(1, 2, vec![100.0, 0.0]);
+        let mut focal = FocalLoss::new(0.5);
+        assert_eq!(focal.forward(&x, &crate::gradcheck::one_hot(&[0], 2)), 0.0);
+        assert!(focal.backward(1.0).iter().all(|g| g.is_finite()));
+    }
+
+    #[test]
+    fn focal_loss_gradients() {
+        let x = uniform(5, 4, -2.0, 2.0, 8);
+        let target = crate::gradcheck::one_hot(&[2, 0, 1, 3, 2], 4);
+        for reduction in [Reduction::Mean, Reduction::Sum, Reduction::None] {
+            for gamma in [0.5, 1.0, 2.0] {
+                let mut loss = FocalLoss::new(gamma).with_reduction(reduction);
+                assert!(gradcheck_loss(&mut loss, &x, &target, 1e-2) < 1e-2);
+                let mut loss = FocalLoss::new(gamma).with_alpha(&[0.25, 1.0, 0.5, 2.0]).with_reduction(reduction);
+                assert!(gradcheck_loss(&mut loss, &x, &target, 1e-2) < 1e-2);
+            }
+        }
+    }
+
+    #[test]
+    fn kl_div_loss() {
+        let student = log_softmax(&uniform(5, 4, -2.0, 2.0, 9));
+        let teacher = log_softmax(&uniform(5, 4, -2.0, 2.0, 10));
+        let mut loss = KLDivLoss::new();
+        // zero against itself, positive otherwise
+        assert!(loss.forward(&student, &student.exp()).abs() < 1e-5);
+        let kl = loss.forward(&student, &teacher.exp());
+        assert!(kl > 0.0);
+        // log targets and zero targets
+        assert!((KLDivLoss::new().with_log_target(true).forward(&student, &teacher) - kl).abs() < 1e-6);
+        let target = Matrix::from_vec(1, 2, vec![0.0, 1.0]);
+        let input = Matrix::from_vec(1, 2, vec![(0.25f32).ln(), (0.75f32).ln()]);
+        assert!((KLDivLoss::new().forward(&input, &target) + (0.75f32).ln()).abs() < 1e-6);
+
+        for reduction in [Reduction::Mean, Reduction::Sum, Reduction::None] {
+            let mut loss = KLDivLoss::new().with_reduction(reduction);
+            assert!(gradcheck_loss(&mut loss, &student, &teacher.exp(), 1e-2) < 1e-2);
+        }
+    }
+
+    #[test]
+    fn every_loss_is_a_multi_loss() {
+        let (x, target) = (uniform(3, 4, -2.0, 2.0, 11), uniform(3, 4, -2.0, 2.0, 12));
+        let mut loss = MSELoss::new();
+        let single = loss.forward(&x, &target);
+        let grad = loss.backward(2.0);
+        assert_eq!(loss.forward_multi(&[x], Some(&target)), single);
+        let grads = loss.backward_multi(2.0);
+        assert_eq!(grads.len(), 1);
+        assert_eq!(grads[0].to_vec(), grad.to_vec());
+    }
+
+    #[test]
+    fn triplet_margin_loss() {
+        let anchor = Matrix::from_vec(2, 2, vec![0.0, 0.0, 0.0, 0.0]);
+        let positive = Matrix::from_vec(2, 2, vec![3.0, 4.0, 1.0, 0.0]);
+        let negative = Matrix::from_vec(2, 2, vec![0.0, 2.0, 2.5, 0.0]);
+        let mut loss = TripletMarginLoss::new().with_reduction(Reduction::None);
+        loss.forward_multi(&[anchor.clone(), positive.clone(), negative.clone()], None);
+        // 5 - 2 + 1 and max(0, 1 - 2.5 + 1)
+        let losses = loss.losses().to_vec();
+        assert!((losses[0] - 4.0).abs() < 1e-4 && losses[1] == 0.0);
+        assert_eq!(loss.backward_multi(1.0)[0].get_row(1).to_vec(), vec![0.0, 0.0]);
+        // the positive of the second triplet is closer to its negative than the anchor is
+        let mut swap = TripletMarginLoss::new().with_swap(true).with_reduction(Reduction::None);
+        swap.forward_multi(&[anchor, positive, negative], None);
+        let losses = swap.losses().to_vec();
+        assert!((losses[0] - 4.0).abs() < 1e-4 && (losses[1] - 0.5).abs() < 1e-4);
+
+        let inputs: Vec<Matrix> = (20..23).map(|seed| uniform(4, 3, -1.0, 1.0, seed)).collect();
+        // the 1-norm has kinks wherever two coordinates are equal, smaller steps to miss them
+        for (p, swap, eps) in [(2.0, false, 1e-2), (2.0, true, 1e-2), (1.0, false, 1e-3), (3.0, true, 1e-2)] {
+            let mut loss = TripletMarginLoss::new().with_margin(2.0).with_p(p).with_swap(swap);
+            for err in gradcheck_multi_loss(&mut loss, &inputs, None, eps) {
+                assert!(err < 1e-2, "p {p} swap {swap}: {err}");
+            }
+        }
+    }
+
+    #[test]
+    fn contrastive_loss() {
+        let x1 = Matrix::from_vec(3, 2, vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        let x2 = Matrix::from_vec(3, 2, vec![0.6, 0.8, 0.3, 0.4, 3.0, 4.0]);
+        let similar = Matrix::from_vec_nd(&[3], vec![1.0, 0.0, 0.0]);
+        let mut loss = ContrastiveLoss::new().with_reduction(Reduction::None);
+        loss.forward_multi(&[x1, x2], Some(&similar));
+        // 0.5 * 1^2, 0.5 * (1 - 0.5)^2, beyond the margin
+        for (l, e) in loss.losses().iter().zip([0.5, 0.125, 0.0]) {
+            assert!((l - e).abs() < 1e-5);
+        }
+
+        let inputs: Vec<Matrix> = (30..32).map(|seed| uniform(4, 3, -1.0, 1.0, seed)).collect();
+        let similar = Matrix::from_vec_nd(&[4], vec![1.0, 0.0, 0.0, 1.0]);
+        for reduction in [Reduction::Mean, Reduction::Sum, Reduction::None] {
+            let mut loss = ContrastiveLoss::new().with_margin(2.0).with_reduction(reduction);
+            for err in gradcheck_multi_loss(&mut loss, &inputs, Some(&similar), 1e-2) {
+                assert!(err < 1e-2, "{err}");
+            }
+        }
+    }
+
+    #[test]
+    fn cosine_embedding_loss() {
+        let x1 = Matrix::from_vec(3, 2, vec![1.0, 0.0, 1.0, 1.0, 2.0, 0.0]);
+        let x2 = Matrix::from_vec(3, 2, vec![5.0, 0.0, -1.0, 1.0, 1.0, 1.0]);
+        let labels = Matrix::from_vec_nd(&[3], vec![1.0, 1.0, -1.0]);
+        let mut loss = CosineEmbeddingLoss::new().with_reduction(Reduction::None);
+        loss.forward_multi(&[x1, x2], Some(&labels));
+        // same direction whatever the length, orthogonal, cos 0.707 above margin 0
+        for (l, e) in loss.losses().iter().zip([0.0, 1.0, 0.5f32.sqrt()]) {
+            assert!((l - e).abs() < 1e-5);
+        }
+
+        let inputs: Vec<Matrix> = (40..42).map(|seed| uniform(4, 3, -1.0, 1.0, seed)).collect();
+        let labels = Matrix::from_vec_nd(&[4], vec![1.0, -1.0, -1.0, 1.0]);
+        for reduction in [Reduction::Mean, Reduction::Sum, Reduction::None] {
+            let mut loss = CosineEmbeddingLoss::new().with_margin(-0.5).with_reduction(reduction);
+            for err in gradcheck_multi_loss(&mut loss, &inputs, Some(&labels), 1e-3) {
+                assert!(err < 1e-2);
+            }
+        }
+    }
+}
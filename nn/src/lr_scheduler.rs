@@ -1,7 +1,12 @@
 use crate::optimizer::Optimizer;
+use crate::state::{check_keys, scalar, StateDict, StateDictError};
 
 pub trait Scheduler {
     fn step(&mut self, optimizer: &mut impl Optimizer);
+
+    // step count and whatever else the next lr depends on
+    fn state_dict(&self) -> StateDict;
+    fn load_state_dict(&mut self, state: &StateDict) -> Result<(), StateDictError>;
 }
 
 pub struct ExponentialDecay {
@@ -22,4 +27,18 @@ impl Scheduler for ExponentialDecay {
         let new_lr = self.initial_lr * (-self.k * (self.t as f32)).exp();
         optimizer.set_lr(new_lr);
     }
+
+    fn state_dict(&self) -> StateDict {
+        StateDict::from([
+            ("t".to_string(), scalar(self.t as f32)),
+            ("initial_lr".to_string(), scalar(self.initial_lr)),
+        ])
+    }
+
+    fn load_state_dict(&mut self, state: &StateDict) -> Result<(), StateDictError> {
+        check_keys(&[("t".to_string(), vec![1]), ("initial_lr".to_string(), vec![1])], state)?;
+        self.t = state["t"].get_nd(&[0]) as usize;
+        self.initial_lr = state["initial_lr"].get_nd(&[0]);
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -1,41 +1,115 @@
 use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::error::Error;
+use std::fmt;
 use std::iter::zip;
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread;
 use std::vec;
 use rand::Rng;
 
+// Returned by the `try_` ops when operand shapes don't fit together.
+// The non `try_` versions (and operator overloads) panic with the same message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShapeError {
+    pub op: &'static str,
+    pub lhs: Vec<usize>,
+    pub rhs: Vec<usize>,
+}
+
+impl ShapeError {
+    fn new(op: &'static str, lhs: &[usize], rhs: &[usize]) -> Self {
+        Self { op, lhs: lhs.to_vec(), rhs: rhs.to_vec() }
+    }
+}
+
+impl fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Shape mismatch in {}: {:?} and {:?}.", self.op, self.lhs, self.rhs)
+    }
+}
+
+impl Error for ShapeError {}
+
+// unwraps the result of a `try_` op, panicking with the shape error message
+fn or_panic<T>(result: Result<T, ShapeError>) -> T {
+    result.unwrap_or_else(|e| panic!("{e}"))
+}
+
+// N-dimensional array of f32. Most of the crate only needs two dims (rows and cols),
+// but shape and strides can be of any length, e.g. [batch, chan, height, width] images.
+// Matrices share their storage, so clones, transposes, permutes, reshapes of contiguous
+// data and slices are O(1) views into the same buffer. Writing through `set` copies the
+// viewed elements into a fresh buffer first if anybody else still holds it.
 #[derive(Debug, Clone)]
 pub struct Matrix {
-    data: Vec<f32>,
+    data: Arc<Vec<f32>>,
+    offset: usize,
     strides: Vec<usize>,
     pub shape: Vec<usize>,
 }
 
+// N-d code reads better with this name. It is only an alias, the 2-D helpers like
+// `rows`, `cols`, `get` and `T` are there on tensors too.
+pub type Tensor = Matrix;
+
 impl Matrix {
     pub fn from_vec(rows: usize, cols: usize, vec: Vec<f32>) -> Self{
-        if vec.len() != rows * cols { panic!("Size mismatch.") }
-        Self {
-            data: vec,
-            strides: vec![cols, 1],
-            shape: vec![rows, cols],
+        or_panic(Self::try_from_vec(rows, cols, vec))
+    }
+
+    pub fn try_from_vec(rows: usize, cols: usize, vec: Vec<f32>) -> Result<Self, ShapeError> {
+        Self::try_from_vec_nd(&[rows, cols], vec)
+    }
+
+    pub fn from_vec_nd(shape: &[usize], vec: Vec<f32>) -> Self {
+        or_panic(Self::try_from_vec_nd(shape, vec))
+    }
+
+    // the error reports the vec length as lhs and the requested shape as rhs
+    pub fn try_from_vec_nd(shape: &[usize], vec: Vec<f32>) -> Result<Self, ShapeError> {
+        if vec.len() != shape.iter().product::<usize>() {
+            return Err(ShapeError::new("from_vec", &[vec.len()], shape));
         }
+        Ok(Self {
+            data: Arc::new(vec),
+            offset: 0,
+            strides: contiguous_strides(shape),
+            shape: shape.to_vec(),
+        })
     }
     
     pub fn full(rows: usize, cols: usize, value: f32) -> Self {
-        Self {
-            data: vec![value; rows * cols],
-            strides: vec![cols, 1],
-            shape: vec![rows, cols],
-        }
+        Self::full_nd(&[rows, cols], value)
+    }
+
+    pub fn full_nd(shape: &[usize], value: f32) -> Self {
+        Self::from_vec_nd(shape, vec![value; shape.iter().product()])
     }
 
     pub fn full_like(other: &Self, value: f32) -> Self {
-        Self::full(other.shape[0], other.shape[1], value)
+        Self::full_nd(&other.shape, value)
     }
 
     pub fn random<R: Rng>(rows: usize, cols: usize, rng: &mut R) -> Matrix {
-        Self::from_vec(rows, cols, (0..rows*cols).map(|_| rng.gen::<f32>()).collect())
+        Self::random_nd(&[rows, cols], rng)
     }
 
+    pub fn random_nd<R: Rng>(shape: &[usize], rng: &mut R) -> Matrix {
+        let n = shape.iter().product();
+        Self::from_vec_nd(shape, (0..n).map(|_| rng.gen::<f32>()).collect())
+    }
+
+    pub fn ndim(&self) -> usize {
+        self.shape.len()
+    }
+
+    pub fn numel(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    // rows and cols are the sizes of the first two axes whatever ndim is, e.g. [batch, chan]
+    // of images, so `rows()` is the batch size of any batched input
     pub fn rows(&self) -> usize {
         self.shape[0]
     }
@@ -53,80 +127,446 @@ impl Matrix {
     }
 
     pub fn get(&self, row: usize, col: usize) -> f32 {
-        self.data[self.strides[0] * row + self.strides[1] * col]
+        self.data[self.offset + self.strides[0] * row + self.strides[1] * col]
     }
 
-    // broadcasted version of get
-    fn get_b(&self, row: usize, col: usize) -> f32 {
-        let row_ = broadcast_idx(self.rows(), row);
-        let col_ = broadcast_idx(self.cols(), col);
-        self.get(row_, col_)
+    pub fn get_nd(&self, index: &[usize]) -> f32 {
+        self.data[self.offset_of(index)]
     }
 
-    pub fn get_row(&self, row: usize) -> Self {
-        let mut out: Vec<f32> = vec![];
-        for i in 0..self.cols() {
-            out.push(self.get(row, i));
+    fn offset_of(&self, index: &[usize]) -> usize {
+        if index.len() != self.ndim() {
+            panic!("Index {index:?} has wrong number of dims for shape {:?}.", self.shape);
         }
-        Self::from_vec(1, self.cols(), out)
+        let mut offset = self.offset;
+        for ((i, dim), stride) in zip(zip(index, &self.shape), &self.strides) {
+            if i >= dim { panic!("Index {index:?} out of bounds for shape {:?}.", self.shape) }
+            offset += i * stride;
+        }
+        offset
+    }
+
+    pub fn get_row(&self, row: usize) -> Self {
+        self.slice(row..row + 1, 0..self.cols())
     }
 
     pub fn get_col(&self, col: usize) -> Self {
-        self.T().get_row(col).T()
+        self.slice(0..self.rows(), col..col + 1)
+    }
+
+    // view of the rows x cols block, shares storage with self
+    pub fn slice(&self, rows: Range<usize>, cols: Range<usize>) -> Self {
+        self.narrow(0, rows).narrow(1, cols)
+    }
+
+    // view of the range along one axis, shares storage with self
+    pub fn narrow(&self, axis: usize, range: Range<usize>) -> Self {
+        if axis >= self.ndim() {
+            panic!("Axis {axis} out of range for shape {:?}.", self.shape);
+        }
+        if range.start > range.end || range.end > self.shape[axis] {
+            panic!("Slice {range:?} out of bounds for axis {axis} of shape {:?}.", self.shape);
+        }
+        let mut out = self.clone();
+        out.offset += self.strides[axis] * range.start;
+        out.shape[axis] = range.len();
+        out
+    }
+
+    // view of index i along axis, with that axis removed
+    pub fn select(&self, axis: usize, i: usize) -> Self {
+        self.narrow(axis, i..i + 1).squeeze(axis)
     }
 
     pub fn set(&mut self, row: usize, col: usize, value: f32) {
-        self.data[self.strides[0] * row + self.strides[1] * col] = value;
+        self.set_nd(&[row, col], value);
+    }
+
+    pub fn set_nd(&mut self, index: &[usize], value: f32) {
+        self.make_unique();
+        let idx = self.offset_of(index);
+        Arc::get_mut(&mut self.data).expect("Buffer is unique.")[idx] = value;
     }
 
-    // TODO maybe no clone is needed
+    // elements in row major order for writing in place, e.g. scattering into a few rows
+    // of a big gradient. Copies first if the buffer is shared or the view has gaps.
+    pub fn as_mut_slice(&mut self) -> &mut [f32] {
+        self.make_unique();
+        if self.as_slice().is_none() {
+            *self = Self::from_vec_nd(&self.shape, self.to_vec());
+        }
+        let range = self.offset..self.offset + self.numel();
+        &mut Arc::get_mut(&mut self.data).expect("Buffer is unique.")[range]
+    }
+
+    // copy on write - if the buffer is shared or the view repeats elements through
+    // broadcasting, detach from it before writing
+    fn make_unique(&mut self) {
+        let broadcasted = zip(&self.shape, &self.strides).any(|(d, s)| *d > 1 && *s == 0);
+        if broadcasted || Arc::get_mut(&mut self.data).is_none() {
+            *self = Self::from_vec_nd(&self.shape, self.to_vec());
+        }
+    }
+
+    // reverses all dims, for 2-D it is the usual transpose
+    #[allow(non_snake_case)]
     pub fn T(&self) -> Self {
-        let mut out = Self {
-            data: self.data.clone(),
-            strides: self.strides.clone(),
-            shape: self.shape.clone(),
-        };
+        let mut out = self.clone();
         out.strides.reverse();
         out.shape.reverse();
         out
     }
 
-    pub fn apply_unary<F: Fn(&f32) -> f32>(&self, fn_: F) -> Self{
-        Self {
-            data: self.data.iter().map(fn_).collect(),
-            strides: self.strides.clone(),
-            shape: self.shape.clone(),
+    // swaps two axes
+    pub fn transpose(&self, axis1: usize, axis2: usize) -> Self {
+        let mut out = self.clone();
+        out.strides.swap(axis1, axis2);
+        out.shape.swap(axis1, axis2);
+        out
+    }
+
+    // reorders axes, output axis i is input axis axes[i]
+    pub fn permute(&self, axes: &[usize]) -> Self {
+        let mut seen = vec![false; self.ndim()];
+        for &a in axes {
+            if a >= self.ndim() || seen[a] { panic!("Invalid permutation {axes:?} for {} dims.", self.ndim()) }
+            seen[a] = true;
         }
+        if axes.len() != self.ndim() { panic!("Invalid permutation {axes:?} for {} dims.", self.ndim()) }
+        let mut out = self.clone();
+        out.shape = axes.iter().map(|&a| self.shape[a]).collect();
+        out.strides = axes.iter().map(|&a| self.strides[a]).collect();
+        out
     }
 
-    pub fn apply_binary<F: Fn(&f32, &f32) -> f32>(&self, other: &Self, fn_: F) -> Self {
-        let r = broadcast_shape(self.rows(), other.rows());
-        let c = broadcast_shape(self.cols(), other.cols());
-        let mut out = Self::full(r, c, 0.0);
-        for i in 0..r {
-            for j in 0..c {
-                let s = self.get_b(i, j);
-                let o = other.get_b(i, j);
-                out.set(i, j, fn_(&s, &o));
-            }
+    // same elements in a new shape, a view if the data is contiguous, a copy otherwise
+    pub fn reshape(&self, shape: &[usize]) -> Self {
+        or_panic(self.try_reshape(shape))
+    }
+
+    pub fn try_reshape(&self, shape: &[usize]) -> Result<Self, ShapeError> {
+        if shape.iter().product::<usize>() != self.numel() {
+            return Err(ShapeError::new("reshape", &self.shape, shape));
+        }
+        Ok(match self.as_slice() {
+            Some(_) => Self {
+                data: Arc::clone(&self.data),
+                offset: self.offset,
+                strides: contiguous_strides(shape),
+                shape: shape.to_vec(),
+            },
+            None => Self::from_vec_nd(shape, self.to_vec()),
+        })
+    }
+
+    // collapses all but the first axis, [batch, ...] -> [batch, features]
+    pub fn flatten(&self) -> Self {
+        let rows = self.shape.first().copied().unwrap_or(1);
+        self.reshape(&[rows, self.numel() / rows.max(1)])
+    }
+
+    // removes a size 1 axis
+    pub fn squeeze(&self, axis: usize) -> Self {
+        if self.shape.get(axis) != Some(&1) {
+            panic!("Cannot squeeze axis {axis} of shape {:?}.", self.shape);
         }
+        let mut out = self.clone();
+        out.shape.remove(axis);
+        out.strides.remove(axis);
         out
     }
+
+    // inserts a size 1 axis at position axis
+    pub fn unsqueeze(&self, axis: usize) -> Self {
+        if axis > self.ndim() {
+            panic!("Cannot unsqueeze axis {axis} of shape {:?}.", self.shape);
+        }
+        let mut out = self.clone();
+        out.shape.insert(axis, 1);
+        out.strides.insert(axis, 0);
+        out
+    }
+
+    // view with size 1 axes repeated to match shape, numpy broadcasting rules
+    pub fn broadcast_to(&self, shape: &[usize]) -> Self {
+        or_panic(self.try_broadcast_to(shape))
+    }
+
+    pub fn try_broadcast_to(&self, shape: &[usize]) -> Result<Self, ShapeError> {
+        let error = || ShapeError::new("broadcast_to", &self.shape, shape);
+        if shape.len() < self.ndim() {
+            return Err(error());
+        }
+        let lead = shape.len() - self.ndim();
+        let mut strides = vec![0; shape.len()];
+        for (i, &dim) in self.shape.iter().enumerate() {
+            if dim == shape[lead + i] {
+                strides[lead + i] = self.strides[i];
+            } else if dim != 1 {
+                return Err(error());
+            }
+        }
+        Ok(Self {
+            data: Arc::clone(&self.data),
+            offset: self.offset,
+            strides,
+            shape: shape.to_vec(),
+        })
+    }
+
+    // joins matrices along an existing axis, all other dims have to match
+    pub fn concat(parts: &[Matrix], axis: usize) -> Self {
+        or_panic(Self::try_concat(parts, axis))
+    }
+
+    pub fn try_concat(parts: &[Matrix], axis: usize) -> Result<Self, ShapeError> {
+        let first = parts.first().expect("Nothing to concat.");
+        if axis >= first.ndim() {
+            return Err(ShapeError::new("concat", &first.shape, &[axis]));
+        }
+        let mut shape = first.shape.clone();
+        shape[axis] = 0;
+        for part in parts {
+            let fits = part.ndim() == first.ndim()
+                && zip(&part.shape, &first.shape).enumerate().all(|(i, (a, b))| i == axis || a == b);
+            if !fits { return Err(ShapeError::new("concat", &first.shape, &part.shape)) }
+            shape[axis] += part.shape[axis];
+        }
+        // every part is a contiguous chunk per index of the leading axes
+        let outer: usize = shape[..axis].iter().product();
+        let parts: Vec<Vec<f32>> = parts.iter().map(|p| p.to_vec()).collect();
+        let mut out = Vec::with_capacity(shape.iter().product());
+        for i in 0..outer {
+            for part in &parts {
+                let chunk = part.len() / outer.max(1);
+                out.extend_from_slice(&part[i * chunk..(i + 1) * chunk]);
+            }
+        }
+        Ok(Self::from_vec_nd(&shape, out))
+    }
+
+    // joins matrices of the same shape along a new axis
+    pub fn stack(parts: &[Matrix], axis: usize) -> Self {
+        let parts: Vec<Matrix> = parts.iter().map(|p| p.unsqueeze(axis)).collect();
+        Self::concat(&parts, axis)
+    }
+
+    // viewed elements as a slice, if they are laid out row major without gaps
+    fn as_slice(&self) -> Option<&[f32]> {
+        let contiguous = zip(zip(&self.shape, &self.strides), contiguous_strides(&self.shape))
+            .all(|((dim, stride), expected)| *dim <= 1 || *stride == expected);
+        if !contiguous { return None }
+        Some(&self.data[self.offset..self.offset + self.numel()])
+    }
+
+    // elements in row major order
+    pub fn iter(&self) -> impl Iterator<Item = f32> + '_ {
+        StridedOffsets::new(self).map(|o| self.data[o])
+    }
+
+    pub fn to_vec(&self) -> Vec<f32> {
+        match self.as_slice() {
+            Some(slice) => slice.to_vec(),
+            None => self.iter().collect(),
+        }
+    }
+
+    pub fn apply_unary<F: Fn(&f32) -> f32>(&self, fn_: F) -> Self{
+        let data = match self.as_slice() {
+            Some(slice) => slice.iter().map(fn_).collect(),
+            None => self.iter().map(|x| fn_(&x)).collect(),
+        };
+        Self::from_vec_nd(&self.shape, data)
+    }
+
+    pub fn apply_binary<F: Fn(&f32, &f32) -> f32>(&self, other: &Self, fn_: F) -> Self {
+        or_panic(self.try_apply_binary(other, fn_))
+    }
+
+    pub fn try_apply_binary<F: Fn(&f32, &f32) -> f32>(&self, other: &Self, fn_: F) -> Result<Self, ShapeError> {
+        self.zip_with("apply_binary", other, fn_)
+    }
+
+    // elementwise op with broadcasting, op is the name reported on shape mismatch
+    fn zip_with<F: Fn(&f32, &f32) -> f32>(&self, op: &'static str, other: &Self, fn_: F) -> Result<Self, ShapeError> {
+        let shape = try_broadcast_shape(&self.shape, &other.shape)
+            .map_err(|_| ShapeError::new(op, &self.shape, &other.shape))?;
+        if let (true, true, Some(s), Some(o)) = (self.shape == shape, other.shape == shape, self.as_slice(), other.as_slice()) {
+            return Ok(Self::from_vec_nd(&shape, zip(s, o).map(|(s, o)| fn_(s, o)).collect()));
+        }
+        let s = self.broadcast_to(&shape);
+        let o = other.broadcast_to(&shape);
+        Ok(Self::from_vec_nd(&shape, zip(s.iter(), o.iter()).map(|(s, o)| fn_(&s, &o)).collect()))
+    }
+
+    pub fn try_add(&self, other: &Self) -> Result<Self, ShapeError> {
+        self.zip_with("add", other, |x, y| x + y)
+    }
+    pub fn try_sub(&self, other: &Self) -> Result<Self, ShapeError> {
+        self.zip_with("sub", other, |x, y| x - y)
+    }
+    pub fn try_mul(&self, other: &Self) -> Result<Self, ShapeError> {
+        self.zip_with("mul", other, |x, y| x * y)
+    }
+    pub fn try_div(&self, other: &Self) -> Result<Self, ShapeError> {
+        self.zip_with("div", other, |x, y| x / y)
+    }
 }
 
 
 // helpers
 
-fn broadcast_shape(dim1: usize, dim2: usize) -> usize {
-    if dim1 == 1 || dim1 == dim2 { return dim2 }
-    if dim2 == 1 { return dim1 }
-    panic!("Cannot broadcast {dim1} to {dim2}.");
+// numpy style, shapes are aligned from the right and size 1 dims stretch to match
+pub fn broadcast_shape(shape1: &[usize], shape2: &[usize]) -> Vec<usize> {
+    or_panic(try_broadcast_shape(shape1, shape2))
 }
 
-fn broadcast_idx(dim: usize, idx: usize) -> usize {
-    if dim == 1 { return 0 }
-    if idx < dim { return idx } 
-    panic!("Cannot broadcast {idx} to {dim}.");
+pub fn try_broadcast_shape(shape1: &[usize], shape2: &[usize]) -> Result<Vec<usize>, ShapeError> {
+    let n = shape1.len().max(shape2.len());
+    let dim = |shape: &[usize], i: usize| if i + shape.len() >= n { shape[i + shape.len() - n] } else { 1 };
+    (0..n)
+        .map(|i| {
+            let (dim1, dim2) = (dim(shape1, i), dim(shape2, i));
+            if dim1 == 1 || dim1 == dim2 { return Ok(dim2) }
+            if dim2 == 1 { return Ok(dim1) }
+            Err(ShapeError::new("broadcast", shape1, shape2))
+        })
+        .collect()
+}
+
+fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+// values along one axis of a strided view
+#[derive(Clone)]
+struct AxisLane<'a> {
+    data: &'a [f32],
+    offset: usize,
+    stride: usize,
+    remaining: usize,
+}
+
+impl Iterator for AxisLane<'_> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.remaining == 0 { return None }
+        self.remaining -= 1;
+        let x = self.data[self.offset];
+        self.offset += self.stride;
+        Some(x)
+    }
+}
+
+// index of the element that beats all before it, as f32.
+// NaN beats everything, so the first NaN of a lane is its argmax and argmin as in numpy.
+fn arg_best<F: Fn(f32, f32) -> bool>(lane: AxisLane, beats: F) -> f32 {
+    let mut best = (0, f32::NAN);
+    for (i, x) in lane.enumerate() {
+        if i == 0 || (x.is_nan() && !best.1.is_nan()) || beats(x, best.1) { best = (i, x) }
+    }
+    best.0 as f32
+}
+
+// walks the buffer offsets of a strided view in row major order
+struct StridedOffsets<'a> {
+    shape: &'a [usize],
+    strides: &'a [usize],
+    index: Vec<usize>,
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> StridedOffsets<'a> {
+    fn new(m: &'a Matrix) -> Self {
+        Self {
+            shape: &m.shape,
+            strides: &m.strides,
+            index: vec![0; m.ndim()],
+            offset: m.offset,
+            remaining: m.numel(),
+        }
+    }
+}
+
+impl Iterator for StridedOffsets<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.remaining == 0 { return None }
+        self.remaining -= 1;
+        let current = self.offset;
+        // odometer increment, last axis fastest
+        for axis in (0..self.index.len()).rev() {
+            self.index[axis] += 1;
+            self.offset += self.strides[axis];
+            if self.index[axis] < self.shape[axis] { break }
+            self.offset -= self.strides[axis] * self.shape[axis];
+            self.index[axis] = 0;
+        }
+        Some(current)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+// matmul tiling, a TILE_M x TILE_K block of lhs and TILE_N x TILE_K block of rhs^T
+// fit into L2 together with the output tile
+const TILE_M: usize = 32;
+const TILE_N: usize = 64;
+const TILE_K: usize = 256;
+
+// below this many multiply-adds spawning threads costs more than it saves
+const MATMUL_PARALLEL_THRESHOLD: usize = 1 << 18;
+
+fn matmul_threads(m: usize, k: usize, n: usize) -> usize {
+    if m * k * n < MATMUL_PARALLEL_THRESHOLD { return 1 }
+    let available = thread::available_parallelism().map(|t| t.get()).unwrap_or(1);
+    available.min(m.div_ceil(TILE_M)).max(1)
+}
+
+// out (m x n) += lhs (m x k) * rhs_t^T, where rhs_t is n x k, all row major
+fn matmul_kernel(lhs: &[f32], rhs_t: &[f32], out: &mut [f32], k: usize, n: usize) {
+    let m = out.len() / n;
+    for i0 in (0..m).step_by(TILE_M) {
+        let i1 = (i0 + TILE_M).min(m);
+        for j0 in (0..n).step_by(TILE_N) {
+            let j1 = (j0 + TILE_N).min(n);
+            for k0 in (0..k).step_by(TILE_K) {
+                let k1 = (k0 + TILE_K).min(k);
+                for i in i0..i1 {
+                    let a = &lhs[i * k + k0..i * k + k1];
+                    for j in j0..j1 {
+                        let b = &rhs_t[j * k + k0..j * k + k1];
+                        out[i * n + j] += dot_unrolled(a, b);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// dot product with independent accumulators so the compiler can vectorize it
+fn dot_unrolled(a: &[f32], b: &[f32]) -> f32 {
+    let mut acc = [0.0f32; 8];
+    let a_chunks = a.chunks_exact(8);
+    let b_chunks = b.chunks_exact(8);
+    let tail: f32 = zip(a_chunks.remainder(), b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    for (x, y) in zip(a_chunks, b_chunks) {
+        for l in 0..8 {
+            acc[l] += x[l] * y[l];
+        }
+    }
+    acc.iter().sum::<f32>() + tail
 }
 
 
@@ -148,7 +588,7 @@ impl Add<&Matrix> for f32 {
 impl Add<&Matrix> for &Matrix {
     type Output = Matrix;
     fn add(self, rhs: &Matrix) -> Self::Output {
-        self.apply_binary(rhs, |x, y| x + y)
+        or_panic(self.try_add(rhs))
     }
 }
 
@@ -168,7 +608,7 @@ impl Mul<&Matrix> for f32 {
 impl Mul<&Matrix> for &Matrix {
     type Output = Matrix;
     fn mul(self, rhs: &Matrix) -> Self::Output {
-        self.apply_binary(rhs, |x, y| x * y)
+        or_panic(self.try_mul(rhs))
     }
 }
 
@@ -188,7 +628,7 @@ impl Div<&Matrix> for f32 {
 impl Div<&Matrix> for &Matrix {
     type Output = Matrix;
     fn div(self, rhs: &Matrix) -> Self::Output {
-        self.apply_binary(rhs, |x, y| x / y)
+        or_panic(self.try_div(rhs))
     }
 }
 
@@ -208,7 +648,7 @@ impl Sub<&Matrix> for f32 {
 impl Sub<&Matrix> for &Matrix {
     type Output = Matrix;
     fn sub(self, rhs: &Matrix) -> Self::Output {
-        self.apply_binary(rhs, |x, y| x - y)
+        or_panic(self.try_sub(rhs))
     }
 }
 
@@ -223,12 +663,86 @@ impl Neg for &Matrix {
 // exp and log, maximum
 impl Matrix {
     pub fn dot(&self, other: &Self) -> f32 {
-        if !(self.is_row() && other.is_col()) { panic!("Shape mismatch in dot.") }
-        zip(&self.data, &other.data).map(|(x, y)| x * y).sum()
+        or_panic(self.try_dot(other))
+    }
+
+    // row times col
+    pub fn try_dot(&self, other: &Self) -> Result<f32, ShapeError> {
+        let fits = self.ndim() == 2 && other.ndim() == 2
+            && self.is_row() && other.is_col() && self.cols() == other.rows();
+        if !fits { return Err(ShapeError::new("dot", &self.shape, &other.shape)) }
+        Ok(zip(self.iter(), other.iter()).map(|(x, y)| x * y).sum())
     }
 
+    // cache-blocked matmul, row blocks of the output are split across threads
     pub fn matmul(&self, other: &Self) -> Self {
-        if self.cols() != other.rows() { panic!("Shape mismatch in matmul.") }
+        or_panic(self.try_matmul(other))
+    }
+
+    pub fn try_matmul(&self, other: &Self) -> Result<Self, ShapeError> {
+        if self.ndim() != 2 || other.ndim() != 2 || self.cols() != other.rows() {
+            return Err(ShapeError::new("matmul", &self.shape, &other.shape));
+        }
+        let (m, k, n) = (self.rows(), self.cols(), other.cols());
+        if m == 0 || n == 0 {
+            return Ok(Matrix::full(m, n, 0.0));
+        }
+
+        // pack lhs rows and rhs cols contiguously so the kernel only does unit stride loads
+        let lhs = self.to_vec();
+        let rhs_t = other.T().to_vec();
+        let mut out = vec![0.0f32; m * n];
+
+        let threads = matmul_threads(m, k, n);
+        if threads == 1 {
+            matmul_kernel(&lhs, &rhs_t, &mut out, k, n);
+        } else {
+            let rows_per_thread = m.div_ceil(threads);
+            thread::scope(|s| {
+                for (i, out_block) in out.chunks_mut(rows_per_thread * n).enumerate() {
+                    let rows = out_block.len() / n;
+                    let lhs_block = &lhs[i * rows_per_thread * k..(i * rows_per_thread + rows) * k];
+                    let rhs_t = &rhs_t;
+                    s.spawn(move || matmul_kernel(lhs_block, rhs_t, out_block, k, n));
+                }
+            });
+        }
+        Ok(Self::from_vec(m, n, out))
+    }
+
+    // matmul of the last two dims, leading dims are batch dims and broadcast,
+    // [..., m, k] x [..., k, n] -> [..., m, n], e.g. attention over [batch, heads, time, dim]
+    pub fn batched_matmul(&self, other: &Self) -> Self {
+        or_panic(self.try_batched_matmul(other))
+    }
+
+    pub fn try_batched_matmul(&self, other: &Self) -> Result<Self, ShapeError> {
+        let error = || ShapeError::new("batched_matmul", &self.shape, &other.shape);
+        if self.ndim() < 2 || other.ndim() < 2 {
+            return Err(error());
+        }
+        let (lhs_batch, lhs_mat) = self.shape.split_at(self.ndim() - 2);
+        let (rhs_batch, rhs_mat) = other.shape.split_at(other.ndim() - 2);
+        if lhs_mat[1] != rhs_mat[0] {
+            return Err(error());
+        }
+        let batch = try_broadcast_shape(lhs_batch, rhs_batch).map_err(|_| error())?;
+        let count = batch.iter().product();
+        let (m, k, n) = (lhs_mat[0], lhs_mat[1], rhs_mat[1]);
+        let lhs = self.broadcast_to(&[&batch[..], lhs_mat].concat()).reshape(&[count, m, k]);
+        let rhs = other.broadcast_to(&[&batch[..], rhs_mat].concat()).reshape(&[count, k, n]);
+        let mut out = Vec::with_capacity(count * m * n);
+        for b in 0..count {
+            out.extend(lhs.select(0, b).matmul(&rhs.select(0, b)).to_vec());
+        }
+        Ok(Self::from_vec_nd(&[&batch[..], &[m, n]].concat(), out))
+    }
+
+    // reference triple loop matmul, kept around for benchmarking and testing the blocked one
+    pub fn matmul_naive(&self, other: &Self) -> Self {
+        if self.ndim() != 2 || other.ndim() != 2 || self.cols() != other.rows() {
+            panic!("{}", ShapeError::new("matmul", &self.shape, &other.shape));
+        }
         let mut out = Matrix::full(self.rows(), other.cols(), 0.0);
         for i in 0..self.rows() {
             for j in 0..other.cols() {
@@ -241,37 +755,91 @@ impl Matrix {
         out
     }
 
-    // sums
+    // full reductions
     pub fn sum(&self) -> f32 {
-        self.data.iter().sum()
-    }
-    pub fn row_sum(&self) -> Matrix {
-        Self::from_vec(1, self.cols(), 
-            (0..self.cols())
-            .map(|r| self.get_col(r).sum())
-            .collect()
-        )
+        match self.as_slice() {
+            Some(slice) => slice.iter().sum(),
+            None => self.iter().sum(),
+        }
     }
-    pub fn col_sum(&self) -> Matrix {
-        self.T().row_sum().T()
+    pub fn mean(&self) -> f32 {
+        self.sum() / self.numel() as f32
     }
-
-    // maxes
     pub fn max(&self) -> f32 {
-        self.data.iter()
+        self.iter()
             .reduce(|a, b| if b > a { b } else { a })
             .expect("idk vro something went wrong")
-            .clone()
     }
-    pub fn row_max(&self) -> Matrix {
-        Self::from_vec(1, self.cols(), 
-            (0..self.cols())
-            .map(|r| self.get_col(r).max())
-            .collect()
-        )
+    pub fn min(&self) -> f32 {
+        self.iter()
+            .reduce(|a, b| if b < a { b } else { a })
+            .expect("Cannot take min of an empty matrix.")
+    }
+
+    // axis reductions
+    // `axis` is the axis that gets reduced away, so for a [rows, cols] matrix
+    // `sum_axis(0, _)` adds up the rows and gives one value per col.
+    // With `keepdim` the reduced axis stays as size 1, so the result broadcasts against self.
+    pub fn sum_axis(&self, axis: usize, keepdim: bool) -> Self {
+        self.reduce_axis(axis, keepdim, |lane| lane.sum())
+    }
+    pub fn mean_axis(&self, axis: usize, keepdim: bool) -> Self {
+        let n = self.shape[axis] as f32;
+        self.reduce_axis(axis, keepdim, |lane| lane.sum::<f32>() / n)
+    }
+    // biased (population) variance, divides by n
+    pub fn var_axis(&self, axis: usize, keepdim: bool) -> Self {
+        let n = self.shape[axis] as f32;
+        self.reduce_axis(axis, keepdim, |lane| {
+            let mean = lane.clone().sum::<f32>() / n;
+            lane.map(|x| (x - mean) * (x - mean)).sum::<f32>() / n
+        })
+    }
+    pub fn std_axis(&self, axis: usize, keepdim: bool) -> Self {
+        self.var_axis(axis, keepdim).sqrt()
+    }
+    pub fn prod_axis(&self, axis: usize, keepdim: bool) -> Self {
+        self.reduce_axis(axis, keepdim, |lane| lane.product())
+    }
+    pub fn max_axis(&self, axis: usize, keepdim: bool) -> Self {
+        self.reduce_axis(axis, keepdim, |lane| lane.fold(f32::NEG_INFINITY, f32::max))
     }
-    pub fn col_max(&self) -> Matrix {
-        self.T().row_max().T()
+    pub fn min_axis(&self, axis: usize, keepdim: bool) -> Self {
+        self.reduce_axis(axis, keepdim, |lane| lane.fold(f32::INFINITY, f32::min))
+    }
+    // ln(sum(exp(x))), shifted by the max so large inputs don't overflow
+    pub fn logsumexp_axis(&self, axis: usize, keepdim: bool) -> Self {
+        self.reduce_axis(axis, keepdim, |lane| {
+            let max = lane.clone().fold(f32::NEG_INFINITY, f32::max);
+            if max == f32::NEG_INFINITY { return max }
+            max + lane.map(|x| (x - max).exp()).sum::<f32>().ln()
+        })
+    }
+    // indices are stored as f32, ties go to the first occurrence, a NaN wins over any number
+    pub fn argmax_axis(&self, axis: usize, keepdim: bool) -> Self {
+        self.reduce_axis(axis, keepdim, |lane| arg_best(lane, |x, best| x > best))
+    }
+    pub fn argmin_axis(&self, axis: usize, keepdim: bool) -> Self {
+        self.reduce_axis(axis, keepdim, |lane| arg_best(lane, |x, best| x < best))
+    }
+
+    // applies `fn_` to every lane of values along axis, walking the strides directly
+    fn reduce_axis<F: Fn(AxisLane) -> f32>(&self, axis: usize, keepdim: bool, fn_: F) -> Self {
+        if axis >= self.ndim() {
+            panic!("Axis {axis} out of range for shape {:?}.", self.shape);
+        }
+        let (len, stride) = (self.shape[axis], self.strides[axis]);
+
+        // offsets of the first element of each lane
+        let mut starts = self.clone();
+        starts.shape[axis] = 1;
+        let data = StridedOffsets::new(&starts)
+            .map(|offset| fn_(AxisLane { data: &self.data, offset, stride, remaining: len }))
+            .collect();
+
+        let mut shape = self.shape.clone();
+        if keepdim { shape[axis] = 1 } else { shape.remove(axis); }
+        Self::from_vec_nd(&shape, data)
     }
 
     // exp and log
@@ -291,4 +859,227 @@ impl Matrix {
     pub fn maximum(&self, other: f32) -> Self {
         self.apply_unary(|x| (x >= &other) as i32 as f32)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn assert_close(a: &Matrix, b: &Matrix) {
+        assert_eq!(a.shape, b.shape);
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() <= 1e-4 * y.abs().max(1.0), "{x} != {y}");
+        }
+    }
+
+    #[test]
+    fn matmul_matches_naive_for_odd_shapes() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // none of them a multiple of the tiles, k spans two TILE_K blocks
+        for (m, k, n) in [(1, 1, 1), (3, 5, 7), (33, 300, 65), (70, 9, 1)] {
+            let (a, b) = (Matrix::random(m, k, &mut rng), Matrix::random(k, n, &mut rng));
+            assert_close(&a.matmul(&b), &a.matmul_naive(&b));
+        }
+    }
+
+    #[test]
+    fn matmul_of_views() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let a = Matrix::random(50, 40, &mut rng).T();
+        let b = Matrix::random(60, 80, &mut rng).slice(5..55, 3..70);
+        assert_close(&a.matmul(&b), &a.matmul_naive(&b));
+        // both operands views, sliced and transposed
+        let c = Matrix::random(40, 80, &mut rng).slice(2..30, 5..72).T();
+        assert_close(&b.matmul(&c), &b.matmul_naive(&c));
+    }
+
+    #[test]
+    fn matmul_with_empty_inner_dim_is_zero() {
+        let a = Matrix::from_vec(3, 0, vec![]);
+        let b = Matrix::from_vec(0, 4, vec![]);
+        assert_eq!(a.matmul(&b).to_vec(), vec![0.0; 12]);
+        assert_eq!(a.matmul_naive(&b).to_vec(), vec![0.0; 12]);
+    }
+
+    #[test]
+    fn threaded_matmul_matches_naive() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let (m, k, n) = (130, 70, 60);
+        assert!(m * k * n >= MATMUL_PARALLEL_THRESHOLD);
+        let available = thread::available_parallelism().map(|t| t.get()).unwrap_or(1);
+        assert_eq!(matmul_threads(m, k, n), available.min(m.div_ceil(TILE_M)));
+        let (a, b) = (Matrix::random(m, k, &mut rng), Matrix::random(k, n, &mut rng));
+        assert_close(&a.matmul(&b), &a.matmul_naive(&b));
+    }
+
+    #[test]
+    fn views_share_storage_until_written() {
+        let mut original = Matrix::from_vec(2, 3, vec![1., 2., 3., 4., 5., 6.]);
+        let mut view = original.T().get_row(1);
+        assert!(Arc::ptr_eq(&original.data, &view.data));
+
+        // writing through the view detaches it, the original keeps its values
+        view.set(0, 1, 50.0);
+        assert!(!Arc::ptr_eq(&original.data, &view.data));
+        assert_eq!(view.to_vec(), vec![2., 50.]);
+        assert_eq!(original.to_vec(), vec![1., 2., 3., 4., 5., 6.]);
+
+        // and the other way round
+        let view = original.slice(0..2, 1..3);
+        original.set(1, 2, 60.0);
+        assert_eq!(view.to_vec(), vec![2., 3., 5., 6.]);
+        assert_eq!(original.to_vec(), vec![1., 2., 3., 4., 5., 60.]);
+
+        // a buffer nobody else holds is written in place
+        let before = Arc::as_ptr(&original.data);
+        drop(view);
+        original.set(0, 0, 10.0);
+        assert_eq!(Arc::as_ptr(&original.data), before);
+        assert_eq!(original.get(0, 0), 10.0);
+    }
+
+    fn arange(shape: &[usize]) -> Matrix {
+        Matrix::from_vec_nd(shape, (0..shape.iter().product::<usize>()).map(|i| i as f32).collect())
+    }
+
+    #[test]
+    fn permute_reorders_elements() {
+        let x = arange(&[2, 3, 4]);
+        let p = x.permute(&[2, 0, 1]);
+        assert_eq!(p.shape, vec![4, 2, 3]);
+        assert_eq!(p.get_nd(&[3, 1, 2]), x.get_nd(&[1, 2, 3]));
+        assert_eq!(p.to_vec()[..8], [0., 4., 8., 12., 16., 20., 1., 5.]);
+        assert!(Arc::ptr_eq(&x.data, &p.data));
+    }
+
+    #[test]
+    fn reshape_of_views() {
+        let x = arange(&[2, 3, 4]);
+        // contiguous data stays a view
+        assert!(Arc::ptr_eq(&x.data, &x.reshape(&[6, 4]).data));
+        assert!(Arc::ptr_eq(&x.data, &x.narrow(0, 1..2).reshape(&[3, 4]).data));
+
+        // others are copied in logical order
+        let p = x.permute(&[1, 0, 2]);
+        let r = p.reshape(&[6, 4]);
+        assert!(!Arc::ptr_eq(&x.data, &r.data));
+        assert_eq!(r.to_vec(), p.to_vec());
+        assert_eq!(r.get_row(1).to_vec(), vec![12., 13., 14., 15.]);
+        let n = x.narrow(2, 1..3).reshape(&[12]);
+        assert_eq!(n.to_vec()[..6], [1., 2., 5., 6., 9., 10.]);
+        assert!(x.try_reshape(&[5, 5]).is_err());
+    }
+
+    #[test]
+    fn broadcast_to_repeats_with_zero_strides() {
+        let col = arange(&[3, 1]);
+        let b = col.broadcast_to(&[2, 3, 4]);
+        assert_eq!(b.strides, vec![0, 1, 0]);
+        assert!(Arc::ptr_eq(&col.data, &b.data));
+        assert_eq!(b.select(0, 1).get_row(2).to_vec(), vec![2.; 4]);
+        assert_eq!(b.sum(), 2.0 * 4.0 * 3.0);
+
+        let err = col.try_broadcast_to(&[3, 2, 4]).unwrap_err();
+        assert_eq!(err, ShapeError::new("broadcast_to", &[3, 1], &[3, 2, 4]));
+        assert!(arange(&[2, 3]).try_broadcast_to(&[3]).is_err());
+    }
+
+    #[test]
+    fn squeeze_and_unsqueeze() {
+        let x = arange(&[2, 1, 3]);
+        assert_eq!(x.squeeze(1).shape, vec![2, 3]);
+        assert_eq!(x.unsqueeze(3).shape, vec![2, 1, 3, 1]);
+        assert_eq!(x.unsqueeze(0).squeeze(0).to_vec(), x.to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot squeeze axis 3 of shape [2, 1, 3].")]
+    fn squeeze_out_of_range() {
+        arange(&[2, 1, 3]).squeeze(3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot unsqueeze axis 4 of shape [2, 1, 3].")]
+    fn unsqueeze_out_of_range() {
+        arange(&[2, 1, 3]).unsqueeze(4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Axis 2 out of range for shape [2, 3].")]
+    fn narrow_out_of_range() {
+        arange(&[2, 3]).narrow(2, 0..1);
+    }
+
+    #[test]
+    fn axis_reductions_of_a_permuted_view() {
+        // p[a][b][c] = 6b + 2c + a, strided since it's a permute of a contiguous buffer
+        let p = arange(&[2, 3, 2]).permute(&[2, 0, 1]);
+        let lse = (1.0 + 2f32.exp() + 4f32.exp()).ln();
+        let var: f32 = 8.0 / 3.0;
+        let cases = [
+            ("sum 2", p.sum_axis(2, false), vec![2, 2], vec![6., 24., 9., 27.]),
+            ("sum 0 keepdim", p.sum_axis(0, true), vec![1, 2, 3], vec![1., 5., 9., 13., 17., 21.]),
+            ("sum 1 keepdim", p.sum_axis(1, true), vec![2, 1, 3], vec![6., 10., 14., 8., 12., 16.]),
+            ("mean 2", p.mean_axis(2, false), vec![2, 2], vec![2., 8., 3., 9.]),
+            ("mean 0", p.mean_axis(0, false), vec![2, 3], vec![0.5, 2.5, 4.5, 6.5, 8.5, 10.5]),
+            ("var 2 keepdim", p.var_axis(2, true), vec![2, 2, 1], vec![var; 4]),
+            ("var 1", p.var_axis(1, false), vec![2, 3], vec![9.; 6]),
+            ("std 2", p.std_axis(2, false), vec![2, 2], vec![var.sqrt(); 4]),
+            ("prod 2", p.prod_axis(2, false), vec![2, 2], vec![0., 480., 15., 693.]),
+            ("max 2", p.max_axis(2, false), vec![2, 2], vec![4., 10., 5., 11.]),
+            ("max 0 keepdim", p.max_axis(0, true), vec![1, 2, 3], vec![1., 3., 5., 7., 9., 11.]),
+            ("min 2", p.min_axis(2, false), vec![2, 2], vec![0., 6., 1., 7.]),
+            ("min 1", p.min_axis(1, false), vec![2, 3], vec![0., 2., 4., 1., 3., 5.]),
+            ("logsumexp 2", p.logsumexp_axis(2, false), vec![2, 2], vec![lse, 6. + lse, 1. + lse, 7. + lse]),
+            ("argmax 2", p.argmax_axis(2, false), vec![2, 2], vec![2.; 4]),
+            ("argmax 0 keepdim", p.argmax_axis(0, true), vec![1, 2, 3], vec![1.; 6]),
+            ("argmin 1", p.argmin_axis(1, false), vec![2, 3], vec![0.; 6]),
+        ];
+        for (name, got, shape, expected) in cases {
+            assert_eq!(got.shape, shape, "{name}");
+            for (g, e) in got.iter().zip(expected) {
+                assert!((g - e).abs() < 1e-4, "{name}: {g} != {e}");
+            }
+        }
+    }
+
+    #[test]
+    fn arg_reductions_with_nan() {
+        let x = Matrix::from_vec(3, 4, vec![
+            1., f32::NAN, 3., f32::NAN,
+            f32::NAN, 5., 2., 0.,
+            4., 4., -1., 4.,
+        ]);
+        // the first NaN wins, otherwise the first of equal values
+        assert_eq!(x.argmax_axis(1, false).to_vec(), vec![1., 0., 0.]);
+        assert_eq!(x.argmin_axis(1, false).to_vec(), vec![1., 0., 2.]);
+    }
+
+    #[test]
+    fn try_ops_report_op_and_shapes() {
+        let (a, b) = (Matrix::full(2, 3, 1.0), Matrix::full(2, 4, 1.0));
+        let err = a.try_matmul(&b).unwrap_err();
+        assert_eq!((err.op, err.lhs.clone(), err.rhs.clone()), ("matmul", vec![2, 3], vec![2, 4]));
+        assert_eq!(err.to_string(), "Shape mismatch in matmul: [2, 3] and [2, 4].");
+
+        let err = a.try_add(&b).unwrap_err();
+        assert_eq!(err, ShapeError { op: "add", lhs: vec![2, 3], rhs: vec![2, 4] });
+        assert_eq!(err.to_string(), "Shape mismatch in add: [2, 3] and [2, 4].");
+
+        let err = Matrix::try_from_vec_nd(&[2, 2], vec![1.0; 5]).unwrap_err();
+        assert_eq!(err, ShapeError { op: "from_vec", lhs: vec![5], rhs: vec![2, 2] });
+        assert_eq!(err.to_string(), "Shape mismatch in from_vec: [5] and [2, 2].");
+
+        // the ok paths agree with the panicking versions
+        let c = Matrix::full(3, 4, 2.0);
+        assert_eq!(a.try_matmul(&c).unwrap().to_vec(), a.matmul(&c).to_vec());
+        assert_eq!(a.try_add(&Matrix::full(1, 3, 2.0)).unwrap().to_vec(), vec![3.0; 6]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Shape mismatch in mul: [2, 3] and [3, 2].")]
+    fn operators_panic_with_the_shape_error() {
+        let _ = &Matrix::full(2, 3, 1.0) * &Matrix::full(3, 2, 1.0);
+    }
+}
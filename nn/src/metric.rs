@@ -2,13 +2,82 @@ use crate::matrix::Matrix;
 
 pub fn accuracy(y: &Matrix, target: &Matrix) -> f32 {
     let mut correct = 0.0;
-    for i in 0..y.rows() {
-        let max_idx = (0..y.cols())
-            .map( |j| y.get(i, j) )
-            .enumerate()
-            .max_by( |(_, a), (_, b)| a.total_cmp(b) )
-            .unwrap().0;
-        correct += (target.get(i, max_idx) == 1.0f32) as i32 as f32;
+    let pred = y.argmax_axis(1, false);
+    for (i, max_idx) in pred.iter().enumerate() {
+        correct += (target.get(i, max_idx as usize) == 1.0f32) as i32 as f32;
     }
     correct / (y.rows() as f32)
-}
\ No newline at end of file
+}
+
+// accuracy against class indices, [batch] or [batch, 1], as SparseCrossentropy takes them
+pub fn sparse_accuracy(y: &Matrix, labels: &Matrix) -> f32 {
+    let pred = y.argmax_axis(1, false);
+    let correct = pred.iter().zip(labels.iter()).filter(|(p, l)| p == l).count();
+    correct as f32 / (y.rows() as f32)
+}
+
+// Multi-label metrics for logits like the ones BCEWithLogitsLoss trains, [samples, labels].
+// A label is predicted when its logit is above 0 (probability above 0.5) and present
+// when its target is above 0.5.
+fn multilabel<'a>(y: &'a Matrix, target: &'a Matrix) -> impl Iterator<Item = (bool, bool)> + 'a {
+    if y.shape != target.shape {
+        panic!("Multi-label metrics need logits and targets of the same shape, got {:?} and {:?}.", y.shape, target.shape);
+    }
+    y.iter().zip(target.iter()).map(|(y, t)| (y > 0.0, t > 0.5))
+}
+
+// fraction of labels predicted right, 1 - hamming loss
+pub fn hamming_accuracy(y: &Matrix, target: &Matrix) -> f32 {
+    let correct = multilabel(y, target).filter(|(p, t)| p == t).count();
+    correct as f32 / y.numel() as f32
+}
+
+// fraction of samples with every one of their labels right
+pub fn exact_match(y: &Matrix, target: &Matrix) -> f32 {
+    let hits: Vec<bool> = multilabel(y, target).map(|(p, t)| p == t).collect();
+    let correct = hits.chunks(y.cols()).filter(|row| row.iter().all(|&hit| hit)).count();
+    correct as f32 / y.rows() as f32
+}
+
+// micro averaged over all labels of all samples, (precision, recall, f1), 0 where undefined
+pub fn precision_recall_f1(y: &Matrix, target: &Matrix) -> (f32, f32, f32) {
+    let (mut tp, mut fp, mut fn_) = (0.0, 0.0, 0.0);
+    for (p, t) in multilabel(y, target) {
+        match (p, t) {
+            (true, true) => tp += 1.0,
+            (true, false) => fp += 1.0,
+            (false, true) => fn_ += 1.0,
+            (false, false) => {}
+        }
+    }
+    let ratio = |a: f32, b: f32| if b == 0.0 { 0.0 } else { a / b };
+    let (precision, recall) = (ratio(tp, tp + fp), ratio(tp, tp + fn_));
+    (precision, recall, ratio(2.0 * precision * recall, precision + recall))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_accuracy_matches_one_hot() {
+        let y = Matrix::from_vec(3, 3, vec![0.1, 2.0, 0.3, 1.0, 0.0, 0.5, -1.0, -2.0, 0.0]);
+        let one_hot = Matrix::from_vec(3, 3, vec![0., 1., 0., 0., 0., 1., 0., 0., 1.]);
+        let labels = Matrix::from_vec(3, 1, vec![1., 2., 2.]);
+        assert_eq!(sparse_accuracy(&y, &labels), 2.0 / 3.0);
+        assert_eq!(sparse_accuracy(&y, &labels), accuracy(&y, &one_hot));
+    }
+
+    #[test]
+    fn multilabel_metrics() {
+        let y = Matrix::from_vec(3, 3, vec![2.0, -1.0, 0.5, -3.0, -0.2, 1.0, 0.1, 0.2, -0.1]);
+        let target = Matrix::from_vec(3, 3, vec![1., 0., 1., 0., 1., 1., 1., 0., 0.]);
+        assert_eq!(hamming_accuracy(&y, &target), 7.0 / 9.0);
+        assert_eq!(exact_match(&y, &target), 1.0 / 3.0);
+        // tp 4, fp 1, fn 1
+        let (precision, recall, f1) = precision_recall_f1(&y, &target);
+        assert_eq!((precision, recall), (0.8, 0.8));
+        assert!((f1 - 0.8).abs() < 1e-6);
+        assert_eq!(precision_recall_f1(&Matrix::full(2, 2, -1.0), &Matrix::full(2, 2, 0.0)), (0.0, 0.0, 0.0));
+    }
+}
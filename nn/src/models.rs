@@ -0,0 +1,294 @@
+use rand::Rng;
+
+use crate::conv::Conv2d;
+use crate::layer::{Dropout, Flatten, Layer, Linear, ReLU, Residual, Sequential};
+use crate::matrix::Matrix;
+use crate::norm::{BatchNorm1d, BatchNorm2d};
+use crate::parameter::Parameter;
+use crate::pool::AdaptiveAvgPool2d;
+
+// MLP - stack of Linear layers with an activation (and optionally batchnorm and dropout)
+// after every one but the last, [batch, sizes[0]] -> [batch, sizes.last()].
+#[allow(clippy::upper_case_acronyms)]
+pub struct MLP(Sequential);
+
+impl MLP {
+    // relu, no batchnorm, no dropout, see `MLP::builder` for the rest
+    pub fn new<R: Rng>(sizes: &[usize], rng: &mut R) -> Self {
+        Self::builder(sizes).build(rng)
+    }
+
+    // layer sizes including the input and output, e.g. [3072, 128, 128, 10]
+    pub fn builder(sizes: &[usize]) -> MLPBuilder {
+        if sizes.len() < 2 { panic!("MLP needs at least input and output sizes, got {sizes:?}.") }
+        MLPBuilder {
+            sizes: sizes.to_vec(),
+            activation: || Box::new(ReLU::new()),
+            batch_norm: false,
+            dropout: 0.0,
+        }
+    }
+}
+
+pub struct MLPBuilder {
+    sizes: Vec<usize>,
+    activation: fn() -> Box<dyn Layer>,
+    batch_norm: bool,
+    dropout: f32,
+}
+
+impl MLPBuilder {
+    // makes a fresh activation layer for every hidden layer, e.g. `|| Box::new(GELU::new())`
+    pub fn with_activation(mut self, activation: fn() -> Box<dyn Layer>) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    // BatchNorm1d between every hidden Linear and its activation
+    pub fn with_batch_norm(mut self, batch_norm: bool) -> Self {
+        self.batch_norm = batch_norm;
+        self
+    }
+
+    // Dropout after every hidden activation
+    pub fn with_dropout(mut self, p: f32) -> Self {
+        self.dropout = p;
+        self
+    }
+
+    pub fn build<R: Rng>(self, rng: &mut R) -> MLP {
+        let mut layers: Vec<Box<dyn Layer>> = vec![];
+        let hidden = self.sizes.len() - 2;
+        for (i, pair) in self.sizes.windows(2).enumerate() {
+            layers.push(Box::new(Linear::new(pair[0], pair[1], true, rng)));
+            if i == hidden { break }
+            if self.batch_norm {
+                layers.push(Box::new(BatchNorm1d::new(pair[1])));
+            }
+            layers.push((self.activation)());
+            if self.dropout > 0.0 {
+                layers.push(Box::new(Dropout::new(self.dropout, rng)));
+            }
+        }
+        MLP(Sequential::new(layers))
+    }
+}
+
+impl Layer for MLP {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.0.forward(input)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.0.backward(partial)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        self.0.parameters()
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        self.0.named_parameters()
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        self.0.named_buffers()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.0.set_training(training);
+    }
+}
+
+fn conv_bn<R: Rng>(in_chan: usize, out_chan: usize, kernel_size: usize, stride: usize, rng: &mut R) -> Vec<Box<dyn Layer>> {
+    vec![
+        Box::new(Conv2d::new(in_chan, out_chan, kernel_size, false, rng)
+            .with_stride(stride)
+            .with_padding(kernel_size / 2)),
+        Box::new(BatchNorm2d::new(out_chan)),
+    ]
+}
+
+// BasicBlock layer - residual block of ResNet18/34 and the CIFAR ResNets,
+// relu(bn(conv3x3(relu(bn(conv3x3(x))))) + shortcut(x)).
+// When the block downsamples or changes the channels the shortcut is a strided
+// 1x1 conv and batchnorm (option B of the paper), otherwise the identity.
+pub struct BasicBlock(Sequential);
+
+impl BasicBlock {
+    pub fn new<R: Rng>(in_chan: usize, out_chan: usize, stride: usize, rng: &mut R) -> Self {
+        let mut main = conv_bn(in_chan, out_chan, 3, stride, rng);
+        main.push(Box::new(ReLU::new()));
+        main.append(&mut conv_bn(out_chan, out_chan, 3, 1, rng));
+        let shortcut: Option<Box<dyn Layer>> = if stride != 1 || in_chan != out_chan {
+            Some(Box::new(Sequential::new(conv_bn(in_chan, out_chan, 1, stride, rng))))
+        } else { None };
+        Self(Sequential::new(vec![
+            Box::new(Residual::new(Box::new(Sequential::new(main)), shortcut)),
+            Box::new(ReLU::new()),
+        ]))
+    }
+}
+
+impl Layer for BasicBlock {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.0.forward(input)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.0.backward(partial)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        self.0.parameters()
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        self.0.named_parameters()
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        self.0.named_buffers()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.0.set_training(training);
+    }
+}
+
+// ResNet for CIFAR from the original paper - a 3x3 conv with 16 channels, then three stages
+// of n BasicBlocks with 16, 32 and 64 channels, the last two halving the resolution,
+// global average pooling and a Linear classifier. depth = 6n + 2.
+// Takes [batch, 3, height, width] images, put an Unflatten in front for flat CIFAR10 rows.
+pub struct ResNet(Sequential);
+
+impl ResNet {
+    pub fn new<R: Rng>(depth: usize, num_classes: usize, rng: &mut R) -> Self {
+        if depth < 8 || (depth - 2) % 6 != 0 { panic!("ResNet depth has to be 6n + 2, got {depth}.") }
+        let n = (depth - 2) / 6;
+        let mut layers = conv_bn(3, 16, 3, 1, rng);
+        layers.push(Box::new(ReLU::new()));
+        let mut in_chan = 16;
+        for (stage, out_chan) in [16, 32, 64].into_iter().enumerate() {
+            for block in 0..n {
+                let stride = if stage > 0 && block == 0 { 2 } else { 1 };
+                layers.push(Box::new(BasicBlock::new(in_chan, out_chan, stride, rng)));
+                in_chan = out_chan;
+            }
+        }
+        layers.push(Box::new(AdaptiveAvgPool2d::global()));
+        layers.push(Box::new(Flatten::new()));
+        layers.push(Box::new(Linear::new(64, num_classes, true, rng)));
+        Self(Sequential::new(layers))
+    }
+
+    pub fn resnet20<R: Rng>(num_classes: usize, rng: &mut R) -> Self {
+        Self::new(20, num_classes, rng)
+    }
+
+    pub fn resnet32<R: Rng>(num_classes: usize, rng: &mut R) -> Self {
+        Self::new(32, num_classes, rng)
+    }
+
+    pub fn resnet56<R: Rng>(num_classes: usize, rng: &mut R) -> Self {
+        Self::new(56, num_classes, rng)
+    }
+}
+
+impl Layer for ResNet {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.0.forward(input)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.0.backward(partial)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        self.0.parameters()
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        self.0.named_parameters()
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        self.0.named_buffers()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.0.set_training(training);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activation::Tanh;
+    use crate::gradcheck::{gradcheck, one_hot};
+    use crate::loss::Crossentropy;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn count(model: &mut dyn Layer) -> usize {
+        model.parameters().iter().map(|p| p.data.numel()).sum()
+    }
+
+    #[test]
+    fn mlp_layout() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut mlp = MLP::new(&[3072, 128, 128, 10], &mut rng);
+        assert_eq!(mlp.parameters().len(), 6);
+        assert_eq!(count(&mut mlp), 3072 * 128 + 128 + 128 * 128 + 128 + 128 * 10 + 10);
+
+        let mut mlp = MLP::builder(&[8, 6, 4]).with_batch_norm(true).with_dropout(0.5).build(&mut rng);
+        assert_eq!(mlp.parameters().len(), 6);
+        let x = Matrix::random(5, 8, &mut rng);
+        assert_eq!(mlp.forward(&x).shape, vec![5, 4]);
+        mlp.eval();
+        assert_eq!(mlp.forward(&x).to_vec(), mlp.forward(&x).to_vec());
+    }
+
+    #[test]
+    fn mlp_is_reproducible() {
+        let weights = |seed| MLP::new(&[4, 3, 2], &mut StdRng::seed_from_u64(seed)).parameters()[0].data.to_vec();
+        assert_eq!(weights(7), weights(7));
+        assert_ne!(weights(7), weights(8));
+    }
+
+    #[test]
+    fn mlp_gradients() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut mlp = MLP::builder(&[5, 6, 6, 3]).with_activation(|| Box::new(Tanh::new())).build(&mut rng);
+        let x = &(2.0 * &Matrix::random(4, 5, &mut rng)) - 1.0;
+        gradcheck(&mut mlp, &mut Crossentropy::new(), &x, &one_hot(&[0, 2, 1, 1], 3), 1e-2).assert_below(1e-2);
+    }
+
+    #[test]
+    fn basic_block_gradients() {
+        // in f32 an eps of 1e-3 leaves ~2% rounding noise in the central differences of a
+        // log(32) sized loss, while from 1e-2 on steps cross the relu kinks, 3e-3 sits between
+        let mut rng = StdRng::seed_from_u64(7);
+        let x = &(2.0 * &Matrix::random_nd(&[2, 2, 4, 4], &mut rng)) - 1.0;
+        for (out_chan, stride, classes) in [(2, 1, 32), (3, 2, 12)] {
+            let mut block = Sequential::new(vec![
+                Box::new(BasicBlock::new(2, out_chan, stride, &mut rng)),
+                Box::new(Flatten::new()),
+            ]);
+            let report = gradcheck(&mut block, &mut Crossentropy::new(), &x, &one_hot(&[0, classes - 1], classes), 3e-3);
+            assert_eq!(report.parameters.len(), if stride == 1 { 6 } else { 9 });
+            report.assert_below(1e-2);
+        }
+    }
+
+    #[test]
+    fn resnet_layout() {
+        let mut rng = StdRng::seed_from_u64(3);
+        // convs, batchnorms and the classifier, plus the two projection shortcuts
+        let mut model = ResNet::resnet20(10, &mut rng);
+        assert_eq!(model.parameters().len(), 19 * 3 + 2 + 2 * 3);
+        // 269722 of the paper's option A plus the shortcuts
+        assert_eq!(count(&mut model), 269_722 + 16 * 32 + 32 * 64 + 2 * (32 + 64));
+        assert_eq!(model.forward(&Matrix::random_nd(&[2, 3, 8, 8], &mut rng)).shape, vec![2, 10]);
+        assert_eq!(ResNet::resnet56(10, &mut rng).parameters().len(), 55 * 3 + 2 + 2 * 3);
+    }
+}
@@ -0,0 +1,588 @@
+use crate::layer::Layer;
+use crate::matrix::Matrix;
+use crate::parameter::Parameter;
+
+// Batch normalization over the rows of a [rows, features] matrix,
+// shared by BatchNorm1d and BatchNorm2d which only differ in how they get there.
+// In training the batch statistics are used and folded into the running ones,
+// in eval the running statistics are used as constants.
+struct BatchNorm {
+    gamma: Parameter,
+    beta: Parameter,
+    running_mean: Matrix,
+    running_var: Matrix,
+    momentum: f32,
+    eps: f32,
+    training: bool,
+    // normalized input and 1 / sqrt(var + eps) of the last forward
+    x_hat: Option<Matrix>,
+    inv_std: Option<Matrix>,
+}
+
+impl BatchNorm {
+    fn new(num_features: usize) -> Self {
+        Self {
+            gamma: Parameter::new(Matrix::full(1, num_features, 1.0)),
+            beta: Parameter::new(Matrix::full(1, num_features, 0.0)),
+            running_mean: Matrix::full(1, num_features, 0.0),
+            running_var: Matrix::full(1, num_features, 1.0),
+            momentum: 0.1,
+            eps: 1e-5,
+            training: true,
+            x_hat: None,
+            inv_std: None,
+        }
+    }
+
+    fn forward(&mut self, x: &Matrix) -> Matrix {
+        if x.ndim() != 2 || x.cols() != self.gamma.data.cols() {
+            panic!("BatchNorm over {} features got input of shape {:?}.", self.gamma.data.cols(), x.shape);
+        }
+        let (mean, var) = if self.training {
+            let m = x.rows() as f32;
+            if m < 2.0 { panic!("BatchNorm needs more than one value per feature in training.") }
+            let mean = x.mean_axis(0, true);
+            let var = x.var_axis(0, true);
+            // running variance is unbiased, same as pytorch
+            self.running_mean = &(&self.running_mean * (1.0 - self.momentum)) + &(&mean * self.momentum);
+            self.running_var = &(&self.running_var * (1.0 - self.momentum)) + &(&var * (self.momentum * m / (m - 1.0)));
+            (mean, var)
+        } else {
+            (self.running_mean.clone(), self.running_var.clone())
+        };
+
+        let inv_std = (&var + self.eps).sqrt().apply_unary(|x| 1.0 / x);
+        let x_hat = &(x - &mean) * &inv_std;
+        let y = &(&x_hat * &self.gamma.data) + &self.beta.data;
+        self.x_hat = Some(x_hat);
+        self.inv_std = Some(inv_std);
+        y
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let x_hat = self.x_hat.as_ref().expect("Cannot call backward before forward.");
+        let inv_std = self.inv_std.as_ref().unwrap();
+        self.gamma.grad = &self.gamma.grad + &(partial * x_hat).sum_axis(0, true);
+        self.beta.grad = &self.beta.grad + &partial.sum_axis(0, true);
+
+        let d_x_hat = partial * &self.gamma.data;
+        if !self.training {
+            return &d_x_hat * inv_std;
+        }
+        // batch statistics depend on every row
+        normalize_backward(&d_x_hat, x_hat, inv_std, 0)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![&mut self.gamma, &mut self.beta]
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        vec![("weight".to_string(), &mut self.gamma), ("bias".to_string(), &mut self.beta)]
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        vec![("running_mean".to_string(), &mut self.running_mean), ("running_var".to_string(), &mut self.running_var)]
+    }
+}
+
+// gradient of x_hat = (x - mean) * inv_std w.r.t. x, with mean and var taken along axis
+// dx = inv_std * (d_x_hat - mean(d_x_hat) - x_hat * mean(d_x_hat * x_hat))
+fn normalize_backward(d_x_hat: &Matrix, x_hat: &Matrix, inv_std: &Matrix, axis: usize) -> Matrix {
+    let mean_d = d_x_hat.mean_axis(axis, true);
+    let mean_dx = (d_x_hat * x_hat).mean_axis(axis, true);
+    &(&(d_x_hat - &mean_d) - &(x_hat * &mean_dx)) * inv_std
+}
+
+// x_hat and inv_std for normalizing each row of a [rows, features] matrix
+fn normalize_rows(x: &Matrix, eps: f32) -> (Matrix, Matrix) {
+    let inv_std = (&x.var_axis(1, true) + eps).sqrt().apply_unary(|x| 1.0 / x);
+    (&(x - &x.mean_axis(1, true)) * &inv_std, inv_std)
+}
+
+// BatchNorm1d layer - [batch, features], statistics per feature
+pub struct BatchNorm1d(BatchNorm);
+
+impl BatchNorm1d {
+    pub fn new(num_features: usize) -> Self {
+        Self(BatchNorm::new(num_features))
+    }
+
+    // weight of the current batch in the running statistics, 0.1 by default
+    pub fn with_momentum(mut self, momentum: f32) -> Self {
+        self.0.momentum = momentum;
+        self
+    }
+
+    pub fn with_eps(mut self, eps: f32) -> Self {
+        self.0.eps = eps;
+        self
+    }
+
+    pub fn running_mean(&self) -> &Matrix {
+        &self.0.running_mean
+    }
+
+    pub fn running_var(&self) -> &Matrix {
+        &self.0.running_var
+    }
+}
+
+impl Layer for BatchNorm1d {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.0.forward(input)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.0.backward(partial)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        self.0.parameters()
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        self.0.named_parameters()
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        self.0.named_buffers()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.0.training = training;
+    }
+}
+
+// BatchNorm2d layer - [batch, chan, height, width], statistics per channel
+// over the batch and all spatial positions
+pub struct BatchNorm2d(BatchNorm);
+
+impl BatchNorm2d {
+    pub fn new(num_features: usize) -> Self {
+        Self(BatchNorm::new(num_features))
+    }
+
+    // weight of the current batch in the running statistics, 0.1 by default
+    pub fn with_momentum(mut self, momentum: f32) -> Self {
+        self.0.momentum = momentum;
+        self
+    }
+
+    pub fn with_eps(mut self, eps: f32) -> Self {
+        self.0.eps = eps;
+        self
+    }
+
+    pub fn running_mean(&self) -> &Matrix {
+        &self.0.running_mean
+    }
+
+    pub fn running_var(&self) -> &Matrix {
+        &self.0.running_var
+    }
+}
+
+// [batch, chan, h, w] <-> [batch * h * w, chan]
+fn channels_last(x: &Matrix) -> Matrix {
+    if x.ndim() != 4 { panic!("BatchNorm2d expects [batch, chan, height, width] input, got {:?}.", x.shape) }
+    x.permute(&[0, 2, 3, 1]).reshape(&[x.shape[0] * x.shape[2] * x.shape[3], x.shape[1]])
+}
+
+fn channels_first(x: &Matrix, shape: &[usize]) -> Matrix {
+    x.reshape(&[shape[0], shape[2], shape[3], shape[1]]).permute(&[0, 3, 1, 2])
+}
+
+impl Layer for BatchNorm2d {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        channels_first(&self.0.forward(&channels_last(input)), &input.shape)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        channels_first(&self.0.backward(&channels_last(partial)), &partial.shape)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        self.0.parameters()
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        self.0.named_parameters()
+    }
+
+    fn named_buffers(&mut self) -> Vec<(String, &mut Matrix)> {
+        self.0.named_buffers()
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.0.training = training;
+    }
+}
+
+// LayerNorm layer - normalizes every sample over its trailing `normalized_shape` dims,
+// e.g. the features of each token, then scales and shifts elementwise.
+// Uses the statistics of the sample itself, so it is the same in training and eval.
+pub struct LayerNorm {
+    normalized_shape: Vec<usize>,
+    gamma: Option<Parameter>,
+    beta: Option<Parameter>,
+    eps: f32,
+    x_hat: Option<Matrix>,
+    inv_std: Option<Matrix>,
+}
+
+impl LayerNorm {
+    pub fn new(normalized_shape: &[usize], elementwise_affine: bool) -> Self {
+        Self {
+            normalized_shape: normalized_shape.to_vec(),
+            gamma: elementwise_affine.then(|| Parameter::new(Matrix::full_nd(normalized_shape, 1.0))),
+            beta: elementwise_affine.then(|| Parameter::new(Matrix::full_nd(normalized_shape, 0.0))),
+            eps: 1e-5,
+            x_hat: None,
+            inv_std: None,
+        }
+    }
+
+    pub fn with_eps(mut self, eps: f32) -> Self {
+        self.eps = eps;
+        self
+    }
+
+    fn features(&self) -> usize {
+        self.normalized_shape.iter().product()
+    }
+
+    // [..., normalized_shape] -> [samples, features]
+    fn rows(&self, x: &Matrix) -> Matrix {
+        let lead = x.ndim().checked_sub(self.normalized_shape.len());
+        if lead.map_or(true, |lead| x.shape[lead..] != self.normalized_shape[..]) {
+            panic!("LayerNorm over {:?} got input of shape {:?}.", self.normalized_shape, x.shape);
+        }
+        x.reshape(&[x.numel() / self.features(), self.features()])
+    }
+}
+
+impl Layer for LayerNorm {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let (x_hat, inv_std) = normalize_rows(&self.rows(input), self.eps);
+        let mut y = x_hat.clone();
+        if let (Some(gamma), Some(beta)) = (&self.gamma, &self.beta) {
+            let shape = [1, self.features()];
+            y = &(&y * &gamma.data.reshape(&shape)) + &beta.data.reshape(&shape);
+        }
+        self.x_hat = Some(x_hat);
+        self.inv_std = Some(inv_std);
+        y.reshape(&input.shape)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let x_hat = self.x_hat.as_ref().expect("Cannot call backward before forward.");
+        let p = self.rows(partial);
+        let mut d_x_hat = p.clone();
+        if let (Some(gamma), Some(beta)) = (&mut self.gamma, &mut self.beta) {
+            let shape = gamma.data.shape.clone();
+            gamma.grad = &gamma.grad + &(&p * x_hat).sum_axis(0, false).reshape(&shape);
+            beta.grad = &beta.grad + &p.sum_axis(0, false).reshape(&shape);
+            d_x_hat = &d_x_hat * &gamma.data.reshape(&[1, self.normalized_shape.iter().product()]);
+        }
+        normalize_backward(&d_x_hat, x_hat, self.inv_std.as_ref().unwrap(), 1).reshape(&partial.shape)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        match (&mut self.gamma, &mut self.beta) {
+            (Some(gamma), Some(beta)) => vec![gamma, beta],
+            _ => vec![],
+        }
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        match (&mut self.gamma, &mut self.beta) {
+            (Some(gamma), Some(beta)) => vec![("weight".to_string(), gamma), ("bias".to_string(), beta)],
+            _ => vec![],
+        }
+    }
+}
+
+// GroupNorm layer - splits the channels of [batch, chan, ...] inputs into groups and
+// normalizes each group of each sample, then scales and shifts per channel.
+// Independent of the batch, so it works with tiny batches and is the same in training and eval.
+pub struct GroupNorm {
+    num_groups: usize,
+    num_channels: usize,
+    gamma: Option<Parameter>,
+    beta: Option<Parameter>,
+    eps: f32,
+    x_hat: Option<Matrix>,
+    inv_std: Option<Matrix>,
+}
+
+impl GroupNorm {
+    pub fn new(num_groups: usize, num_channels: usize, affine: bool) -> Self {
+        if num_groups == 0 || num_channels % num_groups != 0 {
+            panic!("{num_channels} channels can't be split into {num_groups} groups.");
+        }
+        Self {
+            num_groups, num_channels,
+            gamma: affine.then(|| Parameter::new(Matrix::full(1, num_channels, 1.0))),
+            beta: affine.then(|| Parameter::new(Matrix::full(1, num_channels, 0.0))),
+            eps: 1e-5,
+            x_hat: None,
+            inv_std: None,
+        }
+    }
+
+    pub fn with_eps(mut self, eps: f32) -> Self {
+        self.eps = eps;
+        self
+    }
+
+    // per channel parameter shaped to broadcast against [batch, chan, ...]
+    fn per_channel(&self, p: &Matrix, ndim: usize) -> Matrix {
+        let mut shape = vec![1; ndim];
+        shape[1] = self.num_channels;
+        p.reshape(&shape)
+    }
+
+    // [batch, chan, ...] -> [batch * groups, chan / groups * ...]
+    fn groups(&self, x: &Matrix) -> Matrix {
+        if x.ndim() < 2 || x.shape[1] != self.num_channels {
+            panic!("GroupNorm over {} channels got input of shape {:?}.", self.num_channels, x.shape);
+        }
+        let rows = x.shape[0] * self.num_groups;
+        x.reshape(&[rows, x.numel() / rows])
+    }
+}
+
+impl Layer for GroupNorm {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        let (x_hat, inv_std) = normalize_rows(&self.groups(input), self.eps);
+        let mut y = x_hat.reshape(&input.shape);
+        if let (Some(gamma), Some(beta)) = (&self.gamma, &self.beta) {
+            y = &(&y * &self.per_channel(&gamma.data, input.ndim())) + &self.per_channel(&beta.data, input.ndim());
+        }
+        self.x_hat = Some(x_hat);
+        self.inv_std = Some(inv_std);
+        y
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let x_hat = self.x_hat.as_ref().expect("Cannot call backward before forward.");
+        let mut d_x_hat = partial.clone();
+        let gamma = self.gamma.as_ref().map(|g| self.per_channel(&g.data, partial.ndim()));
+        if let (Some(g), Some(b), Some(gamma)) = (&mut self.gamma, &mut self.beta, gamma) {
+            // sum over everything but the channel axis
+            let channels = g.grad.cols();
+            let per_chan = |m: &Matrix| {
+                let mut m = m.clone();
+                for axis in (0..m.ndim()).rev().filter(|a| *a != 1) {
+                    m = m.sum_axis(axis, false);
+                }
+                m.reshape(&[1, channels])
+            };
+            g.grad = &g.grad + &per_chan(&(partial * &x_hat.reshape(&partial.shape)));
+            b.grad = &b.grad + &per_chan(partial);
+            d_x_hat = &d_x_hat * &gamma;
+        }
+        normalize_backward(&self.groups(&d_x_hat), x_hat, self.inv_std.as_ref().unwrap(), 1).reshape(&partial.shape)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        match (&mut self.gamma, &mut self.beta) {
+            (Some(gamma), Some(beta)) => vec![gamma, beta],
+            _ => vec![],
+        }
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        match (&mut self.gamma, &mut self.beta) {
+            (Some(gamma), Some(beta)) => vec![("weight".to_string(), gamma), ("bias".to_string(), beta)],
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{gradcheck, one_hot};
+    use crate::layer::{Flatten, Linear, Sequential};
+    use crate::loss::Crossentropy;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn input(shape: &[usize], seed: u64) -> Matrix {
+        let mut rng = StdRng::seed_from_u64(seed);
+        &(4.0 * &Matrix::random_nd(shape, &mut rng)) - 1.0
+    }
+
+    // gamma and beta away from their identity init so they show up in the gradients
+    fn perturb(layer: &mut dyn Layer, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        for p in layer.parameters() {
+            p.data = &p.data + &(&Matrix::random_nd(&p.data.shape, &mut rng) - 0.5);
+        }
+    }
+
+    #[test]
+    fn batchnorm1d_normalizes_in_training() {
+        let y = BatchNorm1d::new(3).forward(&input(&[16, 3], 0));
+        for (m, v) in y.mean_axis(0, false).iter().zip(y.var_axis(0, false).iter()) {
+            assert!(m.abs() < 1e-5);
+            assert!((v - 1.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn running_stats_only_update_in_training() {
+        let x = input(&[8, 2], 1);
+        let mut bn = BatchNorm1d::new(2).with_momentum(0.5);
+        bn.forward(&x);
+        let expected_mean = &x.mean_axis(0, true) * 0.5;
+        let expected_var = &(&x.var_axis(0, true) * (0.5 * 8.0 / 7.0)) + 0.5;
+        for (a, b) in bn.running_mean().iter().zip(expected_mean.iter()) { assert!((a - b).abs() < 1e-6) }
+        for (a, b) in bn.running_var().iter().zip(expected_var.iter()) { assert!((a - b).abs() < 1e-5) }
+
+        bn.eval();
+        let before = bn.running_mean().to_vec();
+        let y = bn.forward(&input(&[4, 2], 2));
+        assert_eq!(bn.running_mean().to_vec(), before);
+        // eval normalizes with the running stats, not the batch ones
+        let expected = &(&input(&[4, 2], 2) - bn.running_mean()) / &(bn.running_var() + 1e-5).sqrt();
+        for (a, b) in y.iter().zip(expected.iter()) { assert!((a - b).abs() < 1e-5) }
+    }
+
+    #[test]
+    fn sequential_propagates_eval() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut model = Sequential::new(vec![
+            Box::new(Linear::new(3, 4, true, &mut rng)),
+            Box::new(BatchNorm1d::new(4)),
+        ]);
+        model.eval();
+        // in eval a single sample works, running stats are identity at init
+        let x = input(&[1, 3], 3);
+        let y = model.forward(&x);
+        model.train();
+        let y_train = model.forward(&input(&[5, 3], 3));
+        assert_eq!(y.shape, vec![1, 4]);
+        assert_eq!(y_train.shape, vec![5, 4]);
+    }
+
+    #[test]
+    fn batchnorm1d_gradients() {
+        let mut bn = BatchNorm1d::new(3);
+        perturb(&mut bn, 0);
+        gradcheck(&mut bn, &mut Crossentropy::new(), &input(&[6, 3], 4), &one_hot(&[0, 1, 2, 2, 1, 0], 3), 1e-2).assert_below(1e-2);
+    }
+
+    #[test]
+    fn batchnorm1d_eval_gradients() {
+        let mut bn = BatchNorm1d::new(3);
+        bn.forward(&input(&[6, 3], 5));
+        perturb(&mut bn, 1);
+        bn.eval();
+        gradcheck(&mut bn, &mut Crossentropy::new(), &input(&[4, 3], 6), &one_hot(&[0, 1, 2, 2], 3), 1e-2).assert_below(1e-2);
+    }
+
+    #[test]
+    fn batchnorm2d_normalizes_per_channel() {
+        let x = input(&[4, 2, 3, 3], 7);
+        let y = BatchNorm2d::new(2).forward(&x);
+        assert_eq!(y.shape, x.shape);
+        let per_chan = channels_last(&y);
+        for m in per_chan.mean_axis(0, false).iter() {
+            assert!(m.abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn layernorm_normalizes_each_sample() {
+        let x = input(&[2, 3, 4], 10);
+        let y = LayerNorm::new(&[4], true).forward(&x).reshape(&[6, 4]);
+        for (m, v) in y.mean_axis(1, false).iter().zip(y.var_axis(1, false).iter()) {
+            assert!(m.abs() < 1e-5);
+            assert!((v - 1.0).abs() < 1e-3);
+        }
+        // over the last two dims
+        let y = LayerNorm::new(&[3, 4], false).forward(&x).reshape(&[2, 12]);
+        for m in y.mean_axis(1, false).iter() {
+            assert!(m.abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn layernorm_gradients() {
+        let mut ln = LayerNorm::new(&[5], true);
+        perturb(&mut ln, 3);
+        gradcheck(&mut ln, &mut Crossentropy::new(), &input(&[4, 5], 11), &one_hot(&[0, 1, 4, 2], 5), 1e-2).assert_below(1e-2);
+
+        let mut model = Sequential::new(vec![Box::new(LayerNorm::new(&[2, 3], true)), Box::new(Flatten::new())]);
+        perturb(&mut model, 4);
+        let report = gradcheck(&mut model, &mut Crossentropy::new(), &input(&[2, 2, 2, 3], 12), &one_hot(&[3, 10], 12), 1e-2);
+        assert_eq!(report.parameters.len(), 2);
+        report.assert_below(1e-2);
+
+        let mut ln = LayerNorm::new(&[5], false);
+        assert!(ln.parameters().is_empty());
+        gradcheck(&mut ln, &mut Crossentropy::new(), &input(&[3, 5], 13), &one_hot(&[0, 1, 4], 5), 1e-2).assert_below(1e-2);
+    }
+
+    #[test]
+    fn groupnorm_gradients() {
+        let mut model = Sequential::new(vec![Box::new(GroupNorm::new(2, 4, true)), Box::new(Flatten::new())]);
+        perturb(&mut model, 5);
+        gradcheck(&mut model, &mut Crossentropy::new(), &input(&[2, 4, 2, 2], 14), &one_hot(&[3, 12], 16), 1e-2).assert_below(1e-2);
+
+        let mut model = Sequential::new(vec![Box::new(GroupNorm::new(3, 3, false)), Box::new(Flatten::new())]);
+        gradcheck(&mut model, &mut Crossentropy::new(), &input(&[2, 3, 2], 16), &one_hot(&[0, 5], 6), 1e-2).assert_below(1e-2);
+    }
+
+    #[test]
+    fn groupnorm_with_one_group_is_layernorm() {
+        let x = input(&[2, 4, 3, 3], 17);
+        let a = GroupNorm::new(1, 4, true).forward(&x);
+        let b = LayerNorm::new(&[4, 3, 3], true).forward(&x);
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn layer_and_group_norm_ignore_eval() {
+        let x = input(&[2, 4, 3], 18);
+        let mut ln = LayerNorm::new(&[3], true);
+        let mut gn = GroupNorm::new(2, 4, true);
+        let (ln_train, gn_train) = (ln.forward(&x).to_vec(), gn.forward(&x).to_vec());
+        ln.eval();
+        gn.eval();
+        assert_eq!(ln.forward(&x).to_vec(), ln_train);
+        assert_eq!(gn.forward(&x).to_vec(), gn_train);
+    }
+
+    #[test]
+    fn batchnorm2d_gradients() {
+        let mut model = Sequential::new(vec![Box::new(BatchNorm2d::new(2)), Box::new(Flatten::new())]);
+        perturb(&mut model, 2);
+        gradcheck(&mut model, &mut Crossentropy::new(), &input(&[3, 2, 2, 2], 8), &one_hot(&[0, 7, 3], 8), 1e-2).assert_below(1e-2);
+        model.eval();
+        gradcheck(&mut model, &mut Crossentropy::new(), &input(&[3, 2, 2, 2], 9), &one_hot(&[1, 2, 5], 8), 1e-2).assert_below(1e-2);
+    }
+
+    #[test]
+    fn state_dict_has_running_stats() {
+        let mut model = Sequential::new(vec![Box::new(BatchNorm1d::new(3)), Box::new(LayerNorm::new(&[3], true))]);
+        model.forward(&input(&[4, 3], 20));
+        let state = model.state_dict();
+        let keys: Vec<&String> = state.keys().collect();
+        assert_eq!(keys, vec![
+            "layers.0.bias", "layers.0.running_mean", "layers.0.running_var", "layers.0.weight",
+            "layers.1.bias", "layers.1.weight",
+        ]);
+
+        let mut fresh = Sequential::new(vec![Box::new(BatchNorm1d::new(3)), Box::new(LayerNorm::new(&[3], true))]);
+        fresh.load_state_dict(&state).unwrap();
+        fresh.eval();
+        model.eval();
+        let x = input(&[2, 3], 21);
+        assert_eq!(fresh.forward(&x).to_vec(), model.forward(&x).to_vec());
+    }
+}
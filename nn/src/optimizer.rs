@@ -1,5 +1,5 @@
 use crate::{matrix::Matrix, parameter::Parameter};
-use std::iter::zip;
+use crate::state::{check_keys, scalar, StateDict, StateDictError};
 
 pub trait Optimizer {
     fn step(&mut self, parameters: Vec<&mut Parameter>);
@@ -12,9 +12,14 @@ pub trait Optimizer {
 
     fn get_lr(&self) -> f32;
     fn set_lr(&mut self, lr: f32);
+
+    // lr and internal state like moments, everything needed to resume training
+    fn state_dict(&self) -> StateDict;
+    fn load_state_dict(&mut self, state: &StateDict) -> Result<(), StateDictError>;
 }
 
 // Stochastic gradient descent
+#[allow(clippy::upper_case_acronyms)]
 pub struct SGD {
     lr: f32,
     weight_decay: f32,
@@ -39,9 +44,21 @@ impl Optimizer for SGD {
 
     fn get_lr(&self) -> f32 { self.lr }
     fn set_lr(&mut self, lr: f32) { self.lr = lr; }
+
+    fn state_dict(&self) -> StateDict {
+        StateDict::from([("lr".to_string(), scalar(self.lr))])
+    }
+
+    fn load_state_dict(&mut self, state: &StateDict) -> Result<(), StateDictError> {
+        check_keys(&[("lr".to_string(), vec![1])], state)?;
+        self.lr = state["lr"].get_nd(&[0]);
+        Ok(())
+    }
 }
 
 // Adam optimizer
+// Parameters with `touched_rows` (sparse Embedding weights) get a lazy update: only those rows
+// and their moments change, the moments of unused rows aren't decayed towards zero.
 pub struct Adam {
     lr: f32,
     beta1: f32,
@@ -66,12 +83,39 @@ impl Adam {
             moms2: moms,
         }
     }
+
+    // update of just the given rows of parameter i, same as the dense one restricted to them,
+    // written in place so a step costs O(rows) rather than O(parameter)
+    fn lazy_step(&mut self, i: usize, param: &mut Parameter, rows: &[usize]) {
+        let cols = param.data.numel() / param.data.shape[0];
+        let (lr, beta1, beta2, eps, weight_decay) = (self.lr, self.beta1, self.beta2, self.eps, self.weight_decay);
+        let c1 = 1.0 - beta1.powi(self.t as i32);
+        let c2 = 1.0 - beta2.powi(self.t as i32);
+        let (data, grad) = (param.data.as_mut_slice(), param.grad.as_mut_slice());
+        let (m1, m2) = (self.moms1[i].as_mut_slice(), self.moms2[i].as_mut_slice());
+        for j in rows.iter().flat_map(|r| r * cols..(r + 1) * cols) {
+            let g = grad[j] + weight_decay * data[j];
+            m1[j] = beta1 * m1[j] + (1.0 - beta1) * g;
+            m2[j] = beta2 * m2[j] + (1.0 - beta2) * g * g;
+            data[j] -= lr * (m1[j] / c1) / ((m2[j] / c2).sqrt() + eps);
+        }
+    }
 }
 
 impl Optimizer for Adam {
     fn step(&mut self, parameters: Vec<&mut Parameter>) {
+        if parameters.len() != self.moms1.len() {
+            panic!("Adam was built for {} parameters, got {}.", self.moms1.len(), parameters.len());
+        }
         self.t += 1;
-        for (param, (m1, m2)) in zip(parameters, zip(&mut self.moms1,&mut self.moms2)) {
+        for (i, param) in parameters.into_iter().enumerate() {
+            if let Some(rows) = param.touched_rows.take() {
+                self.lazy_step(i, param, &rows);
+                param.touched_rows = Some(rows);
+                continue;
+            }
+            let (m1, m2) = (&mut self.moms1[i], &mut self.moms2[i]);
+
             // weight decay
             let grad = &(&param.grad + &(self.weight_decay * &param.data));
 
@@ -90,4 +134,33 @@ impl Optimizer for Adam {
 
     fn get_lr(&self) -> f32 { self.lr }
     fn set_lr(&mut self, lr: f32) { self.lr = lr; }
+
+    // moments are keyed by the position of their parameter, as `step` gets them
+    fn state_dict(&self) -> StateDict {
+        let mut state = StateDict::from([
+            ("lr".to_string(), scalar(self.lr)),
+            ("t".to_string(), scalar(self.t as f32)),
+        ]);
+        for (i, (m1, m2)) in self.moms1.iter().zip(&self.moms2).enumerate() {
+            state.insert(format!("moms1.{i}"), m1.clone());
+            state.insert(format!("moms2.{i}"), m2.clone());
+        }
+        state
+    }
+
+    fn load_state_dict(&mut self, state: &StateDict) -> Result<(), StateDictError> {
+        let mut expected = vec![("lr".to_string(), vec![1]), ("t".to_string(), vec![1])];
+        for (i, (m1, m2)) in self.moms1.iter().zip(&self.moms2).enumerate() {
+            expected.push((format!("moms1.{i}"), m1.shape.clone()));
+            expected.push((format!("moms2.{i}"), m2.shape.clone()));
+        }
+        check_keys(&expected, state)?;
+        self.lr = state["lr"].get_nd(&[0]);
+        self.t = state["t"].get_nd(&[0]) as usize;
+        for i in 0..self.moms1.len() {
+            self.moms1[i] = state[&format!("moms1.{i}")].clone();
+            self.moms2[i] = state[&format!("moms2.{i}")].clone();
+        }
+        Ok(())
+    }
 }
\ No newline at end of file
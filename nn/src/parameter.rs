@@ -1,8 +1,12 @@
+use crate::autograd::Var;
 use crate::matrix::Matrix;
 
 pub struct Parameter {
     pub data: Matrix,
     pub grad: Matrix,
+    // rows of a [rows, ...] parameter that got gradient since the last zero_grad,
+    // kept by layers with sparse gradients like Embedding. None means the grad is dense.
+    pub touched_rows: Option<Vec<usize>>,
 }
 
 impl Parameter {
@@ -10,10 +14,33 @@ impl Parameter {
         Self {
             grad: Matrix::full_like(&data, 0.0),
             data,
+            touched_rows: None,
         }
     }
 
     pub fn zero_grad(&mut self) {
-        self.grad = &self.grad * 0.0;
+        match &mut self.touched_rows {
+            // only the touched rows can be nonzero
+            Some(rows) => {
+                let cols = self.grad.numel() / self.grad.shape[0];
+                let grad = self.grad.as_mut_slice();
+                for row in rows.drain(..) {
+                    grad[row * cols..(row + 1) * cols].fill(0.0);
+                }
+            }
+            None => self.grad = &self.grad * 0.0,
+        }
+    }
+
+    // leaf for building autograd graphs, shares storage with data
+    pub fn var(&self) -> Var {
+        Var::leaf(self.data.clone())
+    }
+
+    // adds the gradient that backward left in the leaf made by `var`
+    pub fn accumulate_grad(&mut self, var: &Var) {
+        if let Some(grad) = var.grad() {
+            self.grad = &self.grad + &grad;
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,336 @@
+use crate::conv::ConvGeometry;
+use crate::layer::Layer;
+use crate::matrix::Matrix;
+use crate::parameter::Parameter;
+
+fn check_input(name: &str, shape: &[usize]) {
+    if shape.len() != 4 {
+        panic!("{name} expects [batch, chan, height, width] input, got {shape:?}.");
+    }
+}
+
+// padding can't be more than half the kernel, otherwise a window could see only padding
+fn check_padding(kernel_size: usize, padding: usize) {
+    if 2 * padding > kernel_size {
+        panic!("Padding {padding} should be at most half of kernel size {kernel_size}.");
+    }
+}
+
+// MaxPool2d layer - max over each window, padding never wins
+pub struct MaxPool2d {
+    kernel_size: usize,
+    stride: usize,
+    padding: usize,
+    // flat input index of the max for every output element
+    argmax: Option<Vec<usize>>,
+    input_shape: Option<Vec<usize>>,
+}
+
+impl MaxPool2d {
+    // stride defaults to the kernel size, so windows don't overlap
+    pub fn new(kernel_size: usize) -> Self {
+        Self { kernel_size, stride: kernel_size, padding: 0, argmax: None, input_shape: None }
+    }
+
+    pub fn with_stride(mut self, stride: usize) -> Self {
+        if stride == 0 { panic!("Stride must be positive.") }
+        self.stride = stride;
+        self
+    }
+
+    pub fn with_padding(mut self, padding: usize) -> Self {
+        check_padding(self.kernel_size, padding);
+        self.padding = padding;
+        self
+    }
+}
+
+impl Layer for MaxPool2d {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        check_input("MaxPool2d", &input.shape);
+        let g = ConvGeometry::new(&input.shape, self.kernel_size, self.stride, self.padding, 1);
+        let x = input.to_vec();
+        let mut y = Vec::with_capacity(g.batch * g.chan * g.h_out * g.w_out);
+        let mut argmax = Vec::with_capacity(y.capacity());
+        for plane in 0..g.batch * g.chan {
+            let offset = plane * g.h_in * g.w_in;
+            for oy in 0..g.h_out {
+                for ox in 0..g.w_out {
+                    let mut best = (usize::MAX, f32::NEG_INFINITY);
+                    for ky in 0..g.kernel_size {
+                        for kx in 0..g.kernel_size {
+                            if let Some((iy, ix)) = g.source(oy, ox, ky, kx) {
+                                let idx = offset + iy * g.w_in + ix;
+                                if best.0 == usize::MAX || x[idx] > best.1 { best = (idx, x[idx]) }
+                            }
+                        }
+                    }
+                    argmax.push(best.0);
+                    y.push(best.1);
+                }
+            }
+        }
+        self.argmax = Some(argmax);
+        self.input_shape = Some(input.shape.clone());
+        Matrix::from_vec_nd(&[g.batch, g.chan, g.h_out, g.w_out], y)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let argmax = self.argmax.as_ref().expect("Cannot call backward before forward.");
+        let shape = self.input_shape.as_ref().unwrap();
+        let mut grad = vec![0.0; shape.iter().product()];
+        for (idx, p) in argmax.iter().zip(partial.iter()) {
+            grad[*idx] += p;
+        }
+        Matrix::from_vec_nd(shape, grad)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// AvgPool2d layer - mean over each window
+// With `count_include_pad` (the default) padded zeros count towards the mean,
+// otherwise only the elements that fall inside the input do.
+pub struct AvgPool2d {
+    kernel_size: usize,
+    stride: usize,
+    padding: usize,
+    count_include_pad: bool,
+    input_shape: Option<Vec<usize>>,
+}
+
+impl AvgPool2d {
+    // stride defaults to the kernel size, so windows don't overlap
+    pub fn new(kernel_size: usize) -> Self {
+        Self { kernel_size, stride: kernel_size, padding: 0, count_include_pad: true, input_shape: None }
+    }
+
+    pub fn with_stride(mut self, stride: usize) -> Self {
+        if stride == 0 { panic!("Stride must be positive.") }
+        self.stride = stride;
+        self
+    }
+
+    pub fn with_padding(mut self, padding: usize) -> Self {
+        check_padding(self.kernel_size, padding);
+        self.padding = padding;
+        self
+    }
+
+    pub fn with_count_include_pad(mut self, count_include_pad: bool) -> Self {
+        self.count_include_pad = count_include_pad;
+        self
+    }
+
+    // calls fn_(output index, input index, 1 / divisor) for every input element of every window
+    fn for_each_window<F: FnMut(usize, usize, f32)>(&self, g: &ConvGeometry, mut fn_: F) {
+        let mut out = 0;
+        for plane in 0..g.batch * g.chan {
+            let offset = plane * g.h_in * g.w_in;
+            for oy in 0..g.h_out {
+                for ox in 0..g.w_out {
+                    let sources: Vec<usize> = (0..g.kernel_size * g.kernel_size)
+                        .filter_map(|k| g.source(oy, ox, k / g.kernel_size, k % g.kernel_size))
+                        .map(|(iy, ix)| offset + iy * g.w_in + ix)
+                        .collect();
+                    let divisor = if self.count_include_pad { g.kernel_size * g.kernel_size } else { sources.len() };
+                    for idx in sources {
+                        fn_(out, idx, 1.0 / divisor as f32);
+                    }
+                    out += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Layer for AvgPool2d {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        check_input("AvgPool2d", &input.shape);
+        let g = ConvGeometry::new(&input.shape, self.kernel_size, self.stride, self.padding, 1);
+        let x = input.to_vec();
+        let mut y = vec![0.0; g.batch * g.chan * g.h_out * g.w_out];
+        self.for_each_window(&g, |o, i, w| y[o] += w * x[i]);
+        self.input_shape = Some(input.shape.clone());
+        Matrix::from_vec_nd(&[g.batch, g.chan, g.h_out, g.w_out], y)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let shape = self.input_shape.clone().expect("Cannot call backward before forward.");
+        let g = ConvGeometry::new(&shape, self.kernel_size, self.stride, self.padding, 1);
+        let p = partial.to_vec();
+        let mut grad = vec![0.0; shape.iter().product()];
+        self.for_each_window(&g, |o, i, w| grad[i] += w * p[o]);
+        Matrix::from_vec_nd(&shape, grad)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+// AdaptiveAvgPool2d layer - averages into a fixed [out_h, out_w] grid whatever the input size.
+// Bin i along an axis of size n covers [floor(i * n / out), ceil((i + 1) * n / out)).
+pub struct AdaptiveAvgPool2d {
+    out_h: usize,
+    out_w: usize,
+    input_shape: Option<Vec<usize>>,
+}
+
+impl AdaptiveAvgPool2d {
+    pub fn new(out_h: usize, out_w: usize) -> Self {
+        if out_h == 0 || out_w == 0 { panic!("Output size must be positive.") }
+        Self { out_h, out_w, input_shape: None }
+    }
+
+    // global average pooling, [batch, chan, h, w] -> [batch, chan, 1, 1]
+    pub fn global() -> Self {
+        Self::new(1, 1)
+    }
+
+    fn bin(i: usize, n: usize, out: usize) -> std::ops::Range<usize> {
+        (i * n / out)..((i + 1) * n).div_ceil(out)
+    }
+
+    // calls fn_(output index, input index, 1 / bin size) for every input element of every bin
+    fn for_each_bin<F: FnMut(usize, usize, f32)>(&self, shape: &[usize], mut fn_: F) {
+        let (planes, h, w) = (shape[0] * shape[1], shape[2], shape[3]);
+        let mut out = 0;
+        for plane in 0..planes {
+            for oy in 0..self.out_h {
+                let rows = Self::bin(oy, h, self.out_h);
+                for ox in 0..self.out_w {
+                    let cols = Self::bin(ox, w, self.out_w);
+                    let weight = 1.0 / (rows.len() * cols.len()) as f32;
+                    for iy in rows.clone() {
+                        for ix in cols.clone() {
+                            fn_(out, (plane * h + iy) * w + ix, weight);
+                        }
+                    }
+                    out += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Layer for AdaptiveAvgPool2d {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        check_input("AdaptiveAvgPool2d", &input.shape);
+        let x = input.to_vec();
+        let (batch, chan) = (input.shape[0], input.shape[1]);
+        let mut y = vec![0.0; batch * chan * self.out_h * self.out_w];
+        self.for_each_bin(&input.shape, |o, i, w| y[o] += w * x[i]);
+        self.input_shape = Some(input.shape.clone());
+        Matrix::from_vec_nd(&[batch, chan, self.out_h, self.out_w], y)
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        let shape = self.input_shape.clone().expect("Cannot call backward before forward.");
+        let p = partial.to_vec();
+        let mut grad = vec![0.0; shape.iter().product()];
+        self.for_each_bin(&shape, |o, i, w| grad[i] += w * p[o]);
+        Matrix::from_vec_nd(&shape, grad)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conv::Conv2d;
+    use crate::gradcheck::{gradcheck, one_hot};
+    use crate::layer::{Flatten, Linear, Sequential};
+    use crate::loss::Crossentropy;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // max pooling has kinks where two elements of a window swap, so it needs a small eps
+    fn check(layer: Box<dyn Layer>, input_shape: &[usize], seed: u64, eps: f32) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let x = &(2.0 * &Matrix::random_nd(input_shape, &mut rng)) - 1.0;
+        let mut model = Sequential::new(vec![layer, Box::new(Flatten::new())]);
+        let classes = model.forward(&x).cols();
+        let labels: Vec<usize> = (0..input_shape[0]).map(|i| (i * 5) % classes).collect();
+        gradcheck(&mut model, &mut Crossentropy::new(), &x, &one_hot(&labels, classes), eps).assert_below(1e-2);
+    }
+
+    fn plane(h: usize, w: usize) -> Matrix {
+        Matrix::from_vec_nd(&[1, 1, h, w], (0..h * w).map(|x| x as f32).collect())
+    }
+
+    #[test]
+    fn max_pool_forward() {
+        let y = MaxPool2d::new(2).forward(&plane(4, 4));
+        assert_eq!(y.shape, vec![1, 1, 2, 2]);
+        assert_eq!(y.to_vec(), vec![5., 7., 13., 15.]);
+        let y = MaxPool2d::new(3).with_stride(2).with_padding(1).forward(&plane(4, 4));
+        assert_eq!(y.to_vec(), vec![5., 7., 13., 15.]);
+    }
+
+    #[test]
+    fn max_pool_routes_gradient_to_argmax() {
+        let mut pool = MaxPool2d::new(2);
+        pool.forward(&plane(2, 4));
+        let grad = pool.backward(&Matrix::from_vec_nd(&[1, 1, 1, 2], vec![1.0, 2.0]));
+        assert_eq!(grad.to_vec(), vec![0., 0., 0., 0., 0., 1., 0., 2.]);
+    }
+
+    #[test]
+    fn max_pool_gradients() {
+        check(Box::new(MaxPool2d::new(2)), &[2, 2, 4, 4], 0, 1e-3);
+        check(Box::new(MaxPool2d::new(3).with_stride(2).with_padding(1)), &[2, 1, 5, 5], 1, 1e-3);
+    }
+
+    #[test]
+    fn avg_pool_count_include_pad() {
+        let x = Matrix::full_nd(&[1, 1, 2, 2], 1.0);
+        let y = AvgPool2d::new(2).with_stride(1).with_padding(1).forward(&x);
+        assert_eq!(y.to_vec(), vec![0.25, 0.5, 0.25, 0.5, 1.0, 0.5, 0.25, 0.5, 0.25]);
+        let y = AvgPool2d::new(2).with_stride(1).with_padding(1).with_count_include_pad(false).forward(&x);
+        assert_eq!(y.to_vec(), vec![1.0; 9]);
+    }
+
+    #[test]
+    fn avg_pool_gradients() {
+        check(Box::new(AvgPool2d::new(2)), &[2, 2, 4, 4], 2, 1e-2);
+        check(Box::new(AvgPool2d::new(3).with_stride(2).with_padding(1)), &[2, 1, 5, 5], 3, 1e-2);
+        check(Box::new(AvgPool2d::new(3).with_stride(2).with_padding(1).with_count_include_pad(false)), &[2, 1, 5, 5], 4, 1e-2);
+    }
+
+    #[test]
+    fn adaptive_avg_pool_forward() {
+        let y = AdaptiveAvgPool2d::new(2, 3).forward(&plane(4, 3));
+        assert_eq!(y.to_vec(), vec![1.5, 2.5, 3.5, 7.5, 8.5, 9.5]);
+        // overlapping bins when the input doesn't divide evenly
+        let y = AdaptiveAvgPool2d::new(1, 2).forward(&plane(1, 3));
+        assert_eq!(y.to_vec(), vec![0.5, 1.5]);
+        let y = AdaptiveAvgPool2d::global().forward(&plane(3, 3));
+        assert_eq!((y.shape.clone(), y.to_vec()), (vec![1, 1, 1, 1], vec![4.0]));
+    }
+
+    #[test]
+    fn adaptive_avg_pool_gradients() {
+        check(Box::new(AdaptiveAvgPool2d::new(2, 2)), &[2, 2, 5, 4], 5, 1e-2);
+        check(Box::new(AdaptiveAvgPool2d::global()), &[3, 4, 3, 3], 6, 1e-2);
+    }
+
+    #[test]
+    fn conv_stack_into_linear() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut model = Sequential::new(vec![
+            Box::new(Conv2d::new(3, 8, 3, true, &mut rng).with_padding(1)),
+            Box::new(MaxPool2d::new(2)),
+            Box::new(Conv2d::new(8, 16, 3, true, &mut rng).with_padding(1)),
+            Box::new(AdaptiveAvgPool2d::global()),
+            Box::new(Flatten::new()),
+            Box::new(Linear::new(16, 10, true, &mut rng)),
+        ]);
+        let y = model.forward(&Matrix::random_nd(&[2, 3, 32, 32], &mut rng));
+        assert_eq!(y.shape, vec![2, 10]);
+    }
+}
@@ -0,0 +1,525 @@
+use rand::Rng;
+
+use crate::activation::sigmoid;
+use crate::layer::Layer;
+use crate::matrix::Matrix;
+use crate::parameter::Parameter;
+
+#[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+enum Cell {
+    RNN,
+    LSTM,
+    GRU,
+}
+
+impl Cell {
+    // number of [hidden] blocks in the weights, gates are stacked in pytorch order
+    fn gates(self) -> usize {
+        match self {
+            Cell::RNN => 1,
+            Cell::LSTM => 4, // input, forget, cell, output
+            Cell::GRU => 3,  // reset, update, new
+        }
+    }
+
+    // h, plus c for lstm
+    fn states(self) -> usize {
+        match self {
+            Cell::LSTM => 2,
+            _ => 1,
+        }
+    }
+}
+
+// weights of one layer in one direction, [in, gates * hidden] like Linear
+struct Weights {
+    w_ih: Parameter,
+    w_hh: Parameter,
+    b_ih: Parameter,
+    b_hh: Parameter,
+}
+
+// what one time step keeps for backward
+struct Step {
+    x: Matrix,
+    // states going into the step
+    state: Vec<Matrix>,
+    // gates after their nonlinearity, [batch, gates * hidden]
+    gates: Matrix,
+    // tanh(c') for lstm, the hidden part of the new gate h W_hn + b_hn for gru
+    extra: Option<Matrix>,
+}
+
+// Multi layer, optionally bidirectional recurrence over [batch, time, features] inputs,
+// shared by RNN, LSTM and GRU which only differ in their cell.
+// States are [layers * directions, batch, hidden], like in pytorch.
+struct Recurrent {
+    cell: Cell,
+    hidden_size: usize,
+    num_layers: usize,
+    directions: usize,
+    // index layer * directions + direction
+    weights: Vec<Weights>,
+    steps: Option<Vec<Vec<Step>>>,
+}
+
+fn tanh(m: &Matrix) -> Matrix {
+    m.apply_unary(|x| x.tanh())
+}
+
+fn sigm(m: &Matrix) -> Matrix {
+    m.apply_unary(|x| sigmoid(*x))
+}
+
+impl Recurrent {
+    fn new<R: Rng>(cell: Cell, input_size: usize, hidden_size: usize, num_layers: usize, bidirectional: bool, rng: &mut R) -> Self {
+        if num_layers == 0 { panic!("Recurrent layers need at least one layer.") }
+        let directions = if bidirectional { 2 } else { 1 };
+        let bound = 1.0 / (hidden_size as f32).sqrt(); // pytorch's init
+        let mut uniform = |rows, cols| Parameter::new(bound - &((2.0*bound) * &Matrix::random(rows, cols, rng)));
+        let width = cell.gates() * hidden_size;
+        let mut weights = vec![];
+        for layer in 0..num_layers {
+            let in_size = if layer == 0 { input_size } else { hidden_size * directions };
+            for _ in 0..directions {
+                weights.push(Weights {
+                    w_ih: uniform(in_size, width),
+                    w_hh: uniform(hidden_size, width),
+                    b_ih: uniform(1, width),
+                    b_hh: uniform(1, width),
+                });
+            }
+        }
+        Self { cell, hidden_size, num_layers, directions, weights, steps: None }
+    }
+
+    // one time step of one cell, returns the new states
+    fn step(&self, w: &Weights, x: &Matrix, state: &[Matrix]) -> (Vec<Matrix>, Step) {
+        let hid = self.hidden_size;
+        let gate = |m: &Matrix, k: usize| m.narrow(1, k * hid..(k + 1) * hid);
+        let a = &x.matmul(&w.w_ih.data) + &w.b_ih.data;
+        let hh = &state[0].matmul(&w.w_hh.data) + &w.b_hh.data;
+        let (new, gates, extra) = match self.cell {
+            Cell::RNN => {
+                let h = tanh(&(&a + &hh));
+                (vec![h.clone()], h, None)
+            }
+            Cell::LSTM => {
+                let z = &a + &hh;
+                let (i, f, g, o) = (sigm(&gate(&z, 0)), sigm(&gate(&z, 1)), tanh(&gate(&z, 2)), sigm(&gate(&z, 3)));
+                let c = &(&f * &state[1]) + &(&i * &g);
+                let tc = tanh(&c);
+                let h = &o * &tc;
+                (vec![h, c], Matrix::concat(&[i, f, g, o], 1), Some(tc))
+            }
+            Cell::GRU => {
+                let r = sigm(&(&gate(&a, 0) + &gate(&hh, 0)));
+                let z = sigm(&(&gate(&a, 1) + &gate(&hh, 1)));
+                let hh_n = gate(&hh, 2);
+                let n = tanh(&(&gate(&a, 2) + &(&r * &hh_n)));
+                // (1 - z) * n + z * h
+                let h = &n + &(&z * &(&state[0] - &n));
+                (vec![h], Matrix::concat(&[r, z, n], 1), Some(hh_n))
+            }
+        };
+        (new, Step { x: x.clone(), state: state.to_vec(), gates, extra })
+    }
+
+    // backward of one time step, accumulates the weight grads and
+    // returns the grads of the incoming states and of x
+    fn step_backward(cell: Cell, hid: usize, w: &mut Weights, step: &Step, d_state: &[Matrix]) -> (Vec<Matrix>, Matrix) {
+        let gate = |m: &Matrix, k: usize| m.narrow(1, k * hid..(k + 1) * hid);
+        let dh = &d_state[0];
+        let h_prev = &step.state[0];
+        // grads of the pre activations of x W_ih + b_ih and h W_hh + b_hh
+        let (da, dhh, mut d_prev) = match cell {
+            Cell::RNN => {
+                let h = &step.gates;
+                let dz = dh * &(1.0 - &(h * h));
+                (dz.clone(), dz, vec![])
+            }
+            Cell::LSTM => {
+                let (i, f, g, o) = (gate(&step.gates, 0), gate(&step.gates, 1), gate(&step.gates, 2), gate(&step.gates, 3));
+                let tc = step.extra.as_ref().unwrap();
+                let dc = &d_state[1] + &(&(dh * &o) * &(1.0 - &(tc * tc)));
+                let dz = Matrix::concat(&[
+                    &(&(&dc * &g) * &i) * &(1.0 - &i),
+                    &(&(&dc * &step.state[1]) * &f) * &(1.0 - &f),
+                    &(&dc * &i) * &(1.0 - &(&g * &g)),
+                    &(&(dh * tc) * &o) * &(1.0 - &o),
+                ], 1);
+                (dz.clone(), dz, vec![&dc * &f])
+            }
+            Cell::GRU => {
+                let (r, z, n) = (gate(&step.gates, 0), gate(&step.gates, 1), gate(&step.gates, 2));
+                let hh_n = step.extra.as_ref().unwrap();
+                let dn = &(&(dh * &(1.0 - &z)) * &(1.0 - &(&n * &n)));
+                let dr = &(&(dn * hh_n) * &r) * &(1.0 - &r);
+                let dz = &(&(dh * &(h_prev - &n)) * &z) * &(1.0 - &z);
+                let da = Matrix::concat(&[dr.clone(), dz.clone(), dn.clone()], 1);
+                let dhh = Matrix::concat(&[dr, dz, dn * &r], 1);
+                (da, dhh, vec![])
+            }
+        };
+        w.w_ih.grad = &w.w_ih.grad + &step.x.T().matmul(&da);
+        w.b_ih.grad = &w.b_ih.grad + &da.sum_axis(0, true);
+        w.w_hh.grad = &w.w_hh.grad + &h_prev.T().matmul(&dhh);
+        w.b_hh.grad = &w.b_hh.grad + &dhh.sum_axis(0, true);
+
+        let mut dh_prev = dhh.matmul(&w.w_hh.data.T());
+        if let Cell::GRU = cell {
+            dh_prev = &dh_prev + &(dh * &gate(&step.gates, 1));
+        }
+        d_prev.insert(0, dh_prev);
+        (d_prev, da.matmul(&w.w_ih.data.T()))
+    }
+
+    // time steps in the order a direction processes them
+    fn order(&self, dir: usize, len: usize) -> Vec<usize> {
+        if dir == 0 { (0..len).collect() } else { (0..len).rev().collect() }
+    }
+
+    fn check_state(&self, state: &[Matrix], batch: usize) {
+        let shape = [self.num_layers * self.directions, batch, self.hidden_size];
+        if state.len() != self.cell.states() || state.iter().any(|s| s.shape != shape) {
+            let shapes: Vec<_> = state.iter().map(|s| s.shape.clone()).collect();
+            panic!("Expected {} states of shape {shape:?}, got {shapes:?}.", self.cell.states());
+        }
+    }
+
+    fn forward(&mut self, input: &Matrix, state: Option<&[Matrix]>) -> (Matrix, Vec<Matrix>) {
+        let in_size = self.weights[0].w_ih.data.rows();
+        if input.ndim() != 3 || input.shape[2] != in_size {
+            panic!("Expected [batch, time, {in_size}] input, got {:?}.", input.shape);
+        }
+        let (batch, len) = (input.shape[0], input.shape[1]);
+        let zeros = vec![Matrix::full_nd(&[self.num_layers * self.directions, batch, self.hidden_size], 0.0); self.cell.states()];
+        let state = state.unwrap_or(&zeros);
+        self.check_state(state, batch);
+
+        let mut x = input.clone();
+        let mut steps = vec![];
+        let mut last = vec![vec![]; self.cell.states()];
+        for layer in 0..self.num_layers {
+            let mut outputs = vec![];
+            for dir in 0..self.directions {
+                let idx = layer * self.directions + dir;
+                let mut s: Vec<Matrix> = state.iter().map(|s| s.select(0, idx)).collect();
+                let mut out = vec![None; len];
+                let mut cache = vec![];
+                for t in self.order(dir, len) {
+                    let (new, step) = self.step(&self.weights[idx], &x.select(1, t), &s);
+                    out[t] = Some(new[0].clone());
+                    cache.push(step);
+                    s = new;
+                }
+                let out: Vec<Matrix> = out.into_iter().map(Option::unwrap).collect();
+                outputs.push(Matrix::stack(&out, 1));
+                for (l, s) in last.iter_mut().zip(s) {
+                    l.push(s);
+                }
+                steps.push(cache);
+            }
+            x = Matrix::concat(&outputs, 2);
+        }
+        self.steps = Some(steps);
+        (x, last.iter().map(|s| Matrix::stack(s, 0)).collect())
+    }
+
+    fn backward(&mut self, partial: &Matrix, d_state: Option<&[Matrix]>) -> (Matrix, Vec<Matrix>) {
+        let steps = self.steps.as_ref().expect("Cannot call backward before forward.");
+        let (batch, len) = (partial.shape[0], partial.shape[1]);
+        let hid = self.hidden_size;
+        let zeros = vec![Matrix::full_nd(&[self.num_layers * self.directions, batch, hid], 0.0); self.cell.states()];
+        let d_state = d_state.unwrap_or(&zeros);
+        self.check_state(d_state, batch);
+
+        let mut d_out = partial.clone();
+        let mut d_first = vec![vec![None; self.num_layers * self.directions]; self.cell.states()];
+        for layer in (0..self.num_layers).rev() {
+            let mut d_in: Option<Matrix> = None;
+            for dir in 0..self.directions {
+                let idx = layer * self.directions + dir;
+                let d_h = d_out.narrow(2, dir * hid..(dir + 1) * hid);
+                let mut ds: Vec<Matrix> = d_state.iter().map(|s| s.select(0, idx)).collect();
+                let mut dx = vec![None; len];
+                // walk the steps backwards through time
+                for (step, t) in steps[idx].iter().zip(self.order(dir, len)).rev() {
+                    ds[0] = &ds[0] + &d_h.select(1, t);
+                    let (d_prev, d_x) = Self::step_backward(self.cell, hid, &mut self.weights[idx], step, &ds);
+                    dx[t] = Some(d_x);
+                    ds = d_prev;
+                }
+                let dx: Vec<Matrix> = dx.into_iter().map(Option::unwrap).collect();
+                let dx = Matrix::stack(&dx, 1);
+                d_in = Some(match d_in {
+                    Some(d) => &d + &dx,
+                    None => dx,
+                });
+                for (d, s) in d_first.iter_mut().zip(ds) {
+                    d[idx] = Some(s);
+                }
+            }
+            d_out = d_in.unwrap();
+        }
+        let d_first = d_first.into_iter()
+            .map(|d| Matrix::stack(&d.into_iter().map(Option::unwrap).collect::<Vec<_>>(), 0))
+            .collect();
+        (d_out, d_first)
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        self.weights.iter_mut()
+            .flat_map(|w| [&mut w.w_ih, &mut w.w_hh, &mut w.b_ih, &mut w.b_hh])
+            .collect()
+    }
+
+    // pytorch's names, weight_ih_l0, ..., weight_ih_l0_reverse for the backward direction
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        let directions = self.directions;
+        self.weights.iter_mut().enumerate()
+            .flat_map(|(i, w)| {
+                let suffix = format!("l{}{}", i / directions, if i % directions == 1 { "_reverse" } else { "" });
+                [
+                    (format!("weight_ih_{suffix}"), &mut w.w_ih),
+                    (format!("weight_hh_{suffix}"), &mut w.w_hh),
+                    (format!("bias_ih_{suffix}"), &mut w.b_ih),
+                    (format!("bias_hh_{suffix}"), &mut w.b_hh),
+                ]
+            })
+            .collect()
+    }
+}
+
+// Elman RNN layer - h' = tanh(x W_ih + b_ih + h W_hh + b_hh).
+// [batch, time, input_size] -> [batch, time, directions * hidden_size], the state is
+// [h] of shape [num_layers * directions, batch, hidden_size].
+#[allow(clippy::upper_case_acronyms)]
+pub struct RNN(Recurrent);
+
+impl RNN {
+    pub fn new<R: Rng>(input_size: usize, hidden_size: usize, num_layers: usize, bidirectional: bool, rng: &mut R) -> Self {
+        Self(Recurrent::new(Cell::RNN, input_size, hidden_size, num_layers, bidirectional, rng))
+    }
+
+    // forward from the given initial state instead of zeros, also returns the final state
+    pub fn forward_with_state(&mut self, input: &Matrix, state: Option<&[Matrix]>) -> (Matrix, Vec<Matrix>) {
+        self.0.forward(input, state)
+    }
+
+    // backward with the grad of the final state too, also returns the grad of the initial state
+    pub fn backward_with_state(&mut self, partial: &Matrix, d_state: Option<&[Matrix]>) -> (Matrix, Vec<Matrix>) {
+        self.0.backward(partial, d_state)
+    }
+}
+
+impl Layer for RNN {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.0.forward(input, None).0
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.0.backward(partial, None).0
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        self.0.parameters()
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        self.0.named_parameters()
+    }
+}
+
+// LSTM layer, same shapes as RNN but the state is [h, c]
+#[allow(clippy::upper_case_acronyms)]
+pub struct LSTM(Recurrent);
+
+impl LSTM {
+    pub fn new<R: Rng>(input_size: usize, hidden_size: usize, num_layers: usize, bidirectional: bool, rng: &mut R) -> Self {
+        Self(Recurrent::new(Cell::LSTM, input_size, hidden_size, num_layers, bidirectional, rng))
+    }
+
+    // forward from the given initial state instead of zeros, also returns the final state
+    pub fn forward_with_state(&mut self, input: &Matrix, state: Option<&[Matrix]>) -> (Matrix, Vec<Matrix>) {
+        self.0.forward(input, state)
+    }
+
+    // backward with the grad of the final state too, also returns the grad of the initial state
+    pub fn backward_with_state(&mut self, partial: &Matrix, d_state: Option<&[Matrix]>) -> (Matrix, Vec<Matrix>) {
+        self.0.backward(partial, d_state)
+    }
+}
+
+impl Layer for LSTM {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.0.forward(input, None).0
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.0.backward(partial, None).0
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        self.0.parameters()
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        self.0.named_parameters()
+    }
+}
+
+// GRU layer, same shapes as RNN
+#[allow(clippy::upper_case_acronyms)]
+pub struct GRU(Recurrent);
+
+impl GRU {
+    pub fn new<R: Rng>(input_size: usize, hidden_size: usize, num_layers: usize, bidirectional: bool, rng: &mut R) -> Self {
+        Self(Recurrent::new(Cell::GRU, input_size, hidden_size, num_layers, bidirectional, rng))
+    }
+
+    // forward from the given initial state instead of zeros, also returns the final state
+    pub fn forward_with_state(&mut self, input: &Matrix, state: Option<&[Matrix]>) -> (Matrix, Vec<Matrix>) {
+        self.0.forward(input, state)
+    }
+
+    // backward with the grad of the final state too, also returns the grad of the initial state
+    pub fn backward_with_state(&mut self, partial: &Matrix, d_state: Option<&[Matrix]>) -> (Matrix, Vec<Matrix>) {
+        self.0.backward(partial, d_state)
+    }
+}
+
+impl Layer for GRU {
+    fn forward(&mut self, input: &Matrix) -> Matrix {
+        self.0.forward(input, None).0
+    }
+
+    fn backward(&mut self, partial: &Matrix) -> Matrix {
+        self.0.backward(partial, None).0
+    }
+
+    fn parameters(&mut self) -> Vec<&mut Parameter> {
+        self.0.parameters()
+    }
+
+    fn named_parameters(&mut self) -> Vec<(String, &mut Parameter)> {
+        self.0.named_parameters()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradcheck::{gradcheck, one_hot};
+    use crate::layer::{Flatten, Sequential};
+    use crate::loss::Crossentropy;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn input(shape: &[usize], rng: &mut StdRng) -> Matrix {
+        &(2.0 * &Matrix::random_nd(shape, rng)) - 1.0
+    }
+
+    // [2, 3, 4] input, flattened output into crossentropy
+    fn check(layer: Box<dyn Layer>, seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let x = input(&[2, 3, 4], &mut rng);
+        let mut model = Sequential::new(vec![layer, Box::new(Flatten::new())]);
+        let classes = model.forward(&x).cols();
+        let report = gradcheck(&mut model, &mut Crossentropy::new(), &x, &one_hot(&[1, classes - 2], classes), 1e-2);
+        report.assert_below(1e-2);
+    }
+
+    #[test]
+    fn rnn_gradients() {
+        let mut rng = StdRng::seed_from_u64(0);
+        check(Box::new(RNN::new(4, 3, 1, false, &mut rng)), 1);
+        check(Box::new(RNN::new(4, 3, 2, true, &mut rng)), 2);
+    }
+
+    #[test]
+    fn lstm_gradients() {
+        let mut rng = StdRng::seed_from_u64(3);
+        check(Box::new(LSTM::new(4, 3, 1, false, &mut rng)), 4);
+        check(Box::new(LSTM::new(4, 3, 2, true, &mut rng)), 5);
+    }
+
+    #[test]
+    fn gru_gradients() {
+        let mut rng = StdRng::seed_from_u64(6);
+        check(Box::new(GRU::new(4, 3, 1, false, &mut rng)), 7);
+        check(Box::new(GRU::new(4, 3, 2, true, &mut rng)), 8);
+    }
+
+    #[test]
+    fn shapes_and_parameters() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut lstm = LSTM::new(4, 5, 2, true, &mut rng);
+        assert_eq!(lstm.parameters().len(), 2 * 2 * 4);
+        assert_eq!(lstm.parameters()[0].data.shape, vec![4, 20]);
+        assert_eq!(lstm.parameters()[4].data.shape, vec![4, 20]);
+        assert_eq!(lstm.parameters()[8].data.shape, vec![10, 20]);
+        let (y, state) = lstm.forward_with_state(&input(&[3, 6, 4], &mut rng), None);
+        assert_eq!(y.shape, vec![3, 6, 10]);
+        assert_eq!(state.len(), 2);
+        assert_eq!(state[0].shape, vec![4, 3, 5]);
+
+        // final hidden state of the forward direction is its last output
+        let last = y.select(1, 5).narrow(1, 0..5);
+        assert_eq!(last.to_vec(), state[0].select(0, 2).to_vec());
+        // and of the backward direction its first one
+        let first = y.select(1, 0).narrow(1, 5..10);
+        assert_eq!(first.to_vec(), state[0].select(0, 3).to_vec());
+    }
+
+    #[test]
+    fn state_carries_over_between_chunks() {
+        let mut rng = StdRng::seed_from_u64(10);
+        let x = input(&[2, 6, 3], &mut rng);
+        let mut gru = GRU::new(3, 4, 2, false, &mut rng);
+        let whole = gru.forward(&x);
+        let (_, state) = gru.forward_with_state(&x.narrow(1, 0..4), None);
+        let (rest, _) = gru.forward_with_state(&x.narrow(1, 4..6), Some(&state));
+        for (a, b) in whole.narrow(1, 4..6).iter().zip(rest.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn initial_state_gradients() {
+        // loss = sum(output * w) + sum(h_n * v), checked against finite differences in h0
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut lstm = LSTM::new(3, 2, 2, false, &mut rng);
+        let x = input(&[2, 3, 3], &mut rng);
+        let h0 = vec![input(&[2, 2, 2], &mut rng), input(&[2, 2, 2], &mut rng)];
+        let w = input(&[2, 3, 2], &mut rng);
+        let v = vec![input(&[2, 2, 2], &mut rng), input(&[2, 2, 2], &mut rng)];
+        let loss = |lstm: &mut LSTM, h0: &[Matrix]| {
+            let (y, state) = lstm.forward_with_state(&x, Some(h0));
+            (&y * &w).sum() + state.iter().zip(&v).map(|(s, v)| (s * v).sum()).sum::<f32>()
+        };
+        loss(&mut lstm, &h0);
+        let (_, d_h0) = lstm.backward_with_state(&w, Some(&v));
+
+        let eps = 1e-2;
+        for k in 0..2 {
+            for i in 0..8 {
+                let index = [i / 4, i / 2 % 2, i % 2];
+                let mut plus = h0.clone();
+                plus[k].set_nd(&index, h0[k].get_nd(&index) + eps);
+                let mut minus = h0.clone();
+                minus[k].set_nd(&index, h0[k].get_nd(&index) - eps);
+                let num = (loss(&mut lstm, &plus) - loss(&mut lstm, &minus)) / (2.0 * eps);
+                assert!((num - d_h0[k].get_nd(&index)).abs() < 1e-2, "{num} vs {}", d_h0[k].get_nd(&index));
+            }
+        }
+    }
+
+    #[test]
+    fn pytorch_parameter_names() {
+        let mut gru = GRU::new(2, 3, 2, true, &mut StdRng::seed_from_u64(12));
+        let names: Vec<String> = gru.named_parameters().into_iter().map(|(n, _)| n).collect();
+        assert_eq!(&names[..5], &["weight_ih_l0", "weight_hh_l0", "bias_ih_l0", "bias_hh_l0", "weight_ih_l0_reverse"]);
+        assert_eq!(names[15], "bias_hh_l1_reverse");
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::matrix::Matrix;
+
+// Parameters and buffers of a model by their dotted names, see `Layer::state_dict`
+pub type StateDict = BTreeMap<String, Matrix>;
+
+// Everything that didn't fit when loading a state_dict
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateDictError {
+    // expected by the layer but not in the state_dict
+    pub missing: Vec<String>,
+    // in the state_dict but not in the layer
+    pub unexpected: Vec<String>,
+    // (name, expected shape, shape in the state_dict)
+    pub mismatched: Vec<(String, Vec<usize>, Vec<usize>)>,
+}
+
+impl StateDictError {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl fmt::Display for StateDictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error loading state dict:")?;
+        if !self.missing.is_empty() {
+            write!(f, " missing keys {:?}.", self.missing)?;
+        }
+        if !self.unexpected.is_empty() {
+            write!(f, " unexpected keys {:?}.", self.unexpected)?;
+        }
+        for (name, expected, got) in &self.mismatched {
+            write!(f, " {name} should be {expected:?}, got {got:?}.")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for StateDictError {}
+
+// checks a state_dict against the (name, shape) entries it should have
+pub(crate) fn check_keys(expected: &[(String, Vec<usize>)], state: &StateDict) -> Result<(), StateDictError> {
+    let mut error = StateDictError::default();
+    for (name, shape) in expected {
+        match state.get(name) {
+            None => error.missing.push(name.clone()),
+            Some(m) if m.shape != *shape => error.mismatched.push((name.clone(), shape.clone(), m.shape.clone())),
+            _ => {}
+        }
+    }
+    error.unexpected = state.keys().filter(|k| !expected.iter().any(|(n, _)| n == *k)).cloned().collect();
+    if error.is_empty() { Ok(()) } else { Err(error) }
+}
+
+// single number entries, e.g. the step count of an optimizer
+pub(crate) fn scalar(x: f32) -> Matrix {
+    Matrix::from_vec_nd(&[1], vec![x])
+}